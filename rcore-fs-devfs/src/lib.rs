@@ -22,6 +22,8 @@ pub mod special;
 /// You can add or remove devices through `add()` and `remove()`.
 pub struct DevFS {
     root: Arc<DevINode>,
+    /// Device id in metadata of directories
+    dev: usize,
 }
 
 impl FileSystem for DevFS {
@@ -45,12 +47,21 @@ impl FileSystem for DevFS {
             namemax: 0,
         }
     }
+
+    fn is_dirty(&self) -> bool {
+        false
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "devfs"
+    }
 }
 
 impl DevFS {
     pub fn new() -> Arc<Self> {
         let fs = Arc::new(Self {
             root: DevINode::new(),
+            dev: new_dev_id(),
         });
         *fs.root.fs.write() = Arc::downgrade(&fs);
         fs
@@ -122,10 +133,16 @@ impl DevINode {
         if children.contains_key(name) {
             return Err(FsError::EntryExist);
         }
+        special::set_dev(&*dev, self.dev());
         children.insert(String::from(name), dev);
         Ok(())
     }
 
+    /// Device id of the `DevFS`, 0 if dropped
+    fn dev(&self) -> usize {
+        self.fs.read().upgrade().map_or(0, |fs| fs.dev)
+    }
+
     pub fn remove(&self, name: &str) -> Result<()> {
         let mut children = self.children.write();
         children.remove(name).ok_or(FsError::EntryNotFound)?;
@@ -148,7 +165,7 @@ impl INode for DevINode {
 
     fn metadata(&self) -> Result<Metadata> {
         Ok(Metadata {
-            dev: 0,
+            dev: self.dev(),
             inode: self.inode_id,
             size: self.children.read().len(),
             blk_size: 0,
@@ -1,6 +1,7 @@
 //! Built-in special device files
 
 use super::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 macro_rules! impl_inode {
     () => {
@@ -40,6 +41,9 @@ macro_rules! impl_inode {
         fn mmap(&self, _area: MMapArea) -> Result<()> {
             Err(FsError::NotSupported)
         }
+        fn as_device(&self) -> Option<&dyn DeviceINode> {
+            Some(self)
+        }
         fn fs(&self) -> Arc<dyn FileSystem> {
             unimplemented!()
         }
@@ -54,3 +58,14 @@ mod zero;
 
 pub use self::null::*;
 pub use self::zero::*;
+
+/// Report `dev` in the metadata of `inode` if it is a built-in special file,
+/// once added to a `DevFS` with this device id
+pub(crate) fn set_dev(inode: &dyn INode, dev: usize) {
+    let any = inode.as_any_ref();
+    if let Some(null) = any.downcast_ref::<NullINode>() {
+        null.dev.store(dev, Ordering::Relaxed);
+    } else if let Some(zero) = any.downcast_ref::<ZeroINode>() {
+        zero.dev.store(dev, Ordering::Relaxed);
+    }
+}
@@ -2,12 +2,15 @@ use super::*;
 
 pub struct NullINode {
     inode_id: usize,
+    /// Device id of the `DevFS` it was added to
+    pub(super) dev: AtomicUsize,
 }
 
 impl NullINode {
     pub fn new() -> Self {
         Self {
             inode_id: DevFS::new_inode_id(),
+            dev: AtomicUsize::new(0),
         }
     }
 }
@@ -38,8 +41,9 @@ impl INode for NullINode {
     }
 
     fn metadata(&self) -> Result<Metadata> {
+        let (major, minor) = self.device_id();
         Ok(Metadata {
-            dev: 1,
+            dev: self.dev.load(Ordering::Relaxed),
             inode: self.inode_id,
             size: 0,
             blk_size: 0,
@@ -52,9 +56,23 @@ impl INode for NullINode {
             nlinks: 1,
             uid: 0,
             gid: 0,
-            rdev: make_rdev(1, 3),
+            rdev: make_rdev(major, minor),
         })
     }
 
     impl_inode!();
 }
+
+impl DeviceINode for NullINode {
+    fn device_id(&self) -> (usize, usize) {
+        (1, 3)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        INode::io_control(self, cmd, data)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        INode::poll(self)
+    }
+}
@@ -2,12 +2,15 @@ use super::*;
 
 pub struct ZeroINode {
     inode_id: usize,
+    /// Device id of the `DevFS` it was added to
+    pub(super) dev: AtomicUsize,
 }
 
 impl ZeroINode {
     pub fn new() -> Self {
         Self {
             inode_id: DevFS::new_inode_id(),
+            dev: AtomicUsize::new(0),
         }
     }
 }
@@ -41,8 +44,9 @@ impl INode for ZeroINode {
     }
 
     fn metadata(&self) -> Result<Metadata> {
+        let (major, minor) = self.device_id();
         Ok(Metadata {
-            dev: 1,
+            dev: self.dev.load(Ordering::Relaxed),
             inode: self.inode_id,
             size: 0,
             blk_size: 0,
@@ -55,9 +59,23 @@ impl INode for ZeroINode {
             nlinks: 1,
             uid: 0,
             gid: 0,
-            rdev: make_rdev(1, 5),
+            rdev: make_rdev(major, minor),
         })
     }
 
     impl_inode!();
 }
+
+impl DeviceINode for ZeroINode {
+    fn device_id(&self) -> (usize, usize) {
+        (1, 5)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        INode::io_control(self, cmd, data)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        INode::poll(self)
+    }
+}
@@ -7,9 +7,11 @@ extern crate ext2;
 #[cfg(test)]
 mod tests;
 
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::ops::Range;
 use ext2::error::Error;
 use ext2::fs::sync::Synced;
@@ -18,11 +20,107 @@ use ext2::sector::{Address, Size512};
 use ext2::volume::size::Size;
 use ext2::volume::{Volume, VolumeCommit, VolumeSlice};
 use rcore_fs::dev::{DevError, Device};
+use rcore_fs::util::BlockIter;
 use rcore_fs::vfs;
+use spin::RwLock;
+
+/// Size of blocks in the cache of `Ext2Volume`, the smallest block size of ext2
+const CACHE_BLOCK_SIZE_LOG2: u8 = 10;
+const CACHE_BLOCK_SIZE: usize = 1 << CACHE_BLOCK_SIZE_LOG2;
+/// Max number of blocks in the cache of `Ext2Volume`
+const CACHE_CAPACITY: usize = 64;
+/// Offset of `s_magic` on the device, in the superblock at byte 1024
+const MAGIC_OFFSET: usize = 0x438;
+const EXT2_MAGIC: u16 = 0xef53;
 
 #[derive(Clone)]
 struct Ext2Volume {
     inner: Arc<dyn Device>,
+    /// Recently read blocks, shared by all clones
+    cache: Arc<RwLock<LruCache>>,
+}
+
+/// Blocks of the device, evicting the least recently used ones when full
+struct LruCache {
+    /// data and the time of last access of each block
+    blocks: BTreeMap<usize, (Vec<u8>, u64)>,
+    /// cached blocks by the time of last access
+    lru: BTreeMap<u64, usize>,
+    clock: u64,
+}
+
+impl LruCache {
+    fn new() -> Self {
+        LruCache {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Get block `id`, loading it with `load` if not cached
+    fn get_or_load(
+        &mut self,
+        id: usize,
+        load: impl FnOnce() -> Result<Vec<u8>, DevError>,
+    ) -> Result<&[u8], DevError> {
+        self.clock += 1;
+        let time = self.clock;
+        match self.blocks.get(&id) {
+            Some((_, last)) => {
+                self.lru.remove(last);
+            }
+            None => {
+                let data = load()?;
+                if self.blocks.len() >= CACHE_CAPACITY {
+                    let (&oldest, &victim) = self.lru.iter().next().unwrap();
+                    self.lru.remove(&oldest);
+                    self.blocks.remove(&victim);
+                }
+                self.blocks.insert(id, (data, time));
+            }
+        }
+        self.lru.insert(time, id);
+        let (data, last) = self.blocks.get_mut(&id).unwrap();
+        *last = time;
+        Ok(data.as_slice())
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.lru.clear();
+    }
+}
+
+impl Ext2Volume {
+    fn new(inner: Arc<dyn Device>) -> Self {
+        Ext2Volume {
+            inner,
+            cache: Arc::new(RwLock::new(LruCache::new())),
+        }
+    }
+
+    /// Read bytes at `offset` through the cache
+    fn read_cached(&self, offset: usize, buf: &mut [u8]) -> Result<(), DevError> {
+        let mut cache = self.cache.write();
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: CACHE_BLOCK_SIZE_LOG2,
+        };
+        for range in iter {
+            let data = cache.get_or_load(range.block, || {
+                // a block beyond the end of device reads as zeros
+                let mut data = vec![0; CACHE_BLOCK_SIZE];
+                self.inner
+                    .read_at(range.block * CACHE_BLOCK_SIZE, &mut data)?;
+                Ok(data)
+            })?;
+            buf[range.origin_begin() - offset..range.origin_end() - offset]
+                .copy_from_slice(&data[range.begin..range.end]);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -64,12 +162,20 @@ impl core::convert::From<DevError> for Ext2Error {
 }
 
 impl Ext2FileSystem {
+    /// Open an ext2 fs on `device`, or return `WrongFs` if its superblock magic is not found.
+    /// Errors of the `ext2` crate are reported as `DeviceError`.
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        let mut magic = [0u8; 2];
+        if device.read_at(MAGIC_OFFSET, &mut magic)? != magic.len()
+            || u16::from_le_bytes(magic) != EXT2_MAGIC
+        {
+            return Err(vfs::FsError::WrongFs);
+        }
         Ok(Self::open_internal(device)?)
     }
 
     fn open_internal(device: Arc<dyn Device>) -> Result<Arc<Self>, Ext2Error> {
-        let volume = Ext2Volume { inner: device };
+        let volume = Ext2Volume::new(device);
         let fs = Synced::new(volume.clone())?;
         Ok(Arc::new(Ext2FileSystem {
             _inner: fs,
@@ -85,8 +191,14 @@ impl Volume<u8, Size512> for Ext2Volume {
         Size::Unbounded
     }
 
-    fn commit(&mut self, _slice: Option<VolumeCommit<u8, Size512>>) -> Result<(), Self::Error> {
-        unimplemented!()
+    fn commit(&mut self, slice: Option<VolumeCommit<u8, Size512>>) -> Result<(), Self::Error> {
+        if let Some(slice) = slice {
+            let offset = slice.at_index().into_index() as usize;
+            self.inner.write_at(offset, &slice)?;
+        }
+        // cached blocks may be stale now
+        self.cache.write().clear();
+        Ok(())
     }
 
     unsafe fn slice_unchecked(
@@ -96,8 +208,7 @@ impl Volume<u8, Size512> for Ext2Volume {
         let index = range.start;
         let len = range.end - range.start;
         let mut vec = vec![0; len.into_index() as usize];
-        self.inner
-            .read_at(index.into_index() as usize, vec.as_mut_slice())
+        self.read_cached(index.into_index() as usize, vec.as_mut_slice())
             .unwrap();
         VolumeSlice::new_owned(vec, index)
     }
@@ -109,8 +220,7 @@ impl Volume<u8, Size512> for Ext2Volume {
         let index = range.start;
         let len = range.end - range.start;
         let mut vec = vec![0; len.into_index() as usize];
-        self.inner
-            .read_at(index.into_index() as usize, vec.as_mut_slice())?;
+        self.read_cached(index.into_index() as usize, vec.as_mut_slice())?;
         Ok(VolumeSlice::new_owned(vec, index))
     }
 }
@@ -1,17 +1,23 @@
 extern crate std;
 
 use crate::*;
-use std::fs::{self, OpenOptions};
+use rcore_fs::dev::std_impl::TestDevice;
+use std::fs::{self, File, OpenOptions};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-fn open_sample_file() -> Arc<Ext2FileSystem> {
+fn open_sample_image() -> File {
     fs::copy("ext2.img", "test.img").expect("failed to open ext2.img");
-    let file = OpenOptions::new()
+    OpenOptions::new()
         .read(true)
         .write(true)
         .open("test.img")
-        .expect("failed to open test.img");
+        .expect("failed to open test.img")
+}
+
+fn open_sample_file() -> Arc<Ext2FileSystem> {
+    let file = open_sample_image();
     Ext2FileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open Ext2")
 }
 
@@ -19,3 +25,31 @@ fn open_sample_file() -> Arc<Ext2FileSystem> {
 fn test_open() {
     let _ = open_sample_file();
 }
+
+#[test]
+fn cached_reads() {
+    let device = Arc::new(TestDevice::new(open_sample_image()));
+    let fs = Ext2FileSystem::open(device.clone()).expect("failed to open Ext2");
+    // the superblock, at byte 1024
+    let range = Address::new(2, 0)..Address::new(4, 0);
+    let first = fs._volume.slice(range.clone()).unwrap().to_vec();
+    let reads = device.reads.load(Ordering::SeqCst);
+    let second = fs._volume.slice(range).unwrap().to_vec();
+    assert_eq!(device.reads.load(Ordering::SeqCst), reads);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn open_sfs_image() {
+    use rcore_fs::vfs::FileSystem;
+    let file = tempfile::tempfile().unwrap();
+    let device = Arc::new(Mutex::new(file));
+    rcore_fs_sfs::SimpleFileSystem::create(device.clone(), 32 * 4096)
+        .unwrap()
+        .sync()
+        .unwrap();
+    assert_eq!(
+        Ext2FileSystem::open(device).err(),
+        Some(vfs::FsError::WrongFs)
+    );
+}
@@ -1,6 +1,6 @@
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyStatfs, ReplyWrite, Request,
+    ReplyOpen, ReplyStatfs, ReplyWrite, Request,
 };
 use rcore_fs::vfs;
 use std::collections::btree_map::BTreeMap;
@@ -33,11 +33,21 @@ impl VfsFuse {
             nsec: time.nsec,
         }
     }
-    fn trans_attr(info: vfs::Metadata) -> FileAttr {
+    fn trans_attr(&self, info: vfs::Metadata) -> FileAttr {
+        let blk_size = match info.blk_size {
+            0 => 512,
+            blk_size => blk_size,
+        };
+        // without holes, all blocks up to the size are allocated
+        let blocks = match self.fs.supports_holes() {
+            true => info.blocks,
+            false => info.size.div_ceil(blk_size),
+        };
         FileAttr {
             ino: info.inode as u64,
             size: info.size as u64,
-            blocks: info.blocks as u64,
+            // st_blocks is in 512-byte units, not those of the fs
+            blocks: (blocks * blk_size / 512) as u64,
             atime: Self::trans_time(info.atime),
             mtime: Self::trans_time(info.mtime),
             ctime: Self::trans_time(info.ctime),
@@ -77,6 +87,12 @@ impl VfsFuse {
             vfs::FsError::DirRemoved => ENOENT,
             vfs::FsError::DirNotEmpty => ENOTEMPTY,
             vfs::FsError::WrongFs => EINVAL,
+            vfs::FsError::StaleFileHandle => ESTALE,
+            vfs::FsError::PermissionDenied => EACCES,
+            vfs::FsError::ReadOnlyFs => EROFS,
+            vfs::FsError::NameTooLong => ENAMETOOLONG,
+            vfs::FsError::TooManyLinks => EMLINK,
+            vfs::FsError::ConnectionRefused => ECONNREFUSED,
             _ => EINVAL,
         }
     }
@@ -111,14 +127,14 @@ impl Filesystem for VfsFuse {
         let target = try_vfs!(reply, inode.lookup(name.to_str().unwrap()));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
-        let attr = Self::trans_attr(info);
+        let attr = self.trans_attr(info);
         reply.entry(&TTL, &attr, 0);
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         let inode = try_vfs!(reply, self.get_inode(ino));
         let info = try_vfs!(reply, inode.metadata());
-        let attr = Self::trans_attr(info);
+        let attr = self.trans_attr(info);
         reply.attr(&TTL, &attr);
     }
 
@@ -160,7 +176,7 @@ impl Filesystem for VfsFuse {
             info.mtime = Self::trans_time_r(mtime);
         }
         try_vfs!(reply, inode.set_metadata(&info));
-        let attr = Self::trans_attr(info);
+        let attr = self.trans_attr(info);
         reply.attr(&TTL, &attr);
     }
 
@@ -178,7 +194,7 @@ impl Filesystem for VfsFuse {
         let target = try_vfs!(reply, inode.create(name, vfs::FileType::File, mode));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
-        let attr = Self::trans_attr(info);
+        let attr = self.trans_attr(info);
         reply.entry(&TTL, &attr, 0);
     }
 
@@ -188,7 +204,7 @@ impl Filesystem for VfsFuse {
         let target = try_vfs!(reply, inode.create(name, vfs::FileType::Dir, mode));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
-        let attr = Self::trans_attr(info);
+        let attr = self.trans_attr(info);
         reply.entry(&TTL, &attr, 0);
     }
 
@@ -233,10 +249,16 @@ impl Filesystem for VfsFuse {
         let newparent = try_vfs!(reply, self.get_inode(newparent));
         try_vfs!(reply, newparent.link(newname, inode));
         let info = try_vfs!(reply, inode.metadata());
-        let attr = Self::trans_attr(info);
+        let attr = self.trans_attr(info);
         reply.entry(&TTL, &attr, 0);
     }
 
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        try_vfs!(reply, inode.open());
+        reply.opened(0, 0);
+    }
+
     fn read(
         &mut self,
         _req: &Request,
@@ -274,6 +296,21 @@ impl Filesystem for VfsFuse {
         reply.ok();
     }
 
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let inode = try_vfs!(reply, self.get_inode(ino));
+        try_vfs!(reply, inode.close());
+        reply.ok();
+    }
+
     fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
         let inode = try_vfs!(reply, self.get_inode(ino));
         if datasync {
@@ -294,15 +331,14 @@ impl Filesystem for VfsFuse {
     ) {
         let inode = try_vfs!(reply, self.get_inode(ino));
         for i in offset as usize.. {
-            let name = match inode.get_entry(i) {
-                Ok(name) => name,
+            let (ino, name) = match inode.read_dir_plus(i) {
+                Ok(entry) => entry,
                 Err(vfs::FsError::EntryNotFound) => break,
                 e @ _ => try_vfs!(reply, e),
             };
             let inode = try_vfs!(reply, inode.find(name.as_str()));
-            let info = try_vfs!(reply, inode.metadata());
-            let kind = Self::trans_type(info.type_);
-            let full = reply.add(info.inode as u64, i as i64 + 1, kind, name);
+            let kind = Self::trans_type(try_vfs!(reply, inode.file_type()));
+            let full = reply.add(ino as u64, i as i64 + 1, kind, name);
             if full {
                 break;
             }
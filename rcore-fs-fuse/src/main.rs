@@ -1,9 +1,12 @@
+use std::fmt::Display;
 use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use structopt::StructOpt;
 
+#[cfg(target_os = "linux")]
+use rcore_fs::dev::std_impl::BlockFileDevice;
 use rcore_fs::dev::std_impl::StdTimeProvider;
 use rcore_fs::vfs::FileSystem;
 #[cfg(feature = "use_fuse")]
@@ -32,6 +35,11 @@ struct Opt {
     /// File system: [sfs | sefs | ramfs]
     #[structopt(short = "f", long = "fs", default_value = "sfs")]
     fs: String,
+
+    /// Treat <image> as a raw host block device (sfs only)
+    #[cfg(target_os = "linux")]
+    #[structopt(long = "device")]
+    device: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -44,6 +52,10 @@ enum Cmd {
     #[structopt(name = "unzip")]
     Unzip,
 
+    /// Load file <dir> in <image> into caches
+    #[structopt(name = "prefetch")]
+    Prefetch,
+
     /// Mount <image> to <dir>
     #[cfg(feature = "use_fuse")]
     #[structopt(name = "mount")]
@@ -56,20 +68,52 @@ enum Cmd {
 fn main() {
     env_logger::init();
     let opt = Opt::from_args();
+    if let Err(msg) = run(opt) {
+        eprintln!("error: {}", msg);
+        std::process::exit(1);
+    }
+}
+
+/// Describe a failure to `op` at `path`, caused by the error passed in
+fn context<'a, E: Display>(op: &'a str, path: &'a Path) -> impl FnOnce(E) -> String + 'a {
+    move |e| format!("failed to {} {}: {}", op, path.display(), e)
+}
 
+fn run(opt: Opt) -> Result<(), String> {
     // open or create
     let create = match opt.cmd {
         #[cfg(feature = "use_fuse")]
         Cmd::Mount => !opt.image.is_dir() && !opt.image.is_file(),
         Cmd::Zip => true,
-        Cmd::Unzip => false,
+        Cmd::Unzip | Cmd::Prefetch => false,
         Cmd::GitVersion => {
             println!("{}", git_version!());
-            return;
+            return Ok(());
         }
     };
 
     let fs: Arc<dyn FileSystem> = match opt.fs.as_str() {
+        #[cfg(target_os = "linux")]
+        "sfs" if opt.device => {
+            let device = BlockFileDevice::open(&opt.image, create)
+                .map_err(context("open device", &opt.image))?;
+            match create {
+                true => {
+                    let space = device.size_hint();
+                    sfs::SimpleFileSystem::create_with_time_provider(
+                        Arc::new(device),
+                        space,
+                        &StdTimeProvider,
+                    )
+                    .map_err(context("create sfs on", &opt.image))?
+                }
+                false => sfs::SimpleFileSystem::open_with_time_provider(
+                    Arc::new(device),
+                    &StdTimeProvider,
+                )
+                .map_err(context("open sfs", &opt.image))?,
+            }
+        }
         "sfs" => {
             let file = OpenOptions::new()
                 .read(true)
@@ -77,40 +121,67 @@ fn main() {
                 .create(create)
                 .truncate(create)
                 .open(&opt.image)
-                .expect("failed to open image");
+                .map_err(context("open image", &opt.image))?;
             let device = Mutex::new(file);
             const MAX_SPACE: usize = 0x1000 * 0x1000 * 1024; // 1G
             match create {
-                true => sfs::SimpleFileSystem::create(Arc::new(device), MAX_SPACE)
-                    .expect("failed to create sfs"),
-                false => sfs::SimpleFileSystem::open(Arc::new(device)).expect("failed to open sfs"),
+                true => sfs::SimpleFileSystem::create_with_time_provider(
+                    Arc::new(device),
+                    MAX_SPACE,
+                    &StdTimeProvider,
+                )
+                .map_err(context("create sfs on", &opt.image))?,
+                false => sfs::SimpleFileSystem::open_with_time_provider(
+                    Arc::new(device),
+                    &StdTimeProvider,
+                )
+                .map_err(context("open sfs", &opt.image))?,
             }
         }
         "sefs" => {
-            std::fs::create_dir_all(&opt.image).unwrap();
+            std::fs::create_dir_all(&opt.image).map_err(context("create dir", &opt.image))?;
             let device = sefs::dev::StdStorage::new(&opt.image);
             match create {
-                true => sefs::SEFS::create(Box::new(device), &StdTimeProvider)
-                    .expect("failed to create sefs"),
-                false => sefs::SEFS::open(Box::new(device), &StdTimeProvider)
-                    .expect("failed to open sefs"),
+                true => sefs::SEFS::create(Arc::new(device), &StdTimeProvider)
+                    .map_err(context("create sefs on", &opt.image))?,
+                false => sefs::SEFS::open(Arc::new(device), &StdTimeProvider)
+                    .map_err(context("open sefs", &opt.image))?,
             }
         }
         "ramfs" => ramfs::RamFS::new(),
-        _ => panic!("unsupported file system"),
+        _ => return Err(format!("unsupported file system: {}", opt.fs)),
     };
     match opt.cmd {
         #[cfg(feature = "use_fuse")]
         Cmd::Mount => {
-            fuse::mount(VfsFuse::new(fs), &opt.dir, &[]).expect("failed to mount fs");
+            fuse::mount(VfsFuse::new(fs), &opt.dir, &[])
+                .map_err(context("mount fs at", &opt.dir))?;
         }
         Cmd::Zip => {
-            zip_dir(&opt.dir, fs.root_inode()).expect("failed to zip fs");
+            zip_dir(&opt.dir, fs.root_inode()).map_err(context("zip", &opt.dir))?;
         }
         Cmd::Unzip => {
-            std::fs::create_dir(&opt.dir).expect("failed to create dir");
-            unzip_dir(&opt.dir, fs.root_inode()).expect("failed to unzip fs");
+            std::fs::create_dir(&opt.dir).map_err(context("create dir", &opt.dir))?;
+            unzip_dir(&opt.dir, fs.root_inode()).map_err(context("unzip to", &opt.dir))?;
+        }
+        Cmd::Prefetch => {
+            let path = opt
+                .dir
+                .to_str()
+                .ok_or_else(|| format!("invalid path: {}", opt.dir.display()))?;
+            let inode = fs
+                .root_inode()
+                .lookup(path)
+                .map_err(context("find", &opt.dir))?;
+            let size = inode
+                .metadata()
+                .map_err(context("get metadata of", &opt.dir))?
+                .size;
+            inode
+                .readahead(0, size)
+                .map_err(context("prefetch", &opt.dir))?;
         }
         Cmd::GitVersion => unreachable!(),
     }
+    Ok(())
 }
@@ -1,9 +1,10 @@
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::io::{Read, Write};
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Arc;
 
@@ -15,75 +16,238 @@ use rcore_fs::{
 const DEFAULT_MODE: u32 = 0o664;
 const BUF_SIZE: usize = 0x1000;
 
+/// A buffer for copying a file from or to `inode`, sized as preferred by its fs
+pub fn io_buf(inode: &Arc<dyn INode>) -> Vec<u8> {
+    vec![0; inode.fs().preferred_io_size()]
+}
+
+/// An error copying `path`, between the host and a file system
+#[derive(Debug)]
+pub struct PathError {
+    pub path: PathBuf,
+    pub source: Box<dyn Error>,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl Error for PathError {}
+
+/// Attach `path` to an error
+fn at<E: Into<Box<dyn Error>>>(path: &Path) -> impl FnOnce(E) -> Box<dyn Error> + '_ {
+    move |e| {
+        Box::new(PathError {
+            path: path.to_path_buf(),
+            source: e.into(),
+        })
+    }
+}
+
+/// Copy the host directory `path` into `inode`.
+/// Errors are `PathError`s naming the host path that failed.
 pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    let dir = fs::read_dir(path)?;
+    let dir = fs::read_dir(path).map_err(at(path))?;
     for entry in dir {
-        let entry = entry?;
-        let name_ = entry.file_name();
-        let name = name_.to_str().unwrap();
-        let type_ = entry.file_type()?;
-        if type_.is_file() {
-            let inode = inode.create(name, FileType::File, DEFAULT_MODE)?;
-            let mut file = fs::File::open(entry.path())?;
-            inode.resize(file.metadata()?.len() as usize)?;
-            let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
-            let mut offset = 0usize;
-            let mut len = BUF_SIZE;
-            while len == BUF_SIZE {
-                len = file.read(&mut buf)?;
-                inode.write_at(offset, &buf[..len])?;
-                offset += len;
-            }
-        } else if type_.is_dir() {
-            let inode = inode.create(name, FileType::Dir, DEFAULT_MODE)?;
-            zip_dir(entry.path().as_path(), inode)?;
-        } else if type_.is_symlink() {
-            let target = fs::read_link(entry.path())?;
-            let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
-            #[cfg(unix)]
-            let data = target.as_os_str().as_bytes();
-            #[cfg(windows)]
-            let data = target.to_str().unwrap().as_bytes();
-            inode.resize(data.len())?;
-            inode.write_at(0, data)?;
+        let entry = entry.map_err(at(path))?;
+        let path = entry.path();
+        let type_ = entry.file_type().map_err(at(&path))?;
+        if type_.is_dir() {
+            let inode = inode
+                .create(
+                    file_name(&path).map_err(at(&path))?,
+                    FileType::Dir,
+                    DEFAULT_MODE,
+                )
+                .map_err(at(&path))?;
+            zip_dir(&path, inode)?;
+        } else {
+            zip_entry(&path, type_, &inode).map_err(at(&path))?;
+        }
+    }
+    Ok(())
+}
+
+fn file_name(path: &Path) -> Result<&str, &'static str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("invalid file name")
+}
+
+/// Copy the host file or symlink at `path` into the directory `inode`
+fn zip_entry(
+    path: &Path,
+    type_: fs::FileType,
+    inode: &Arc<dyn INode>,
+) -> Result<(), Box<dyn Error>> {
+    let name = file_name(path)?;
+    if type_.is_file() {
+        let inode = inode.create(name, FileType::File, DEFAULT_MODE)?;
+        let mut file = fs::File::open(path)?;
+        inode.resize(file.metadata()?.len() as usize)?;
+        let mut buf = io_buf(&inode);
+        let mut offset = 0usize;
+        let mut len = buf.len();
+        while len == buf.len() {
+            len = file.read(&mut buf)?;
+            inode.write_at(offset, &buf[..len])?;
+            offset += len;
         }
+    } else if type_.is_symlink() {
+        let target = fs::read_link(path)?;
+        let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
+        #[cfg(unix)]
+        let data = target.as_os_str().as_bytes();
+        #[cfg(windows)]
+        let data = target.to_str().unwrap().as_bytes();
+        inode.resize(data.len())?;
+        inode.write_at(0, data)?;
     }
     Ok(())
 }
 
+/// Copy the directory `inode` to the host directory `path`.
+/// Errors are `PathError`s naming the host path that failed.
 pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    let files = inode.list()?;
+    let files = inode.list().map_err(at(path))?;
     for name in files.iter().skip(2) {
-        let inode = inode.lookup(name.as_str())?;
         let mut path = path.to_path_buf();
         path.push(name);
-        let info = inode.metadata()?;
-        match info.type_ {
-            FileType::File => {
-                let mut file = fs::File::create(&path)?;
-                let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
-                let mut offset = 0usize;
-                let mut len = BUF_SIZE;
-                while len == BUF_SIZE {
-                    len = inode.read_at(offset, buf.as_mut())?;
-                    file.write(&buf[..len])?;
-                    offset += len;
+        let inode = inode.lookup(name.as_str()).map_err(at(&path))?;
+        let info = inode.metadata().map_err(at(&path))?;
+        if info.type_ == FileType::Dir {
+            fs::create_dir(&path).map_err(at(&path))?;
+            unzip_dir(path.as_path(), inode)?;
+        } else {
+            unzip_entry(&path, info.type_, &inode).map_err(at(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the file or symlink `inode` to the host `path`
+fn unzip_entry(path: &Path, type_: FileType, inode: &Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
+    match type_ {
+        FileType::File => {
+            // one buffer at a time, however large the file is
+            let mut file = fs::File::create(path)?;
+            let mut buf = io_buf(inode);
+            let mut offset = 0usize;
+            loop {
+                let len = inode.read_at(offset, buf.as_mut())?;
+                if len == 0 {
+                    break;
                 }
+                file.write_all(&buf[..len])?;
+                offset += len;
             }
-            FileType::Dir => {
-                fs::create_dir(&path)?;
-                unzip_dir(path.as_path(), inode)?;
-            }
-            FileType::SymLink => {
-                let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
-                let len = inode.read_at(0, buf.as_mut())?;
-                #[cfg(unix)]
-                std::os::unix::fs::symlink(str::from_utf8(&buf[..len]).unwrap(), path)?;
-                #[cfg(windows)]
-                std::os::windows::fs::symlink_file(str::from_utf8(&buf[..len]).unwrap(), path)?;
-            }
-            _ => panic!("unsupported file type"),
         }
+        FileType::SymLink => {
+            let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
+            let len = inode.read_at(0, buf.as_mut())?;
+            let target = str::from_utf8(&buf[..len])?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(target, path)?;
+            #[cfg(windows)]
+            std::os::windows::fs::symlink_file(target, path)?;
+        }
+        _ => return Err(format!("unsupported file type {:?}", type_).into()),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcore_fs::vfs::{FileSystem, Metadata, PollStatus, Result};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::any::Any;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// Wraps the INodes of a tree, recording the largest buffer read into
+    struct MaxReadINode {
+        inner: Arc<dyn INode>,
+        max_read: Arc<AtomicUsize>,
+    }
+
+    impl INode for MaxReadINode {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            self.max_read.fetch_max(buf.len(), Ordering::SeqCst);
+            self.inner.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            self.inner.write_at(offset, buf)
+        }
+        fn poll(&self) -> Result<PollStatus> {
+            self.inner.poll()
+        }
+        fn metadata(&self) -> Result<Metadata> {
+            self.inner.metadata()
+        }
+        fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+            Ok(Arc::new(MaxReadINode {
+                inner: self.inner.find(name)?,
+                max_read: self.max_read.clone(),
+            }))
+        }
+        fn get_entry(&self, id: usize) -> Result<String> {
+            self.inner.get_entry(id)
+        }
+        fn fs(&self) -> Arc<dyn FileSystem> {
+            self.inner.fs()
+        }
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn zip_with_preferred_io_size() {
+        let image = tempfile::tempfile().unwrap();
+        let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(image)), 32 * 4096).unwrap();
+        assert_eq!(sfs.preferred_io_size(), sfs.info().bsize);
+        assert_eq!(io_buf(&sfs.root_inode()).len(), sfs.info().bsize);
+
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("file"), &data).unwrap();
+        zip_dir(src.path(), sfs.root_inode()).unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        let dst = dst.path().join("unzip");
+        fs::create_dir(&dst).unwrap();
+        unzip_dir(&dst, sfs.root_inode()).unwrap();
+        assert_eq!(fs::read(dst.join("file")).unwrap(), data);
+    }
+
+    #[test]
+    fn unzip_large_file_in_chunks() {
+        let image = tempfile::tempfile().unwrap();
+        let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(image)), 1024 * 4096).unwrap();
+        let data: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+        let src = tempfile::tempdir().unwrap();
+        fs::create_dir(src.path().join("dir")).unwrap();
+        fs::write(src.path().join("dir/large"), &data).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("dir/large", src.path().join("link")).unwrap();
+        zip_dir(src.path(), sfs.root_inode()).unwrap();
+
+        let max_read = Arc::new(AtomicUsize::new(0));
+        let root = Arc::new(MaxReadINode {
+            inner: sfs.root_inode(),
+            max_read: max_read.clone(),
+        });
+        let dst = tempfile::tempdir().unwrap();
+        unzip_dir(dst.path(), root).unwrap();
+        assert_eq!(fs::read(dst.path().join("dir/large")).unwrap(), data);
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(dst.path().join("link")).unwrap(),
+            Path::new("dir/large")
+        );
+        assert!(max_read.load(Ordering::SeqCst) <= BUF_SIZE.max(sfs.preferred_io_size()));
+    }
+}
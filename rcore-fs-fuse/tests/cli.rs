@@ -0,0 +1,49 @@
+use std::fs;
+use std::process::Command;
+
+fn cli() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_rcore-fs-fuse"))
+}
+
+#[test]
+fn unzip_corrupt_image() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("image");
+    fs::write(&image, vec![0xa5u8; 64 * 4096]).unwrap();
+
+    let output = cli()
+        .arg(&image)
+        .arg(dir.path().join("out"))
+        .arg("unzip")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("failed to open sfs {}: WrongFs", image.display())),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn zip_missing_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+
+    let output = cli()
+        .arg(dir.path().join("image"))
+        .arg(&missing)
+        .arg("zip")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    // the failing path is named, along with the cause
+    assert!(
+        stderr.contains(&format!("failed to zip {0}: {0}: ", missing.display())),
+        "{}",
+        stderr
+    );
+}
@@ -1,6 +1,7 @@
 #![feature(get_mut_unchecked)]
 
 use core::any::Any;
+use rcore_fs::util::{checked_end, validate_name};
 use rcore_fs::vfs::*;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
@@ -14,16 +15,25 @@ extern crate log;
 /// File system at host
 pub struct HostFS {
     path: PathBuf,
+    /// The last `FsInfo` read from the host, returned if it cannot be read again
+    info: Mutex<FsInfo>,
     self_ref: Weak<HostFS>,
 }
 
 /// INode for `HostFS`
 pub struct HNode {
     path: PathBuf,
-    file: Mutex<Option<std::fs::File>>,
+    file: Mutex<Option<HostFile>>,
     fs: Arc<HostFS>,
 }
 
+/// A host file opened by `HNode`
+struct HostFile {
+    file: std::fs::File,
+    /// Opened with write access
+    writable: bool,
+}
+
 impl FileSystem for HostFS {
     fn sync(&self) -> Result<()> {
         warn!("HostFS: sync is unimplemented");
@@ -39,19 +49,25 @@ impl FileSystem for HostFS {
     }
 
     fn info(&self) -> FsInfo {
-        let statvfs =
-            nix::sys::statvfs::statvfs(&self.path).expect("fail to get info from host fs");
-        FsInfo {
-            bsize: statvfs.block_size() as _,
-            frsize: statvfs.fragment_size() as _,
-            blocks: statvfs.blocks() as _,
-            bfree: statvfs.blocks_free() as _,
-            bavail: statvfs.blocks_available() as _,
-            files: statvfs.files() as _,
-            ffree: statvfs.files_free() as _,
-            namemax: statvfs.name_max() as _,
+        let mut info = self.info.lock().unwrap();
+        match nix::sys::statvfs::statvfs(&self.path) {
+            Ok(statvfs) => *info = statvfs_to_info(&statvfs),
+            // e.g. the host path was removed or unmounted underneath
+            Err(e) => warn!("HostFS: fail to get info from host fs: {}", e),
+        }
+        *info
+    }
+
+    fn preferred_io_size(&self) -> usize {
+        match nix::sys::statvfs::statvfs(&self.path) {
+            Ok(statvfs) => statvfs.block_size() as _,
+            Err(_) => 0x1000,
         }
     }
+
+    fn fs_type(&self) -> &'static str {
+        "hostfs"
+    }
 }
 
 impl HostFS {
@@ -59,6 +75,7 @@ impl HostFS {
     pub fn new(path: impl AsRef<Path>) -> Arc<HostFS> {
         HostFS {
             path: path.as_ref().to_path_buf(),
+            info: Mutex::new(FsInfo::default()),
             self_ref: Weak::default(),
         }
         .wrap()
@@ -78,16 +95,25 @@ impl HostFS {
 
 impl INode for HNode {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        let mut guard = self.open_file()?;
-        let file = guard.as_mut().unwrap();
+        let mut guard = self.open_file(false)?;
+        let file = &mut guard.as_mut().unwrap().file;
         file.seek(SeekFrom::Start(offset as u64))?;
-        let len = file.read(buf)?;
+        // a single read may return less before the end of file
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
         Ok(len)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
-        let mut guard = self.open_file()?;
-        let file = guard.as_mut().unwrap();
+        // offsets on the host are `off_t`
+        checked_end(offset, buf.len(), i64::MAX as usize)?;
+        let mut guard = self.open_file(true)?;
+        let file = &mut guard.as_mut().unwrap().file;
         file.seek(SeekFrom::Start(offset as u64))?;
         let len = file.write(buf)?;
         Ok(len)
@@ -128,21 +154,27 @@ impl INode for HNode {
     }
 
     fn sync_all(&self) -> Result<()> {
-        self.open_file()?.as_mut().unwrap().sync_all()?;
+        self.open_file(false)?.as_ref().unwrap().file.sync_all()?;
         Ok(())
     }
 
     fn sync_data(&self) -> Result<()> {
-        self.open_file()?.as_mut().unwrap().sync_data()?;
+        self.open_file(false)?.as_ref().unwrap().file.sync_data()?;
         Ok(())
     }
 
     fn resize(&self, len: usize) -> Result<()> {
-        self.open_file()?.as_mut().unwrap().set_len(len as u64)?;
+        self.open_file(true)?
+            .as_ref()
+            .unwrap()
+            .file
+            .set_len(len as u64)?;
         Ok(())
     }
 
     fn create(&self, name: &str, type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        // the host rejects names that are too long with ENAMETOOLONG
+        validate_name(name, usize::MAX)?;
         let new_path = self.path.join(name);
         if new_path.exists() {
             return Err(FsError::EntryExist);
@@ -164,6 +196,7 @@ impl INode for HNode {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        validate_name(name, usize::MAX)?;
         let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
         std::fs::hard_link(&other.path, &self.path.join(name))?;
         Ok(())
@@ -182,6 +215,7 @@ impl INode for HNode {
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        validate_name(new_name, usize::MAX)?;
         let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
         let old_path = self.path.join(old_name);
         let new_path = target.path.join(new_name);
@@ -190,7 +224,12 @@ impl INode for HNode {
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
-        let new_path = self.path.join(name);
+        // do not escape from the root of HostFS
+        let new_path = if name == ".." && self.path == self.fs.path {
+            self.path.clone()
+        } else {
+            self.path.join(name)
+        };
         if new_path.exists() {
             Ok(Arc::new(HNode {
                 path: new_path,
@@ -203,19 +242,45 @@ impl INode for HNode {
     }
 
     fn get_entry(&self, id: usize) -> Result<String> {
-        if self.path.is_dir() {
-            self.path
+        if !self.path.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => self
+                .path
                 .read_dir()?
-                .nth(id)
+                .nth(i - 2)
                 .ok_or(FsError::EntryNotFound)??
                 .file_name()
                 .into_string()
-                .map_err(|_| FsError::InvalidParam)
-        } else {
-            Err(FsError::NotDir)
+                .map_err(|_| FsError::InvalidParam),
         }
     }
 
+    fn snapshot_entries(&self) -> Result<Vec<(String, usize)>> {
+        if !self.path.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        // read the directory once, the host may still modify it meanwhile
+        let mut entries = vec![
+            (String::from("."), self.metadata()?.inode),
+            (String::from(".."), self.find("..")?.metadata()?.inode),
+        ];
+        for entry in self.path.read_dir()? {
+            let entry = entry?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| FsError::InvalidParam)?;
+            // not following symlinks
+            let metadata: Metadata = entry.metadata()?.into();
+            entries.push((name, metadata.inode));
+        }
+        Ok(entries)
+    }
+
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
     }
@@ -234,10 +299,12 @@ impl INode for HNode {
 }
 
 impl HNode {
-    /// Ensure to open the file and store a `File` into `self.file`,
+    /// Ensure to open the file and store a `HostFile` into `self.file`,
     /// return the `MutexGuard`.
+    /// The file is opened read-only until `write` access is asked for,
+    /// so that files the process cannot write to can still be read.
     /// If the type of `self.path` is not file, then return Err
-    fn open_file(&self) -> Result<MutexGuard<Option<std::fs::File>>> {
+    fn open_file(&self, write: bool) -> Result<MutexGuard<'_, Option<HostFile>>> {
         if !self.path.exists() {
             return Err(FsError::EntryNotFound);
         }
@@ -245,14 +312,127 @@ impl HNode {
             return Err(FsError::NotFile);
         }
         let mut maybe_file = self.file.lock().unwrap();
-        if maybe_file.is_none() {
+        let reopen = match maybe_file.as_ref() {
+            Some(file) => write && !file.writable,
+            None => true,
+        };
+        if reopen {
             let file = std::fs::OpenOptions::new()
                 .read(true)
-                .write(true)
-                .create(true)
+                .write(write)
                 .open(&self.path)?;
-            *maybe_file = Some(file);
+            *maybe_file = Some(HostFile {
+                file,
+                writable: write,
+            });
         }
         Ok(maybe_file)
     }
 }
+
+fn statvfs_to_info(statvfs: &nix::sys::statvfs::Statvfs) -> FsInfo {
+    FsInfo {
+        bsize: statvfs.block_size() as _,
+        frsize: statvfs.fragment_size() as _,
+        blocks: statvfs.blocks() as _,
+        bfree: statvfs.blocks_free() as _,
+        bavail: statvfs.blocks_available() as _,
+        files: statvfs.files() as _,
+        ffree: statvfs.files_free() as _,
+        namemax: statvfs.name_max() as _,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcore_fs::testing::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn info_after_path_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("root");
+        std::fs::create_dir(&path).unwrap();
+        let fs = HostFS::new(&path);
+        let info = fs.info();
+        assert_ne!(info.bsize, 0);
+        std::fs::remove_dir(&path).unwrap();
+        // falls back to the last known info
+        assert_eq!(fs.info().bsize, info.bsize);
+        // never read successfully
+        std::fs::create_dir(&path).unwrap();
+        let fs = HostFS::new(&path);
+        std::fs::remove_dir(&path).unwrap();
+        assert_eq!(fs.info().blocks, 0);
+    }
+
+    #[test]
+    fn not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = HostFS::new(dir.path()).root_inode();
+        assert_eq!(root.find("file").err(), Some(FsError::EntryNotFound));
+        assert_eq!(root.unlink("file"), Err(FsError::EntryNotFound));
+        assert_eq!(root.lookup("dir/file").err(), Some(FsError::EntryNotFound));
+    }
+
+    #[test]
+    fn permission_denied() {
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o500)).unwrap();
+        // permissions are not checked for a privileged user
+        if std::fs::File::create(locked.join("probe")).is_ok() {
+            return;
+        }
+        let root = HostFS::new(dir.path()).root_inode();
+        let locked = root.find("locked").unwrap();
+        assert_eq!(
+            locked.create("file", FileType::File, 0o777).err(),
+            Some(FsError::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn read_only_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"hello").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+        let file = HostFS::new(dir.path()).root_inode().find("file").unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(file.read_at(0, &mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn short_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        check_short_reads(HostFS::new(dir.path()).root_inode());
+    }
+
+    #[test]
+    fn invalid_names() {
+        let dir = tempfile::tempdir().unwrap();
+        check_invalid_names(HostFS::new(dir.path()).root_inode());
+    }
+
+    #[test]
+    fn write_at_offset_overflow() {
+        let dir = tempfile::tempdir().unwrap();
+        check_write_at_offset_overflow(HostFS::new(dir.path()).root_inode());
+    }
+
+    #[test]
+    fn io_error_round_trip() {
+        let round_trip = |err| FsError::from(std::io::Error::from(err));
+        assert_eq!(round_trip(FsError::EntryNotFound), FsError::EntryNotFound);
+        assert_eq!(round_trip(FsError::EntryExist), FsError::EntryExist);
+        assert_eq!(
+            round_trip(FsError::PermissionDenied),
+            FsError::PermissionDenied
+        );
+        assert_eq!(round_trip(FsError::DirNotEmpty), FsError::DirNotEmpty);
+    }
+}
@@ -9,8 +9,9 @@ use alloc::{
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec::Vec,
 };
-use core::{any::Any, future::Future, pin::Pin};
+use core::{any::Any, future::Future, pin::Pin, str, sync::atomic::AtomicBool};
 use rcore_fs::vfs::*;
 use spin::RwLock;
 
@@ -76,6 +77,35 @@ impl MountFS {
         }
         .wrap()
     }
+
+    /// This and all file systems mounted in it recursively, as listed in `/proc/mounts`
+    pub fn mount_entries(&self) -> Result<Vec<MountEntry>> {
+        let mut entries = Vec::new();
+        self.push_mount_entries(&mut entries)?;
+        Ok(entries)
+    }
+
+    fn push_mount_entries(&self, entries: &mut Vec<MountEntry>) -> Result<()> {
+        entries.push(MountEntry {
+            path: self.mountpoint_root_inode().path()?,
+            fs_type: self.inner.fs_type(),
+            read_only: self.inner.mount_options().read_only,
+        });
+        for mount_fs in self.mountpoints.read().values() {
+            mount_fs.push_mount_entries(entries)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mounted file system, see `MountFS::mount_entries`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    /// Absolute path of the mount point
+    pub path: String,
+    /// See `FileSystem::fs_type`
+    pub fs_type: &'static str,
+    pub read_only: bool,
 }
 
 impl MNode {
@@ -114,20 +144,31 @@ impl MNode {
     }
 
     /// Get the root INode of the mounted fs at here.
-    /// Return self if no mounted fs.
+    /// Return self if no mounted fs, or its metadata can not be read.
     fn overlaid_inode(&self) -> Arc<MNode> {
-        let inode_id = self.metadata().unwrap().inode;
-        if let Some(sub_vfs) = self.vfs.mountpoints.read().get(&inode_id) {
-            sub_vfs.mountpoint_root_inode()
-        } else {
-            self.self_ref.upgrade().unwrap()
+        if let Ok(metadata) = self.metadata() {
+            if let Some(sub_vfs) = self.vfs.mountpoints.read().get(&metadata.inode) {
+                return sub_vfs.mountpoint_root_inode();
+            }
         }
+        self.self_ref.upgrade().unwrap()
     }
 
-    /// Is the root INode of its FS?
+    /// Is a file system mounted at the child `name` of this directory?
+    fn is_mounted_at(&self, name: &str) -> Result<bool> {
+        let inode_id = self.inode.find(name)?.metadata()?.inode;
+        Ok(self.vfs.mountpoints.read().contains_key(&inode_id))
+    }
+
+    /// Is the root INode of its FS? Not if either metadata can not be read.
     fn is_mountpoint_root(&self) -> bool {
-        self.inode.fs().root_inode().metadata().unwrap().inode
-            == self.inode.metadata().unwrap().inode
+        match (
+            self.inode.fs().root_inode().metadata(),
+            self.inode.metadata(),
+        ) {
+            (Ok(root), Ok(this)) => root.inode == this.inode,
+            _ => false,
+        }
     }
 
     /// Strong type version of `create()`
@@ -140,6 +181,32 @@ impl MNode {
         .wrap())
     }
 
+    /// Strong type version of `create2()`
+    pub fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<Self>> {
+        Ok(MNode {
+            inode: self.inode.create2(name, type_, mode, data)?,
+            vfs: self.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap())
+    }
+
+    /// Strong type version of `create_with_content()`
+    pub fn create_with_content(&self, name: &str, mode: u32, data: &[u8]) -> Result<Arc<Self>> {
+        Ok(MNode {
+            inode: self.inode.create_with_content(name, mode, data)?,
+            vfs: self.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap())
+    }
+
     /// Strong type version of `find()`
     pub fn find(&self, root: bool, name: &str) -> Result<Arc<Self>> {
         match name {
@@ -184,6 +251,81 @@ impl MNode {
         }
     }
 
+    /// The root INode of the outermost `MountFS`
+    fn global_root(&self) -> Arc<MNode> {
+        match &self.vfs.self_mountpoint {
+            Some(inode) => inode.global_root(),
+            None => self.vfs.mountpoint_root_inode(),
+        }
+    }
+
+    /// Lookup `path` across mount points, following at most `max_symlinks` symlinks.
+    /// A symlink as the last component is followed only if `follow_final`,
+    /// like `O_NOFOLLOW` / `AT_SYMLINK_NOFOLLOW` when not.
+    pub fn lookup(&self, path: &str, follow_final: bool, max_symlinks: usize) -> Result<Arc<Self>> {
+        let mut symlinks = 0;
+        let (mut dir, mut rest_path) = match path.strip_prefix('/') {
+            Some(rest) => (self.global_root(), String::from(rest)),
+            None => (self.self_ref.upgrade().unwrap(), String::from(path)),
+        };
+        while !rest_path.is_empty() {
+            let (name, rest) = match rest_path.find('/') {
+                Some(pos) => (&rest_path[..pos], &rest_path[pos + 1..]),
+                None => (rest_path.as_str(), ""),
+            };
+            if name.is_empty() {
+                rest_path = String::from(rest);
+                continue;
+            }
+            if dir.file_type()? != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            let inode = dir.find(false, name)?;
+            let is_final = rest.is_empty();
+            if inode.file_type()? == FileType::SymLink && (follow_final || !is_final) {
+                if symlinks == max_symlinks {
+                    return Err(FsError::SymLoop);
+                }
+                symlinks += 1;
+                let link_path = inode.inode.read_link()?;
+                // continue from `dir`, or from the root for absolute links
+                let link_path = match link_path.strip_prefix('/') {
+                    Some(link_path) => {
+                        dir = self.global_root();
+                        link_path
+                    }
+                    None => &link_path,
+                };
+                rest_path = String::from(link_path) + "/" + rest;
+            } else {
+                rest_path = String::from(rest);
+                dir = inode;
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Absolute path from the root of the outermost `MountFS`, e.g. "/mnt/dev".
+    /// Each component is found by `find_name_by_child` going up by "..".
+    pub fn path(&self) -> Result<String> {
+        let mut names = Vec::new();
+        let mut inode = self.overlaid_inode();
+        while inode.vfs.self_mountpoint.is_some() || !inode.is_mountpoint_root() {
+            let parent = inode.find(false, "..")?;
+            names.push(parent.find_name_by_child(&inode)?);
+            inode = parent;
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path += "/";
+            path += name;
+        }
+        if path.is_empty() {
+            path += "/";
+        }
+        Ok(path)
+    }
+
     /// If `child` is a child of `self`, return its name.
     pub fn find_name_by_child(&self, child: &Arc<MNode>) -> Result<String> {
         for index in 0.. {
@@ -215,16 +357,62 @@ impl FileSystem for MountFS {
         Ok(())
     }
 
+    fn sync_cancellable(&self, cancel: &AtomicBool) -> Result<()> {
+        self.inner.sync_cancellable(cancel)?;
+        for mount_fs in self.mountpoints.read().values() {
+            mount_fs.sync_cancellable(cancel)?;
+        }
+        Ok(())
+    }
+
     fn root_inode(&self) -> Arc<dyn INode> {
         match &self.self_mountpoint {
             Some(inode) => inode.vfs.root_inode(),
-            None => self.mountpoint_root_inode(),
+            // a file system may be mounted over the root directory itself
+            None => self.mountpoint_root_inode().overlaid_inode(),
         }
     }
 
     fn info(&self) -> FsInfo {
         self.inner.info()
     }
+
+    fn preferred_io_size(&self) -> usize {
+        self.inner.preferred_io_size()
+    }
+
+    fn symlink_max(&self) -> usize {
+        self.inner.symlink_max()
+    }
+
+    fn supports_holes(&self) -> bool {
+        self.inner.supports_holes()
+    }
+
+    fn set_umask(&self, umask: u32) -> Result<()> {
+        self.inner.set_umask(umask)
+    }
+
+    fn mount_options(&self) -> MountOptions {
+        self.inner.mount_options()
+    }
+
+    fn fs_type(&self) -> &'static str {
+        self.inner.fs_type()
+    }
+
+    fn stats(&self) -> Statistics {
+        self.inner.stats()
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.inner.is_dirty()
+            || self
+                .mountpoints
+                .read()
+                .values()
+                .any(|mount_fs| mount_fs.is_dirty())
+    }
 }
 
 // unwrap `MNode` and forward methods to inner except `find()`
@@ -252,10 +440,38 @@ impl INode for MNode {
         self.inode.metadata()
     }
 
+    fn file_type(&self) -> Result<FileType> {
+        self.inode.file_type()
+    }
+
     fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
         self.inode.set_metadata(metadata)
     }
 
+    fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        self.inode.get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        self.inode.set_xattr(name, value)
+    }
+
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        self.inode.list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> Result<()> {
+        self.inode.remove_xattr(name)
+    }
+
+    fn bind(&self, endpoint: usize) -> Result<()> {
+        self.inode.bind(endpoint)
+    }
+
+    fn endpoint(&self) -> Result<usize> {
+        self.inode.endpoint()
+    }
+
     fn sync_all(&self) -> Result<()> {
         self.inode.sync_all()
     }
@@ -272,20 +488,36 @@ impl INode for MNode {
         Ok(self.create(name, type_, mode)?)
     }
 
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        Ok(self.create2(name, type_, mode, data)?)
+    }
+
+    fn create_with_content(&self, name: &str, mode: u32, data: &[u8]) -> Result<Arc<dyn INode>> {
+        Ok(self.create_with_content(name, mode, data)?)
+    }
+
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
         self.inode.link(name, other)
     }
 
     fn unlink(&self, name: &str) -> Result<()> {
-        let inode_id = self.inode.find(name)?.metadata()?.inode;
         // target INode is being mounted
-        if self.vfs.mountpoints.read().contains_key(&inode_id) {
+        if self.is_mounted_at(name)? {
             return Err(FsError::Busy);
         }
         self.inode.unlink(name)
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        if self.is_mounted_at(old_name)? {
+            return Err(FsError::Busy);
+        }
         self.inode.move_(old_name, target, new_name)
     }
 
@@ -301,6 +533,18 @@ impl INode for MNode {
         self.inode.get_entry_with_metadata(id)
     }
 
+    fn iterate_dir(&self, f: &mut dyn FnMut(usize, &str) -> Result<bool>) -> Result<()> {
+        self.inode.iterate_dir(f)
+    }
+
+    fn read_dir_plus(&self, id: usize) -> Result<(usize, String)> {
+        self.inode.read_dir_plus(id)
+    }
+
+    fn snapshot_entries(&self) -> Result<Vec<(String, usize)>> {
+        self.inode.snapshot_entries()
+    }
+
     fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
         self.inode.io_control(cmd, data)
     }
@@ -309,6 +553,47 @@ impl INode for MNode {
         self.inode.mmap(area)
     }
 
+    fn fadvise(&self, offset: usize, len: usize, advice: Advice) -> Result<()> {
+        self.inode.fadvise(offset, len, advice)
+    }
+
+    fn readahead(&self, offset: usize, len: usize) -> Result<()> {
+        self.inode.readahead(offset, len)
+    }
+
+    fn punch_hole(&self, offset: usize, len: usize) -> Result<()> {
+        self.inode.punch_hole(offset, len)
+    }
+
+    fn reserve_entries(&self, count: usize) -> Result<()> {
+        self.inode.reserve_entries(count)
+    }
+
+    fn open(&self) -> Result<()> {
+        self.inode.open()
+    }
+
+    fn close(&self) -> Result<()> {
+        self.inode.close()
+    }
+
+    fn open_count(&self) -> usize {
+        self.inode.open_count()
+    }
+
+    fn as_device(&self) -> Option<&dyn DeviceINode> {
+        self.inode.as_device()
+    }
+
+    fn is_mountpoint(&self) -> bool {
+        // either the covered directory, or the root of the file system mounted on it
+        let covered = self
+            .inode
+            .metadata()
+            .is_ok_and(|metadata| self.vfs.mountpoints.read().contains_key(&metadata.inode));
+        covered || (self.vfs.self_mountpoint.is_some() && self.is_mountpoint_root())
+    }
+
     fn fs(&self) -> Arc<dyn FileSystem> {
         self.vfs.clone()
     }
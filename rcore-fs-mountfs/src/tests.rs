@@ -1,5 +1,9 @@
 use crate::*;
+use rcore_fs_devfs::{special, DevFS};
+use rcore_fs_hostfs::HostFS;
 use rcore_fs_ramfs::RamFS;
+use rcore_fs_sfs::SimpleFileSystem;
+use std::sync::Mutex;
 
 #[test]
 fn mount() {
@@ -16,6 +20,28 @@ fn mount() {
     assert!((root as Arc<dyn INode>).lookup("mnt/file").is_ok());
 }
 
+#[test]
+fn mount_over_root() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.create("below", FileType::File, 0o777).unwrap();
+
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("above", FileType::File, 0o777)
+        .unwrap();
+    root.mount(ramfs.clone()).unwrap();
+
+    let new_root = rootfs.root_inode();
+    assert_eq!(
+        new_root.metadata().unwrap().dev,
+        ramfs.root_inode().metadata().unwrap().dev
+    );
+    assert!(new_root.find("above").is_ok());
+    assert_eq!(new_root.find("below").err(), Some(FsError::EntryNotFound));
+}
+
 #[test]
 fn remove_busy() {
     let rootfs = MountFS::new(RamFS::new());
@@ -25,3 +51,450 @@ fn remove_busy() {
     mnt.mount(ramfs).unwrap();
     assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
 }
+
+#[test]
+fn list_starts_with_dots() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+
+    let sfs_file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(sfs_file)), 32 * 4096).unwrap();
+    let host_dir = tempfile::tempdir().unwrap();
+    std::fs::write(host_dir.path().join("file"), b"").unwrap();
+    let fss: Vec<(&str, Arc<dyn FileSystem>)> = vec![
+        ("ram", RamFS::new()),
+        ("sfs", sfs),
+        ("dev", DevFS::new()),
+        ("host", HostFS::new(host_dir.path())),
+    ];
+    for (name, fs) in fss {
+        let mnt = root.create(name, FileType::Dir, 0o777).unwrap();
+        mnt.mount(fs).unwrap();
+    }
+
+    let root = root as Arc<dyn INode>;
+    for name in ["", "ram", "sfs", "dev", "host"] {
+        let dir = root.lookup(name).unwrap();
+        let list = dir.list().unwrap();
+        assert_eq!(&list[..2], [".", ".."], "in {:?}", name);
+        let (inode, dot) = dir.read_dir_plus(0).unwrap();
+        assert_eq!(dot, ".");
+        assert_eq!(inode, dir.metadata().unwrap().inode);
+    }
+    let host = root.lookup("host").unwrap();
+    assert_eq!(host.list().unwrap(), [".", "..", "file"]);
+    assert_eq!(
+        host.read_dir_plus(1).unwrap().0,
+        host.metadata().unwrap().inode
+    );
+}
+
+#[test]
+fn mountpoint_busy() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    assert!(!mnt.is_mountpoint());
+    mnt.mount(RamFS::new()).unwrap();
+    assert!(mnt.is_mountpoint());
+    assert!(!dir.is_mountpoint());
+
+    let root = root as Arc<dyn INode>;
+    assert!(root.lookup("mnt").unwrap().is_mountpoint());
+    assert!(!root.is_mountpoint());
+    assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
+    assert_eq!(root.move_("mnt", &root, "mnt2"), Err(FsError::Busy));
+    let dir = dir as Arc<dyn INode>;
+    assert_eq!(root.move_("mnt", &dir, "mnt"), Err(FsError::Busy));
+    assert!(root.unlink("dir").is_ok());
+}
+
+/// A directory whose metadata can not be read, like a stale SFS INode
+struct StaleDir;
+
+impl INode for StaleDir {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+    fn metadata(&self) -> Result<Metadata> {
+        Err(FsError::StaleFileHandle)
+    }
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        Arc::new(StaleFs)
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct StaleFs;
+
+impl FileSystem for StaleFs {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<dyn INode> {
+        Arc::new(StaleDir)
+    }
+    fn info(&self) -> FsInfo {
+        FsInfo::default()
+    }
+}
+
+#[test]
+fn mountpoint_with_stale_metadata() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.mount(Arc::new(StaleFs)).unwrap();
+    assert!(mnt.is_mountpoint());
+
+    let stale = root.find(false, "mnt").unwrap();
+    assert!(!stale.is_mountpoint());
+    assert!(stale.find(false, "file").is_err());
+    let stale_root = MountFS::new(Arc::new(StaleFs)).mountpoint_root_inode();
+    assert!(!stale_root.is_mountpoint());
+}
+
+#[test]
+fn distinct_dev() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt1 = root.create("mnt1", FileType::Dir, 0o777).unwrap();
+    let mnt2 = root.create("mnt2", FileType::Dir, 0o777).unwrap();
+    mnt1.mount(RamFS::new()).unwrap();
+    mnt2.mount(RamFS::new()).unwrap();
+
+    let root = root as Arc<dyn INode>;
+    let dev0 = root.metadata().unwrap().dev;
+    let dev1 = root.lookup("mnt1").unwrap().metadata().unwrap().dev;
+    let dev2 = root.lookup("mnt2").unwrap().metadata().unwrap().dev;
+    assert_ne!(dev1, dev2);
+    assert_ne!(dev0, dev1);
+    assert_ne!(dev0, dev2);
+
+    let file = root.lookup("mnt1").unwrap();
+    let file = file.create("file", FileType::File, 0o777).unwrap();
+    assert_eq!(file.metadata().unwrap().dev, dev1);
+}
+
+#[test]
+fn walk() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let a = root.create("a", FileType::Dir, 0o777).unwrap();
+    a.create("x", FileType::File, 0o777).unwrap();
+    let link = a.create("l", FileType::SymLink, 0o777).unwrap();
+    link.write_at(0, b"..").unwrap();
+    let sub = a.create("sub", FileType::Dir, 0o777).unwrap();
+    sub.create("y", FileType::File, 0o777).unwrap();
+    root.create("b", FileType::File, 0o777).unwrap();
+    let mnt = root.create("m", FileType::Dir, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("z", FileType::File, 0o777)
+        .unwrap();
+    mnt.mount(ramfs).unwrap();
+
+    let root = root as Arc<dyn INode>;
+    let mut paths = Vec::new();
+    root.walk(&mut |path, inode| {
+        assert_eq!(
+            inode.metadata()?.inode,
+            root.lookup(path)?.metadata()?.inode
+        );
+        paths.push(String::from(path));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+        paths,
+        ["a", "a/l", "a/sub", "a/sub/y", "a/x", "b", "m", "m/z"]
+    );
+
+    // errors from the visitor stop the walk
+    let mut count = 0;
+    let ret = root.walk(&mut |_, _| {
+        count += 1;
+        Err(FsError::Busy)
+    });
+    assert_eq!(ret, Err(FsError::Busy));
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn umask() {
+    let rootfs = MountFS::new(RamFS::new());
+    rootfs.set_umask(0o022).unwrap();
+    let root = rootfs.mountpoint_root_inode();
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    assert_eq!(file.metadata().unwrap().mode, 0o755);
+}
+
+#[test]
+fn lookup_symlinks_across_mounts() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let file0 = root.create("file0", FileType::File, 0o777).unwrap();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.mount(RamFS::new()).unwrap();
+    let mnt = root.find(false, "mnt").unwrap();
+    let file = mnt.create("file", FileType::File, 0o777).unwrap();
+
+    let link = root.create("link", FileType::SymLink, 0o777).unwrap();
+    link.write_at(0, b"mnt/file").unwrap();
+    let back = mnt.create("back", FileType::SymLink, 0o777).unwrap();
+    back.write_at(0, b"../file0").unwrap();
+    let abs = mnt.create("abs", FileType::SymLink, 0o777).unwrap();
+    abs.write_at(0, b"/mnt/file").unwrap();
+    let loop_ = root.create("loop", FileType::SymLink, 0o777).unwrap();
+    loop_.write_at(0, b"loop").unwrap();
+
+    let same = |a: &Arc<MNode>, b: &Arc<MNode>| {
+        let (a, b) = (a.metadata().unwrap(), b.metadata().unwrap());
+        (a.dev, a.inode) == (b.dev, b.inode)
+    };
+    let found = root.lookup("link", false, 8).unwrap();
+    assert_eq!(found.file_type().unwrap(), FileType::SymLink);
+    assert!(same(&found, &link));
+    assert!(same(&root.lookup("link", true, 8).unwrap(), &file));
+    assert!(same(&root.lookup("mnt/back", true, 8).unwrap(), &file0));
+    assert!(same(&mnt.lookup("abs", true, 8).unwrap(), &file));
+    assert!(same(&mnt.lookup("abs", false, 8).unwrap(), &abs));
+    assert!(same(&file.lookup("/mnt/back", true, 8).unwrap(), &file0));
+
+    assert_eq!(root.lookup("link", true, 0).err(), Some(FsError::SymLoop));
+    assert_eq!(root.lookup("loop", true, 8).err(), Some(FsError::SymLoop));
+    assert!(root.lookup("loop", false, 8).is_ok());
+    assert_eq!(root.lookup("link/x", false, 8).err(), Some(FsError::NotDir));
+    assert_eq!(
+        root.lookup("mnt/nothing", true, 8).err(),
+        Some(FsError::EntryNotFound)
+    );
+}
+
+#[test]
+fn long_symlink() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dir_name = "x".repeat(294);
+    let dir = root.create(&dir_name, FileType::Dir, 0o777).unwrap();
+    let file = dir.create("file", FileType::File, 0o777).unwrap();
+    let target = format!("./{}/file", dir_name);
+    assert!(target.len() > 300);
+    let link = root.create("link", FileType::SymLink, 0o777).unwrap();
+    link.write_at(0, target.as_bytes()).unwrap();
+
+    let inode = file.metadata().unwrap().inode;
+    let root = root as Arc<dyn INode>;
+    assert_eq!(root.lookup("link").unwrap().read_link().unwrap(), target);
+    let found = root.lookup_follow("link", 1).unwrap();
+    assert_eq!(found.metadata().unwrap().inode, inode);
+    let root = rootfs.mountpoint_root_inode();
+    let found = root.lookup("link", true, 1).unwrap();
+    assert_eq!(found.metadata().unwrap().inode, inode);
+
+    // beyond the max length, or not UTF-8
+    let long = root.create("long", FileType::SymLink, 0o777).unwrap();
+    long.write_at(0, &[b'a'; SYMLINK_MAX + 1]).unwrap();
+    assert_eq!(
+        root.lookup("long", true, 1).err(),
+        Some(FsError::NameTooLong)
+    );
+    let bad = root.create("bad", FileType::SymLink, 0o777).unwrap();
+    bad.write_at(0, &[0xff, 0xfe]).unwrap();
+    assert_eq!(
+        root.lookup("bad", true, 1).err(),
+        Some(FsError::InvalidParam)
+    );
+}
+
+#[test]
+fn as_device() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add("null", Arc::new(special::NullINode::new()))
+        .unwrap();
+    let dev = root.create("dev", FileType::Dir, 0o777).unwrap();
+    dev.mount(devfs).unwrap();
+
+    let root = root as Arc<dyn INode>;
+    let null = root.lookup("dev/null").unwrap();
+    let device = null.as_device().unwrap();
+    assert_eq!(device.device_id(), (1, 3));
+    assert_eq!(null.metadata().unwrap().rdev, make_rdev(1, 3));
+    assert_eq!(
+        null.metadata().unwrap().dev,
+        root.lookup("dev").unwrap().metadata().unwrap().dev
+    );
+    assert!(device.poll().unwrap().write);
+    assert_eq!(device.io_control(0, 0), Err(FsError::NotSupported));
+    assert!(root.lookup("dev").unwrap().as_device().is_none());
+    assert!(root.as_device().is_none());
+}
+
+#[test]
+fn mount_entries() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    root.create("tmp", FileType::Dir, 0o777).unwrap();
+
+    let sfs_file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(sfs_file)), 32 * 4096).unwrap();
+    sfs.root_inode()
+        .create("sub", FileType::Dir, 0o777)
+        .unwrap()
+        .create("dev", FileType::Dir, 0o777)
+        .unwrap();
+    sfs.remount(MountOptions { read_only: true }).unwrap();
+    mnt.mount(sfs).unwrap();
+    let dev = root.lookup("mnt/sub/dev", true, 0).unwrap();
+    assert_eq!(dev.path().unwrap(), "/mnt/sub/dev");
+    dev.mount(DevFS::new()).unwrap();
+    root.lookup("tmp", true, 0)
+        .unwrap()
+        .mount(RamFS::new())
+        .unwrap();
+
+    let entry = |path: &str, fs_type, read_only| MountEntry {
+        path: String::from(path),
+        fs_type,
+        read_only,
+    };
+    assert_eq!(
+        rootfs.mount_entries().unwrap(),
+        [
+            entry("/", "ramfs", false),
+            entry("/mnt", "sfs", true),
+            entry("/mnt/sub/dev", "devfs", false),
+            entry("/tmp", "ramfs", false),
+        ]
+    );
+    assert_eq!(
+        root.lookup("mnt/sub/dev", true, 0).unwrap().path().unwrap(),
+        "/mnt/sub/dev"
+    );
+    assert_eq!(root.path().unwrap(), "/");
+}
+
+#[test]
+fn mkfs_from_template() {
+    let ramfs = RamFS::new();
+    let root = ramfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    let file = dir.create("file", FileType::File, 0o777).unwrap();
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    file.write_at(0, &data).unwrap();
+    root.link("hard_link", &file).unwrap();
+    root.create("empty", FileType::File, 0o777).unwrap();
+    dir.create("sub", FileType::Dir, 0o777).unwrap();
+    let symlink = root.create("symlink", FileType::SymLink, 0o777).unwrap();
+    symlink.write_at(0, b"dir/file").unwrap();
+    root.create2("null", FileType::CharDevice, 0o666, 3)
+        .unwrap();
+
+    let sfs_file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(sfs_file)), 32 * 4096).unwrap();
+    sfs.mkfs_from_template(ramfs.as_ref()).unwrap();
+    assert!(!sfs.is_dirty());
+
+    let tree = |fs: &dyn FileSystem| {
+        let mut tree = Vec::new();
+        fs.root_inode()
+            .walk(&mut |path, inode| {
+                let metadata = inode.metadata()?;
+                let (content, rdev) = match metadata.type_ {
+                    FileType::File | FileType::SymLink => {
+                        let mut content = vec![0u8; metadata.size];
+                        inode.read_at(0, &mut content)?;
+                        (content, 0)
+                    }
+                    FileType::CharDevice => (Vec::new(), metadata.rdev),
+                    _ => (Vec::new(), 0),
+                };
+                tree.push((String::from(path), metadata.type_, content, rdev));
+                Ok(())
+            })
+            .unwrap();
+        tree
+    };
+    assert_eq!(tree(sfs.as_ref()), tree(ramfs.as_ref()));
+
+    // hard links are kept
+    let sfs_root = sfs.root_inode();
+    let file = sfs_root.lookup("dir/file").unwrap().metadata().unwrap();
+    let hard_link = sfs_root.lookup("hard_link").unwrap().metadata().unwrap();
+    assert_eq!(file.inode, hard_link.inode);
+    assert_eq!(file.nlinks, 2);
+    assert_eq!(
+        sfs_root.lookup("symlink").unwrap().read_link().unwrap(),
+        "dir/file"
+    );
+}
+
+#[test]
+fn create_device_through_mount() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let sfs_file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(sfs_file)), 32 * 4096).unwrap();
+    let mnt = root.create("sfs", FileType::Dir, 0o777).unwrap();
+    mnt.mount(sfs).unwrap();
+
+    let root = root as Arc<dyn INode>;
+    let dir = root.lookup("sfs").unwrap();
+    let dev = dir.create2("tty", FileType::CharDevice, 0o666, 42).unwrap();
+    assert_eq!(dev.metadata().unwrap().rdev, 42);
+    let found = root.lookup("sfs/tty").unwrap();
+    assert_eq!(found.metadata().unwrap().type_, FileType::CharDevice);
+    assert_eq!(found.metadata().unwrap().rdev, 42);
+}
+
+#[test]
+fn share_read_only_device() {
+    let image = tempfile::NamedTempFile::new().unwrap();
+    {
+        let device = Arc::new(Mutex::new(image.reopen().unwrap()));
+        let sfs = SimpleFileSystem::create(device, 32 * 4096).unwrap();
+        let file = sfs
+            .root_inode()
+            .create("file", FileType::File, 0o644)
+            .unwrap();
+        file.write_at(0, b"base layer").unwrap();
+        sfs.sync().unwrap();
+    }
+    let device: Arc<dyn rcore_fs::dev::Device> =
+        Arc::new(Mutex::new(std::fs::File::open(image.path()).unwrap()));
+
+    // two separate trees, each mounting its own SFS on the same device
+    let mut roots = Vec::new();
+    for _ in 0..2 {
+        let rootfs = MountFS::new(RamFS::new());
+        let mnt = rootfs
+            .mountpoint_root_inode()
+            .create("base", FileType::Dir, 0o755)
+            .unwrap();
+        mnt.mount(SimpleFileSystem::open(device.clone()).unwrap())
+            .unwrap();
+        roots.push(rootfs.root_inode());
+    }
+    for root in roots {
+        let file = root.lookup("base/file").unwrap();
+        let mut buf = [0u8; 10];
+        assert_eq!(file.read_at(0, &mut buf), Ok(10));
+        assert_eq!(&buf, b"base layer");
+        assert_eq!(file.write_at(0, b"x").err(), Some(FsError::ReadOnlyFs));
+    }
+}
@@ -10,11 +10,24 @@ use alloc::{
     vec::Vec,
 };
 use core::any::Any;
+use core::sync::atomic::{AtomicU32, Ordering};
+use rcore_fs::dev::Device;
+use rcore_fs::util::validate_name;
 use rcore_fs::vfs::*;
 use spin::{RwLock, RwLockWriteGuard};
 
+use self::swap::{Content, Swap};
+
+mod swap;
+#[cfg(test)]
+mod tests;
+
 pub struct RamFS {
     root: Arc<LockedINode>,
+    /// Bits cleared from the mode of created INodes
+    umask: AtomicU32,
+    /// Where file content beyond the in-memory limit goes, if any
+    swap: Option<Arc<Swap>>,
 }
 
 impl FileSystem for RamFS {
@@ -38,17 +51,40 @@ impl FileSystem for RamFS {
             namemax: 0,
         }
     }
+
+    fn is_dirty(&self) -> bool {
+        false
+    }
+
+    fn set_umask(&self, umask: u32) -> Result<()> {
+        self.umask.store(umask, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "ramfs"
+    }
 }
 
 impl RamFS {
     pub fn new() -> Arc<Self> {
+        Self::new_with_swap(None)
+    }
+
+    /// Create a RamFS keeping at most `in_memory_limit` bytes of file content in memory.
+    /// The least recently used parts are paged out to `device`, and back in when accessed.
+    pub fn new_with_backing(device: Arc<dyn Device>, in_memory_limit: usize) -> Arc<Self> {
+        Self::new_with_swap(Some(Arc::new(Swap::new(device, in_memory_limit))))
+    }
+
+    fn new_with_swap(swap: Option<Arc<Swap>>) -> Arc<Self> {
         let root = Arc::new(LockedINode(RwLock::new(RamFSINode {
             this: Weak::default(),
             parent: Weak::default(),
-            children: BTreeMap::new(),
-            content: Vec::new(),
+            children: Children::default(),
+            content: Content::new(swap.as_ref()),
             extra: Metadata {
-                dev: 0,
+                dev: new_dev_id(),
                 inode: new_inode_id(),
                 size: 0,
                 blk_size: 0,
@@ -63,9 +99,14 @@ impl RamFS {
                 gid: 0,
                 rdev: 0,
             },
+            endpoint: None,
             fs: Weak::default(),
         })));
-        let fs = Arc::new(RamFS { root });
+        let fs = Arc::new(RamFS {
+            root,
+            umask: AtomicU32::new(0),
+            swap,
+        });
         let mut root = fs.root.0.write();
         root.parent = Arc::downgrade(&fs.root);
         root.this = Arc::downgrade(&fs.root);
@@ -75,6 +116,11 @@ impl RamFS {
         drop(root);
         fs
     }
+
+    /// Bytes of file content in memory, if backed by a device
+    pub fn resident_size(&self) -> Option<usize> {
+        self.swap.as_ref().map(|swap| swap.resident_size())
+    }
 }
 
 struct RamFSINode {
@@ -83,41 +129,139 @@ struct RamFSINode {
     /// Reference to myself
     this: Weak<LockedINode>,
     /// Reference to children INodes
-    children: BTreeMap<String, Arc<LockedINode>>,
+    children: Children,
     /// Content of the file
-    content: Vec<u8>,
+    content: Content,
     /// INode metadata
     extra: Metadata,
+    /// Endpoint a socket is bound to, see `INode::bind`
+    endpoint: Option<usize>,
     /// Reference to FS
     fs: Weak<RamFS>,
 }
 
 struct LockedINode(RwLock<RamFSINode>);
 
+/// Children of a directory sorted by name, for lookup by name and by position in `get_entry`
+#[derive(Default)]
+struct Children(Vec<(String, Arc<LockedINode>)>);
+
+impl Children {
+    fn position(&self, name: &str) -> core::result::Result<usize, usize> {
+        self.0.binary_search_by(|(n, _)| n.as_str().cmp(name))
+    }
+
+    fn get(&self, name: &str) -> Option<&Arc<LockedINode>> {
+        let i = self.position(name).ok()?;
+        Some(&self.0[i].1)
+    }
+
+    fn contains_key(&self, name: &str) -> bool {
+        self.position(name).is_ok()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Name of the `i`-th child
+    fn name(&self, i: usize) -> Option<&str> {
+        self.0.get(i).map(|(name, _)| name.as_str())
+    }
+
+    fn insert(&mut self, name: String, inode: Arc<LockedINode>) {
+        match self.position(&name) {
+            Ok(i) => self.0[i].1 = inode,
+            Err(i) => self.0.insert(i, (name, inode)),
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Ok(i) = self.position(name) {
+            self.0.remove(i);
+        }
+    }
+}
+
+impl LockedINode {
+    /// Create a child INode filled with `content`, linked only once it is filled
+    fn create_child(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+        content: &[u8],
+    ) -> Result<Arc<LockedINode>> {
+        validate_name(name, usize::MAX)?;
+        let mut file = self.0.write();
+        if file.extra.type_ == FileType::Dir {
+            if name == "." || name == ".." {
+                return Err(FsError::EntryExist);
+            }
+            if file.children.contains_key(name) {
+                return Err(FsError::EntryExist);
+            }
+            let fs = file.fs.upgrade();
+            let umask = fs.as_ref().map_or(0, |fs| fs.umask.load(Ordering::SeqCst));
+            let swap = fs.as_ref().and_then(|fs| fs.swap.as_ref());
+            let temp_file = Arc::new(LockedINode(RwLock::new(RamFSINode {
+                parent: Weak::clone(&file.this),
+                this: Weak::default(),
+                children: Children::default(),
+                content: Content::new(swap),
+                extra: Metadata {
+                    dev: file.extra.dev,
+                    inode: new_inode_id(),
+                    size: 0,
+                    blk_size: 0,
+                    blocks: 0,
+                    atime: Timespec { sec: 0, nsec: 0 },
+                    mtime: Timespec { sec: 0, nsec: 0 },
+                    ctime: Timespec { sec: 0, nsec: 0 },
+                    type_,
+                    mode: (mode & !umask) as u16,
+                    nlinks: 1,
+                    uid: 0,
+                    gid: 0,
+                    rdev: data,
+                },
+                // a socket created with nonzero `data` is bound to it
+                endpoint: (type_ == FileType::Socket && data != 0).then_some(data),
+                fs: Weak::clone(&file.fs),
+            })));
+            temp_file.0.write().this = Arc::downgrade(&temp_file);
+            if !content.is_empty() {
+                temp_file.0.write().content.write_at(0, content)?;
+            }
+            file.children
+                .insert(String::from(name), Arc::clone(&temp_file));
+            Ok(temp_file)
+        } else {
+            Err(FsError::NotDir)
+        }
+    }
+}
+
 impl INode for LockedINode {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
         let file = self.0.read();
-        if file.extra.type_ == FileType::Dir {
-            return Err(FsError::IsDir);
+        match file.extra.type_ {
+            FileType::Dir => return Err(FsError::IsDir),
+            FileType::Socket => return Err(FsError::NotFile),
+            _ => {}
         }
-        let start = file.content.len().min(offset);
-        let end = file.content.len().min(offset + buf.len());
-        let src = &file.content[start..end];
-        buf[0..src.len()].copy_from_slice(src);
-        Ok(src.len())
+        file.content.read_at(offset, buf)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
         let mut file = self.0.write();
-        if file.extra.type_ == FileType::Dir {
-            return Err(FsError::IsDir);
-        }
-        let content = &mut file.content;
-        if offset + buf.len() > content.len() {
-            content.resize(offset + buf.len(), 0);
+        match file.extra.type_ {
+            FileType::Dir => return Err(FsError::IsDir),
+            FileType::Socket => return Err(FsError::NotFile),
+            _ => {}
         }
-        let target = &mut content[offset..offset + buf.len()];
-        target.copy_from_slice(buf);
+        file.content.write_at(offset, buf)?;
         Ok(buf.len())
     }
 
@@ -140,6 +284,10 @@ impl INode for LockedINode {
         Ok(metadata)
     }
 
+    fn file_type(&self) -> Result<FileType> {
+        Ok(self.0.read().extra.type_)
+    }
+
     fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
         let mut file = self.0.write();
         file.extra.atime = metadata.atime;
@@ -162,8 +310,7 @@ impl INode for LockedINode {
     fn resize(&self, len: usize) -> Result<()> {
         let mut file = self.0.write();
         if file.extra.type_ == FileType::File {
-            file.content.resize(len, 0);
-            Ok(())
+            file.content.resize(len)
         } else {
             Err(FsError::NotFile)
         }
@@ -176,47 +323,15 @@ impl INode for LockedINode {
         mode: u32,
         data: usize,
     ) -> Result<Arc<dyn INode>> {
-        let mut file = self.0.write();
-        if file.extra.type_ == FileType::Dir {
-            if name == "." || name == ".." {
-                return Err(FsError::EntryExist);
-            }
-            if file.children.contains_key(name) {
-                return Err(FsError::EntryExist);
-            }
-            let temp_file = Arc::new(LockedINode(RwLock::new(RamFSINode {
-                parent: Weak::clone(&file.this),
-                this: Weak::default(),
-                children: BTreeMap::new(),
-                content: Vec::new(),
-                extra: Metadata {
-                    dev: 0,
-                    inode: new_inode_id(),
-                    size: 0,
-                    blk_size: 0,
-                    blocks: 0,
-                    atime: Timespec { sec: 0, nsec: 0 },
-                    mtime: Timespec { sec: 0, nsec: 0 },
-                    ctime: Timespec { sec: 0, nsec: 0 },
-                    type_,
-                    mode: mode as u16,
-                    nlinks: 1,
-                    uid: 0,
-                    gid: 0,
-                    rdev: data,
-                },
-                fs: Weak::clone(&file.fs),
-            })));
-            temp_file.0.write().this = Arc::downgrade(&temp_file);
-            file.children
-                .insert(String::from(name), Arc::clone(&temp_file));
-            Ok(temp_file)
-        } else {
-            Err(FsError::NotDir)
-        }
+        Ok(self.create_child(name, type_, mode, data, &[])?)
+    }
+
+    fn create_with_content(&self, name: &str, mode: u32, data: &[u8]) -> Result<Arc<dyn INode>> {
+        Ok(self.create_child(name, FileType::File, mode, 0, data)?)
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        validate_name(name, usize::MAX)?;
         let other = other
             .downcast_ref::<LockedINode>()
             .ok_or(FsError::NotSameFs)?;
@@ -226,6 +341,9 @@ impl INode for LockedINode {
         let mut file = locks.next().unwrap();
         let mut other_l = locks.next().unwrap();
 
+        if !Weak::ptr_eq(&file.fs, &other_l.fs) {
+            return Err(FsError::NotSameFs);
+        }
         if file.extra.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
@@ -260,6 +378,7 @@ impl INode for LockedINode {
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        validate_name(new_name, usize::MAX)?;
         let elem = self.find(old_name)?;
         target.link(new_name, &elem)?;
         if let Err(err) = self.unlink(old_name) {
@@ -296,7 +415,7 @@ impl INode for LockedINode {
             0 => Ok(String::from(".")),
             1 => Ok(String::from("..")),
             i => {
-                if let Some(s) = file.children.keys().nth(i - 2) {
+                if let Some(s) = file.children.name(i - 2) {
                     Ok(s.to_string())
                 } else {
                     Err(FsError::EntryNotFound)
@@ -305,6 +424,49 @@ impl INode for LockedINode {
         }
     }
 
+    fn snapshot_entries(&self) -> Result<Vec<(String, usize)>> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        // the root is its own parent, and already locked
+        let parent = match Weak::ptr_eq(&file.parent, &file.this) {
+            true => file.extra.inode,
+            false => {
+                let parent = file.parent.upgrade().ok_or(FsError::EntryNotFound)?;
+                let inode = parent.0.read().extra.inode;
+                inode
+            }
+        };
+        let mut entries = Vec::with_capacity(file.children.0.len() + 2);
+        entries.push((String::from("."), file.extra.inode));
+        entries.push((String::from(".."), parent));
+        for (name, child) in file.children.0.iter() {
+            entries.push((name.clone(), child.0.read().extra.inode));
+        }
+        Ok(entries)
+    }
+
+    fn bind(&self, endpoint: usize) -> Result<()> {
+        let mut file = self.0.write();
+        if file.extra.type_ != FileType::Socket {
+            return Err(FsError::InvalidParam);
+        }
+        if file.endpoint.is_some() {
+            return Err(FsError::Busy);
+        }
+        file.endpoint = Some(endpoint);
+        Ok(())
+    }
+
+    fn endpoint(&self) -> Result<usize> {
+        let file = self.0.read();
+        if file.extra.type_ != FileType::Socket {
+            return Err(FsError::InvalidParam);
+        }
+        file.endpoint.ok_or(FsError::ConnectionRefused)
+    }
+
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
     }
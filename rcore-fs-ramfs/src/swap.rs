@@ -0,0 +1,279 @@
+//! Swapping file content of RamFS out to a backing device
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use rcore_fs::{
+    dev::{BlockId, Device},
+    util::{checked_end, BlockIter},
+    vfs::{FsError, Result},
+};
+use spin::Mutex;
+
+/// max size of a file, that of the largest `Vec`
+pub const MAX_FILE_SIZE: usize = isize::MAX as usize;
+
+/// size of a page, the unit of swapping
+pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_LOG2;
+const PAGE_SIZE_LOG2: u8 = 12;
+
+type PageId = usize;
+
+/// Pages of all files in a RamFS, keeping at most a given number of them in memory.
+/// The least recently used pages are written to the backing device when out of room.
+pub struct Swap {
+    device: Arc<dyn Device>,
+    /// max number of pages in memory
+    max_resident: usize,
+    inner: Mutex<SwapInner>,
+}
+
+struct SwapInner {
+    pages: BTreeMap<PageId, Page>,
+    /// pages in memory by the time of last access
+    lru: BTreeMap<u64, PageId>,
+    clock: u64,
+    next_page: PageId,
+    /// blocks of device freed for reuse
+    free_blocks: Vec<BlockId>,
+    /// blocks of device from here are never used
+    next_block: BlockId,
+}
+
+enum Page {
+    /// not written yet, all zero
+    Zero,
+    /// in memory, last accessed at `time`
+    Resident { data: Box<[u8]>, time: u64 },
+    /// written to a block of device
+    Swapped(BlockId),
+}
+
+impl Swap {
+    pub fn new(device: Arc<dyn Device>, in_memory_limit: usize) -> Self {
+        Swap {
+            device,
+            max_resident: (in_memory_limit / PAGE_SIZE).max(1),
+            inner: Mutex::new(SwapInner {
+                pages: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                next_page: 0,
+                free_blocks: Vec::new(),
+                next_block: 0,
+            }),
+        }
+    }
+
+    /// Bytes of pages in memory
+    pub fn resident_size(&self) -> usize {
+        self.inner.lock().lru.len() * PAGE_SIZE
+    }
+
+    fn alloc_page(&self) -> PageId {
+        let mut inner = self.inner.lock();
+        let id = inner.next_page;
+        inner.next_page += 1;
+        inner.pages.insert(id, Page::Zero);
+        id
+    }
+
+    fn free_page(&self, id: PageId) {
+        let mut inner = self.inner.lock();
+        match inner.pages.remove(&id) {
+            Some(Page::Resident { time, .. }) => {
+                inner.lru.remove(&time);
+            }
+            Some(Page::Swapped(block_id)) => inner.free_blocks.push(block_id),
+            _ => {}
+        }
+    }
+
+    fn read(&self, id: PageId, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let data = inner.page_in(self, id)?;
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&self, id: PageId, offset: usize, buf: &[u8]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let data = inner.page_in(self, id)?;
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl SwapInner {
+    /// Load page `id` into memory if needed, mark it as the most recently used
+    fn page_in(&mut self, swap: &Swap, id: PageId) -> Result<&mut [u8]> {
+        if !matches!(self.pages[&id], Page::Resident { .. }) {
+            self.make_room(swap)?;
+        }
+        // a page failing to read back stays swapped out
+        let swapped_in = match self.pages[&id] {
+            Page::Swapped(block_id) => {
+                let mut data = vec![0u8; PAGE_SIZE].into_boxed_slice();
+                if swap.device.read_at(block_id * PAGE_SIZE, &mut data)? != PAGE_SIZE {
+                    return Err(FsError::DeviceError);
+                }
+                self.free_blocks.push(block_id);
+                Some(data)
+            }
+            _ => None,
+        };
+        self.clock += 1;
+        let time = self.clock;
+        let page = self.pages.get_mut(&id).unwrap();
+        let data = match core::mem::replace(page, Page::Zero) {
+            Page::Zero => vec![0u8; PAGE_SIZE].into_boxed_slice(),
+            Page::Resident { data, time } => {
+                self.lru.remove(&time);
+                data
+            }
+            Page::Swapped(_) => swapped_in.unwrap(),
+        };
+        *page = Page::Resident { data, time };
+        self.lru.insert(time, id);
+        match page {
+            Page::Resident { data, .. } => Ok(data),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Swap out least recently used pages until one more fits in memory
+    fn make_room(&mut self, swap: &Swap) -> Result<()> {
+        while self.lru.len() >= swap.max_resident {
+            let (&time, &id) = self.lru.iter().next().unwrap();
+            let block_id = match self.free_blocks.pop() {
+                Some(block_id) => block_id,
+                None => {
+                    self.next_block += 1;
+                    self.next_block - 1
+                }
+            };
+            let page = self.pages.get_mut(&id).unwrap();
+            if let Page::Resident { data, .. } = page {
+                match swap.device.write_at(block_id * PAGE_SIZE, data) {
+                    Ok(PAGE_SIZE) => {}
+                    result => {
+                        self.free_blocks.push(block_id);
+                        return Err(result.map_or(FsError::DeviceError, |_| FsError::NoDeviceSpace));
+                    }
+                }
+            }
+            *page = Page::Swapped(block_id);
+            self.lru.remove(&time);
+        }
+        Ok(())
+    }
+}
+
+/// Content of a file
+pub enum Content {
+    /// in memory only
+    Memory(Vec<u8>),
+    /// in pages of a `Swap`
+    Paged {
+        swap: Arc<Swap>,
+        len: usize,
+        pages: Vec<PageId>,
+    },
+}
+
+impl Content {
+    pub fn new(swap: Option<&Arc<Swap>>) -> Self {
+        match swap {
+            Some(swap) => Content::Paged {
+                swap: swap.clone(),
+                len: 0,
+                pages: Vec::new(),
+            },
+            None => Content::Memory(Vec::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Content::Memory(data) => data.len(),
+            Content::Paged { len, .. } => *len,
+        }
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let start = self.len().min(offset);
+        let end = self.len().min(offset.saturating_add(buf.len()));
+        match self {
+            Content::Memory(data) => buf[..end - start].copy_from_slice(&data[start..end]),
+            Content::Paged { swap, pages, .. } => {
+                let iter = BlockIter {
+                    begin: start,
+                    end,
+                    block_size_log2: PAGE_SIZE_LOG2,
+                };
+                for range in iter {
+                    let buf = &mut buf[range.origin_begin() - start..range.origin_end() - start];
+                    swap.read(pages[range.block], range.begin, buf)?;
+                }
+            }
+        }
+        Ok(end - start)
+    }
+
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        let end = checked_end(offset, buf.len(), MAX_FILE_SIZE)?;
+        if end > self.len() {
+            self.resize(end)?;
+        }
+        match self {
+            Content::Memory(data) => data[offset..offset + buf.len()].copy_from_slice(buf),
+            Content::Paged { swap, pages, .. } => {
+                let iter = BlockIter {
+                    begin: offset,
+                    end: offset + buf.len(),
+                    block_size_log2: PAGE_SIZE_LOG2,
+                };
+                for range in iter {
+                    let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+                    swap.write(pages[range.block], range.begin, buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resize(&mut self, new_len: usize) -> Result<()> {
+        if new_len > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        match self {
+            Content::Memory(data) => data.resize(new_len, 0),
+            Content::Paged { swap, len, pages } => {
+                let count = new_len.div_ceil(PAGE_SIZE);
+                while pages.len() > count {
+                    swap.free_page(pages.pop().unwrap());
+                }
+                while pages.len() < count {
+                    pages.push(swap.alloc_page());
+                }
+                // keep bytes beyond the end zero, for growing later
+                let begin = new_len % PAGE_SIZE;
+                if new_len < *len && begin != 0 {
+                    let zeros = [0u8; PAGE_SIZE];
+                    let end = PAGE_SIZE.min(begin + *len - new_len);
+                    swap.write(pages[count - 1], begin, &zeros[..end - begin])?;
+                }
+                *len = new_len;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Content {
+    fn drop(&mut self) {
+        if let Content::Paged { swap, pages, .. } = self {
+            for &id in pages.iter() {
+                swap.free_page(id);
+            }
+        }
+    }
+}
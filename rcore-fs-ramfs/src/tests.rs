@@ -0,0 +1,202 @@
+use crate::swap::PAGE_SIZE;
+use crate::*;
+use rcore_fs::dev::{DevError, DevErrorKind};
+use rcore_fs::testing::*;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+
+#[test]
+fn with_backing() {
+    const LIMIT: usize = 4 * 4096;
+    let device = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let ramfs = RamFS::new_with_backing(device, LIMIT);
+    let root = ramfs.root_inode();
+    let files: Vec<_> = (0..4)
+        .map(|i| {
+            root.create(&format!("file{}", i), FileType::File, 0o777)
+                .unwrap()
+        })
+        .collect();
+    // write past the limit, with a pattern distinct for each file
+    for i in 0..16 {
+        for (j, file) in files.iter().enumerate() {
+            let data = [(i * 4 + j + 1) as u8; 3000];
+            file.write_at(i * 3000, &data).unwrap();
+        }
+        assert!(ramfs.resident_size().unwrap() <= LIMIT);
+    }
+    for (j, file) in files.iter().enumerate() {
+        assert_eq!(file.metadata().unwrap().size, 16 * 3000);
+        let mut buf = [0u8; 3000];
+        for i in 0..16 {
+            assert_eq!(file.read_at(i * 3000, &mut buf).unwrap(), 3000);
+            assert!(buf.iter().all(|&b| b == (i * 4 + j + 1) as u8));
+        }
+        assert!(ramfs.resident_size().unwrap() <= LIMIT);
+    }
+    // shrinking then growing reads back zeros
+    files[0].resize(100).unwrap();
+    files[0].resize(5000).unwrap();
+    let mut buf = [0u8; 5000];
+    files[0].read_at(0, &mut buf).unwrap();
+    assert!(buf[..100].iter().all(|&b| b == 1));
+    assert!(buf[100..].iter().all(|&b| b == 0));
+    assert_eq!(RamFS::new().resident_size(), None);
+}
+
+/// A backing device failing reads or writes on demand, or reading short
+#[derive(Default)]
+struct FailingDevice {
+    inner: Mutex<Vec<u8>>,
+    fail_reads: AtomicBool,
+    short_reads: AtomicBool,
+    fail_writes: AtomicBool,
+}
+
+impl Device for FailingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        if self.fail_reads.load(Ordering::SeqCst) {
+            return Err(DevError::new(DevErrorKind::ReadFailed));
+        }
+        let len = match self.short_reads.load(Ordering::SeqCst) {
+            true => buf.len() / 2,
+            false => buf.len(),
+        };
+        buf[..len].copy_from_slice(&self.inner.lock().unwrap()[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(DevError::new(DevErrorKind::WriteFailed));
+        }
+        let mut data = self.inner.lock().unwrap();
+        if data.len() < offset + buf.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn with_failing_backing() {
+    let device = Arc::new(FailingDevice::default());
+    let ramfs = RamFS::new_with_backing(device.clone(), PAGE_SIZE);
+    let root = ramfs.root_inode();
+    let a = root.create("a", FileType::File, 0o777).unwrap();
+    let b = root.create("b", FileType::File, 0o777).unwrap();
+    a.write_at(0, &[1; PAGE_SIZE]).unwrap();
+    // pages out a
+    b.write_at(0, &[2; PAGE_SIZE]).unwrap();
+
+    // a stays swapped out, not zeroed
+    let mut buf = [0u8; PAGE_SIZE];
+    device.fail_reads.store(true, Ordering::SeqCst);
+    assert_eq!(a.read_at(0, &mut buf), Err(FsError::DeviceError));
+    device.fail_reads.store(false, Ordering::SeqCst);
+    device.short_reads.store(true, Ordering::SeqCst);
+    assert_eq!(a.read_at(0, &mut buf), Err(FsError::DeviceError));
+    device.short_reads.store(false, Ordering::SeqCst);
+    assert_eq!(a.read_at(0, &mut buf), Ok(PAGE_SIZE));
+    assert_eq!(buf, [1; PAGE_SIZE]);
+
+    // b can not make room by paging out a
+    device.fail_writes.store(true, Ordering::SeqCst);
+    assert_eq!(b.read_at(0, &mut buf), Err(FsError::DeviceError));
+    device.fail_writes.store(false, Ordering::SeqCst);
+    assert_eq!(b.read_at(0, &mut buf), Ok(PAGE_SIZE));
+    assert_eq!(buf, [2; PAGE_SIZE]);
+    assert_eq!(a.read_at(0, &mut buf), Ok(PAGE_SIZE));
+    assert_eq!(buf, [1; PAGE_SIZE]);
+    // no block leaked by the failures
+    assert!(device.inner.lock().unwrap().len() <= 2 * PAGE_SIZE);
+}
+
+#[test]
+fn link_across_instances() {
+    let root0 = RamFS::new().root_inode();
+    let root1 = RamFS::new().root_inode();
+    let file = root1.create("file", FileType::File, 0o777).unwrap();
+    assert_eq!(root0.link("file", &file), Err(FsError::NotSameFs));
+    assert_eq!(file.metadata().unwrap().nlinks, 1);
+    root1.link("file1", &file).unwrap();
+    assert_eq!(file.metadata().unwrap().nlinks, 2);
+}
+
+#[test]
+fn short_reads() {
+    check_short_reads(RamFS::new().root_inode());
+}
+
+#[test]
+fn invalid_names() {
+    check_invalid_names(RamFS::new().root_inode());
+}
+
+#[test]
+fn list_large_dir() {
+    let root = RamFS::new().root_inode();
+    // created out of order, listed sorted by name
+    for i in (0..2000).rev() {
+        root.create(&format!("{:04}", i), FileType::File, 0o777)
+            .unwrap();
+    }
+    let names: Vec<String> = (0..2000).map(|i| format!("{:04}", i)).collect();
+    assert_eq!(root.list().unwrap()[2..], names[..]);
+    // each entry is read from its slot in the sorted children, not walked to from the first
+    let dir = root.downcast_ref::<LockedINode>().unwrap();
+    for i in (0..2000).rev() {
+        let name = root.get_entry(2 + i).unwrap();
+        assert_eq!(dir.0.read().children.name(i), Some(name.as_str()));
+    }
+    assert_eq!(root.get_entry(2 + 1234).unwrap(), "1234");
+    assert_eq!(root.get_entry(2 + 2000), Err(FsError::EntryNotFound));
+
+    root.unlink("0000").unwrap();
+    root.move_("0001", &root, "9999").unwrap();
+    assert_eq!(root.get_entry(2).unwrap(), "0002");
+    assert_eq!(root.get_entry(2 + 1998).unwrap(), "9999");
+    assert_eq!(root.list().unwrap().len(), 2 + 1999);
+    assert!(root.find("0001").is_err());
+    assert!(root.find("9999").is_ok());
+}
+
+#[test]
+fn list_while_unlinking() {
+    check_list_while_unlinking(RamFS::new().root_inode());
+}
+
+#[test]
+fn create_with_content_never_empty() {
+    check_create_with_content_never_empty(RamFS::new().root_inode());
+}
+
+#[test]
+fn socket_endpoint() {
+    let root = RamFS::new().root_inode();
+    let dir = root.create("run", FileType::Dir, 0o755).unwrap();
+    let sock = dir.create("sock", FileType::Socket, 0o755).unwrap();
+    assert_eq!(sock.endpoint(), Err(FsError::ConnectionRefused));
+    sock.bind(42).unwrap();
+    assert_eq!(sock.bind(43), Err(FsError::Busy));
+
+    // a connector finds it by path
+    let sock = root.lookup("run/sock").unwrap();
+    assert_eq!(sock.endpoint(), Ok(42));
+    assert_eq!(sock.read_at(0, &mut [0; 4]), Err(FsError::NotFile));
+    assert_eq!(sock.write_at(0, b"data"), Err(FsError::NotFile));
+
+    // bound when created
+    dir.create2("sock2", FileType::Socket, 0o755, 7).unwrap();
+    assert_eq!(root.lookup("run/sock2").unwrap().endpoint(), Ok(7));
+    assert_eq!(dir.endpoint(), Err(FsError::InvalidParam));
+    assert_eq!(dir.bind(1), Err(FsError::InvalidParam));
+}
+
+#[test]
+fn write_at_offset_overflow() {
+    check_write_at_offset_overflow(RamFS::new().root_inode());
+}
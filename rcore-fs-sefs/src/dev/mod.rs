@@ -35,6 +35,8 @@ pub trait File: Send + Sync {
 }
 
 /// The collection of all files in the FS.
+///
+/// Taken by `SEFS` as `Arc<dyn Storage>`, like `Device` for other file systems.
 pub trait Storage: Send + Sync {
     fn open(&self, file_id: usize) -> DevResult<Box<dyn File>>;
     fn create(&self, file_id: usize) -> DevResult<Box<dyn File>>;
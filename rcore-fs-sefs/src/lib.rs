@@ -1,31 +1,125 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 use alloc::{
     boxed::Box,
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use bitvec::prelude::*;
 use rcore_fs::{
     dev::TimeProvider,
     dirty::Dirty,
-    util::uninit_memory,
-    vfs::{self, FileSystem, FsError, INode, MMapArea, Timespec},
+    util::{checked_end, uninit_memory, validate_name},
+    vfs::{self, FileSystem, FsError, INode, MMapArea, StatCounters, Timespec},
 };
-use spin::RwLock;
+use spin::{Mutex, RwLock, RwLockReadGuard};
 
 use dev::*;
 use structs::*;
 
 pub mod dev;
 mod structs;
+#[cfg(test)]
+mod tests;
+
+/// A `File` remembering whether it is modified since the last flush,
+/// so that flushing an untouched file costs nothing
+struct TrackedFile {
+    inner: Box<dyn File>,
+    dirty: AtomicBool,
+}
+
+impl TrackedFile {
+    fn new(inner: Box<dyn File>, dirty: bool) -> Self {
+        TrackedFile {
+            inner,
+            dirty: AtomicBool::new(dirty),
+        }
+    }
+}
+
+impl File for TrackedFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        self.dirty.store(true, Ordering::SeqCst);
+        self.inner.write_at(buf, offset)
+    }
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        self.dirty.store(true, Ordering::SeqCst);
+        self.inner.set_len(len)
+    }
+    fn flush(&self) -> DevResult<()> {
+        if self.dirty.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.inner.flush() {
+                self.dirty.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `Storage` counting reads and writes of its files in `stats`
+struct CountedStorage {
+    inner: Arc<dyn Storage>,
+    stats: Arc<StatCounters>,
+}
+
+struct CountedFile {
+    inner: Box<dyn File>,
+    stats: Arc<StatCounters>,
+}
+
+impl CountedStorage {
+    fn wrap(&self, inner: Box<dyn File>) -> Box<dyn File> {
+        Box::new(CountedFile {
+            inner,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+impl Storage for CountedStorage {
+    fn open(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        Ok(self.wrap(self.inner.open(file_id)?))
+    }
+    fn create(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        Ok(self.wrap(self.inner.create(file_id)?))
+    }
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        self.inner.remove(file_id)
+    }
+}
+
+impl File for CountedFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.stats.block_reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        self.stats.block_writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.write_at(buf, offset)
+    }
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        self.inner.set_len(len)
+    }
+    fn flush(&self) -> DevResult<()> {
+        self.inner.flush()
+    }
+}
 
 /// Helper methods for `File`
 impl dyn File {
@@ -59,7 +153,9 @@ pub struct INodeImpl {
     id: INodeId,
     /// on-disk inode
     disk_inode: RwLock<Dirty<DiskINode>>,
-    /// back file
+    /// Held while modifying directory entries, or taking a snapshot of them
+    dir_lock: Mutex<()>,
+    /// back file, flushed only if modified
     file: Box<dyn File>,
     /// Reference to FS
     fs: Arc<SEFS>,
@@ -76,6 +172,19 @@ impl Debug for INodeImpl {
 }
 
 impl INodeImpl {
+    /// Write the inode block if dirty, without flushing the meta file.
+    /// Return whether it is written.
+    fn write_disk_inode(&self) -> vfs::Result<bool> {
+        let mut disk_inode = self.disk_inode.write();
+        if !disk_inode.dirty() {
+            return Ok(false);
+        }
+        self.fs
+            .meta_file
+            .write_block(self.id, disk_inode.as_buf())?;
+        disk_inode.sync();
+        Ok(true)
+    }
     /// Only for Dir
     fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
         (0..self.disk_inode.read().blocks as usize)
@@ -83,7 +192,7 @@ impl INodeImpl {
                 let entry = self.file.read_direntry(i).unwrap();
                 (entry, i)
             })
-            .find(|(entry, _)| entry.name.as_ref() == name)
+            .find(|(entry, _)| self.fs.entry_name(entry).as_deref() == Ok(name))
             .map(|(entry, id)| (entry.id as INodeId, id))
     }
     fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
@@ -112,6 +221,7 @@ impl INodeImpl {
         Ok(())
     }
     fn dirent_append(&self, entry: &DiskEntry) -> vfs::Result<()> {
+        let _dir_lock = self.dir_lock.lock();
         let mut inode = self.disk_inode.write();
         let total = &mut inode.blocks;
         self.file.write_direntry(*total as usize, entry)?;
@@ -121,8 +231,10 @@ impl INodeImpl {
     /// remove a page in middle of file and insert the last page here, useful for dirent remove
     /// should be only used in unlink
     fn dirent_remove(&self, id: usize) -> vfs::Result<()> {
+        let _dir_lock = self.dir_lock.lock();
         let total = self.disk_inode.read().blocks as usize;
         debug_assert!(id < total);
+        self.fs.free_entry_name(&self.file.read_direntry(id)?)?;
         let last_direntry = self.file.read_direntry(total - 1)?;
         if id != total - 1 {
             self.file.write_direntry(id, &last_direntry)?;
@@ -131,8 +243,126 @@ impl INodeImpl {
         self.disk_inode.write().blocks -= 1;
         Ok(())
     }
-    fn nlinks_inc(&self) {
-        self.disk_inode.write().nlinks += 1;
+    /// Overwrite the entry `id`, freeing its old name
+    fn dirent_replace(&self, id: usize, entry: &DiskEntry) -> vfs::Result<()> {
+        let _dir_lock = self.dir_lock.lock();
+        let old_entry = self.file.read_direntry(id)?;
+        self.file.write_direntry(id, entry)?;
+        self.fs.free_entry_name(&old_entry)
+    }
+    /// Find the entry `name` which moving an INode of `type_` here would replace.
+    /// As POSIX `rename`, a directory only replaces an empty directory,
+    /// and anything else only a non-directory.
+    fn rename_victim(
+        &self,
+        name: &str,
+        type_: FileType,
+    ) -> vfs::Result<Option<(Arc<INodeImpl>, usize)>> {
+        let (victim_id, entry_id) = match self.get_file_inode_and_entry_id(name) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let victim = self.fs.get_inode(victim_id);
+        let victim_type = victim.disk_inode.read().type_;
+        match (type_, victim_type) {
+            (FileType::Dir, FileType::Dir) => {
+                // only . and ..
+                if victim.disk_inode.read().blocks > 2 {
+                    return Err(FsError::DirNotEmpty);
+                }
+            }
+            (FileType::Dir, _) => return Err(FsError::NotDir),
+            (_, FileType::Dir) => return Err(FsError::IsDir),
+            _ => {}
+        }
+        Ok(Some((victim, entry_id)))
+    }
+    /// Drop the links of `victim` replaced by a move, once its entry here is gone
+    fn release_victim(&self, victim: &INodeImpl) {
+        victim.nlinks_dec();
+        if victim.disk_inode.read().type_ == FileType::Dir {
+            victim.nlinks_dec(); //for .
+            self.nlinks_dec(); //for ..
+        }
+    }
+    /// Extended attributes stored in file `file_id`, see `store_xattrs`
+    fn load_xattrs(&self, file_id: usize) -> vfs::Result<BTreeMap<String, Vec<u8>>> {
+        let mut xattrs = BTreeMap::new();
+        if file_id == 0 {
+            return Ok(xattrs);
+        }
+        let file = self.fs.device.open(file_id)?;
+        let mut len = 0u32;
+        file.read_exact_at(len.as_buf_mut(), 0)?;
+        let mut data = vec![0u8; len as usize];
+        file.read_exact_at(&mut data, 4)?;
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            // name length, value length, then both
+            if rest.len() < 4 {
+                return Err(FsError::WrongFs);
+            }
+            let name_len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+            let value_len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+            rest = &rest[4..];
+            if rest.len() < name_len + value_len {
+                return Err(FsError::WrongFs);
+            }
+            let name = core::str::from_utf8(&rest[..name_len]).map_err(|_| FsError::WrongFs)?;
+            let value = &rest[name_len..name_len + value_len];
+            xattrs.insert(String::from(name), Vec::from(value));
+            rest = &rest[name_len + value_len..];
+        }
+        Ok(xattrs)
+    }
+    /// Store `xattrs` in their own file, so they are covered by the storage like file content.
+    /// The file is freed once none is left.
+    fn store_xattrs(
+        &self,
+        disk_inode: &mut DiskINode,
+        xattrs: &BTreeMap<String, Vec<u8>>,
+    ) -> vfs::Result<()> {
+        let mut data = Vec::new();
+        for (name, value) in xattrs.iter() {
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(value);
+        }
+        if data.len() > MAX_XATTR_SIZE {
+            return Err(FsError::NoDeviceSpace);
+        }
+        if xattrs.is_empty() {
+            if disk_inode.xattr != 0 {
+                self.fs.device.remove(disk_inode.xattr as usize)?;
+                self.fs.free_block(disk_inode.xattr as usize);
+                disk_inode.xattr = 0;
+            }
+            return Ok(());
+        }
+        let file = match disk_inode.xattr {
+            0 => {
+                let file_id = self.fs.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+                let file = self.fs.device.create(file_id)?;
+                disk_inode.xattr = file_id as u32;
+                file
+            }
+            file_id => self.fs.device.open(file_id as usize)?,
+        };
+        file.set_len(0)?;
+        file.write_all_at((data.len() as u32).as_buf(), 0)?;
+        file.write_all_at(&data, 4)?;
+        file.flush()?;
+        Ok(())
+    }
+    /// Fails with `TooManyLinks` instead of overflowing the on-disk count
+    fn nlinks_inc(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.nlinks = disk_inode
+            .nlinks
+            .checked_add(1)
+            .ok_or(FsError::TooManyLinks)?;
+        Ok(())
     }
     fn nlinks_dec(&self) {
         let mut disk_inode = self.disk_inode.write();
@@ -143,19 +373,24 @@ impl INodeImpl {
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        let type_ = self.disk_inode.read().type_;
+        let DiskINode { type_, size, .. } = **self.disk_inode.read();
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
-        let len = self.file.read_at(buf, offset)?;
+        // the backing file may read short, but holds all bytes up to the size
+        let len = (size as usize).saturating_sub(offset).min(buf.len());
+        if len != 0 {
+            self.file.read_exact_at(&mut buf[..len], offset)?;
+        }
         Ok(len)
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let _remount = self.fs.begin_write()?;
         let DiskINode { type_, size, .. } = **self.disk_inode.read();
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
-        let end_offset = offset + buf.len();
+        let end_offset = checked_end(offset, buf.len(), MAX_FILE_SIZE)?;
         if (size as usize) < end_offset {
             self.resize(end_offset)?;
         }
@@ -173,7 +408,7 @@ impl vfs::INode for INodeImpl {
     fn metadata(&self) -> vfs::Result<vfs::Metadata> {
         let disk_inode = self.disk_inode.read();
         Ok(vfs::Metadata {
-            dev: 0,
+            dev: self.fs.dev,
             inode: self.id,
             size: match disk_inode.type_ {
                 FileType::File | FileType::SymLink => disk_inode.size as usize,
@@ -183,18 +418,9 @@ impl vfs::INode for INodeImpl {
             mode: disk_inode.mode,
             type_: vfs::FileType::from(disk_inode.type_),
             blocks: disk_inode.blocks as usize,
-            atime: Timespec {
-                sec: disk_inode.atime as i64,
-                nsec: 0,
-            },
-            mtime: Timespec {
-                sec: disk_inode.mtime as i64,
-                nsec: 0,
-            },
-            ctime: Timespec {
-                sec: disk_inode.ctime as i64,
-                nsec: 0,
-            },
+            atime: join_time(disk_inode.atime, disk_inode.atime_hi, disk_inode.atime_nsec),
+            mtime: join_time(disk_inode.mtime, disk_inode.mtime_hi, disk_inode.mtime_nsec),
+            ctime: join_time(disk_inode.ctime, disk_inode.ctime_hi, disk_inode.ctime_nsec),
             nlinks: disk_inode.nlinks as usize,
             uid: disk_inode.uid as usize,
             gid: disk_inode.gid as usize,
@@ -202,27 +428,57 @@ impl vfs::INode for INodeImpl {
             rdev: 0,
         })
     }
+    fn file_type(&self) -> vfs::Result<vfs::FileType> {
+        Ok(vfs::FileType::from(self.disk_inode.read().type_))
+    }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        let _remount = self.fs.begin_write()?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.mode = metadata.mode;
         disk_inode.uid = metadata.uid as u16;
         disk_inode.gid = metadata.gid as u8;
-        disk_inode.atime = metadata.atime.sec as u32;
-        disk_inode.mtime = metadata.mtime.sec as u32;
-        disk_inode.ctime = metadata.ctime.sec as u32;
+        (disk_inode.atime, disk_inode.atime_hi, disk_inode.atime_nsec) = split_time(metadata.atime);
+        (disk_inode.mtime, disk_inode.mtime_hi, disk_inode.mtime_nsec) = split_time(metadata.mtime);
+        (disk_inode.ctime, disk_inode.ctime_hi, disk_inode.ctime_nsec) = split_time(metadata.ctime);
         Ok(())
     }
-    fn sync_all(&self) -> vfs::Result<()> {
+    fn get_xattr(&self, name: &str) -> vfs::Result<Vec<u8>> {
+        let file_id = self.disk_inode.read().xattr as usize;
+        let mut xattrs = self.load_xattrs(file_id)?;
+        xattrs.remove(name).ok_or(FsError::EntryNotFound)
+    }
+    fn set_xattr(&self, name: &str, value: &[u8]) -> vfs::Result<()> {
+        if name.is_empty() {
+            return Err(FsError::InvalidParam);
+        }
+        if name.len() > MAX_XATTR_NAME_LEN {
+            return Err(FsError::NameTooLong);
+        }
+        let _remount = self.fs.begin_write()?;
         let mut disk_inode = self.disk_inode.write();
-        if disk_inode.dirty() {
-            self.fs
-                .meta_file
-                .write_block(self.id, disk_inode.as_buf())?;
-            disk_inode.sync();
+        let mut xattrs = self.load_xattrs(disk_inode.xattr as usize)?;
+        xattrs.insert(String::from(name), Vec::from(value));
+        self.store_xattrs(&mut disk_inode, &xattrs)
+    }
+    fn list_xattr(&self) -> vfs::Result<Vec<String>> {
+        let file_id = self.disk_inode.read().xattr as usize;
+        Ok(self.load_xattrs(file_id)?.into_keys().collect())
+    }
+    fn remove_xattr(&self, name: &str) -> vfs::Result<()> {
+        let _remount = self.fs.begin_write()?;
+        let mut disk_inode = self.disk_inode.write();
+        let mut xattrs = self.load_xattrs(disk_inode.xattr as usize)?;
+        xattrs.remove(name).ok_or(FsError::EntryNotFound)?;
+        self.store_xattrs(&mut disk_inode, &xattrs)
+    }
+    fn sync_all(&self) -> vfs::Result<()> {
+        if self.write_disk_inode()? {
+            self.fs.meta_file.flush()?;
         }
-        self.sync_data()?;
+        self.file.flush()?;
         Ok(())
     }
+    /// Only the back file, the inode block is left to `sync_all` or `SEFS::sync`
     fn sync_data(&self) -> vfs::Result<()> {
         self.file.flush()?;
         Ok(())
@@ -232,6 +488,10 @@ impl vfs::INode for INodeImpl {
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        let _remount = self.fs.begin_write()?;
         self.file.set_len(len)?;
         self.disk_inode.write().size = len as u32;
         Ok(())
@@ -242,6 +502,7 @@ impl vfs::INode for INodeImpl {
         type_: vfs::FileType,
         mode: u32,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        validate_name(name, MAX_FNAME_LEN)?;
         let type_ = match type_ {
             vfs::FileType::File => FileType::File,
             vfs::FileType::Dir => FileType::Dir,
@@ -257,26 +518,36 @@ impl vfs::INode for INodeImpl {
         }
 
         // Ensure the name is not exist
+        let _remount = self.fs.begin_write()?;
         if self.get_file_inode_id(name).is_some() {
             return Err(FsError::EntryExist);
         }
 
         // Create new INode
+        let mode = mode & !self.fs.umask.load(Ordering::SeqCst);
         let inode = self.fs.new_inode(type_, mode as u16)?;
         if type_ == FileType::Dir {
             inode.dirent_init(self.id)?;
         }
 
+        if type_ == FileType::Dir {
+            self.nlinks_inc()?; //for ..
+        }
+
         // Write new entry
-        let entry = DiskEntry {
-            id: inode.id as u32,
-            name: Str256::from(name),
-        };
-        self.dirent_append(&entry)?;
-        inode.nlinks_inc();
+        if let Err(e) = self
+            .fs
+            .new_entry(inode.id, name)
+            .and_then(|entry| self.dirent_append(&entry))
+        {
+            if type_ == FileType::Dir {
+                self.nlinks_dec();
+            }
+            return Err(e);
+        }
+        inode.nlinks_inc()?;
         if type_ == FileType::Dir {
-            inode.nlinks_inc(); //for .
-            self.nlinks_inc(); //for ..
+            inode.nlinks_inc()?; //for .
         }
 
         Ok(inode)
@@ -296,6 +567,7 @@ impl vfs::INode for INodeImpl {
             return Err(FsError::IsDir);
         }
 
+        let _remount = self.fs.begin_write()?;
         let (inode_id, entry_id) = self
             .get_file_inode_and_entry_id(name)
             .ok_or(FsError::EntryNotFound)?;
@@ -319,6 +591,7 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        validate_name(name, MAX_FNAME_LEN)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -326,6 +599,7 @@ impl vfs::INode for INodeImpl {
         if info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
+        let _remount = self.fs.begin_write()?;
         if self.get_file_inode_id(name).is_some() {
             return Err(FsError::EntryExist);
         }
@@ -335,18 +609,22 @@ impl vfs::INode for INodeImpl {
         if !Arc::ptr_eq(&self.fs, &child.fs) {
             return Err(FsError::NotSameFs);
         }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
+        if child.file_type()? == vfs::FileType::Dir {
             return Err(FsError::IsDir);
         }
-        let entry = DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        };
-        self.dirent_append(&entry)?;
-        child.nlinks_inc();
+        child.nlinks_inc()?;
+        if let Err(e) = self
+            .fs
+            .new_entry(child.id, name)
+            .and_then(|entry| self.dirent_append(&entry))
+        {
+            child.nlinks_dec();
+            return Err(e);
+        }
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        validate_name(new_name, MAX_FNAME_LEN)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -361,6 +639,7 @@ impl vfs::INode for INodeImpl {
             return Err(FsError::IsDir);
         }
 
+        let _remount = self.fs.begin_write()?;
         let dest = target
             .downcast_ref::<INodeImpl>()
             .ok_or(FsError::NotSameFs)?;
@@ -374,34 +653,92 @@ impl vfs::INode for INodeImpl {
         if dest_info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if dest.get_file_inode_id(new_name).is_some() {
+        if new_name == "." || new_name == ".." {
             return Err(FsError::EntryExist);
         }
 
         let (inode_id, entry_id) = self
             .get_file_inode_and_entry_id(old_name)
             .ok_or(FsError::EntryNotFound)?;
+        // both names link to the same INode, nothing to do
+        if dest.get_file_inode_id(new_name) == Some(inode_id) {
+            return Ok(());
+        }
+        let inode = self.fs.get_inode(inode_id);
+        let type_ = inode.disk_inode.read().type_;
+        let victim = dest.rename_victim(new_name, type_)?;
         if info.inode == dest_info.inode {
             // rename: in place modify name
-            let entry = DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            };
-            self.file.write_direntry(entry_id, &entry)?;
+            let entry = self.fs.new_entry(inode_id, new_name)?;
+            self.dirent_replace(entry_id, &entry)?;
+            if let Some((victim, victim_entry_id)) = victim {
+                self.dirent_remove(victim_entry_id)?;
+                self.release_victim(&victim);
+            }
         } else {
             // move
-            let inode = self.fs.get_inode(inode_id);
+            let is_dir = type_ == FileType::Dir;
+            if is_dir {
+                dest.nlinks_inc()?; //for .. of the moved dir
+            }
 
-            let entry = DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            };
-            dest.dirent_append(&entry)?;
-            self.dirent_remove(entry_id)?;
+            if let Err(e) = self
+                .fs
+                .new_entry(inode_id, new_name)
+                .and_then(|entry| match &victim {
+                    Some((_, victim_entry_id)) => dest.dirent_replace(*victim_entry_id, &entry),
+                    None => dest.dirent_append(&entry),
+                })
+            {
+                if is_dir {
+                    dest.nlinks_dec();
+                }
+                return Err(e);
+            }
+            if let Err(e) = self.dirent_remove(entry_id) {
+                // linked from both directories, drop the new entry
+                let undone = match &victim {
+                    Some((victim, victim_entry_id)) => self
+                        .fs
+                        .new_entry(victim.id, new_name)
+                        .and_then(|entry| dest.dirent_replace(*victim_entry_id, &entry)),
+                    None => {
+                        let last = dest.disk_inode.read().blocks as usize - 1;
+                        dest.dirent_remove(last)
+                    }
+                };
+                if let Err(undo_e) = undone {
+                    // keep both names then, each counted as a link
+                    error!(
+                        "failed to undo the entry {} of a move: {:?}",
+                        new_name, undo_e
+                    );
+                    if let Some((victim, _)) = &victim {
+                        dest.release_victim(victim);
+                    }
+                    if let Err(inc_e) = inode.nlinks_inc() {
+                        error!("failed to count the link {}: {:?}", new_name, inc_e);
+                    }
+                }
+                if is_dir {
+                    dest.nlinks_dec();
+                }
+                return Err(e);
+            }
+            if let Some((victim, _)) = victim {
+                dest.release_victim(&victim);
+            }
 
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
+            if is_dir {
+                // '..' of the moved dir refers to the new parent
+                inode.file.write_direntry(
+                    1,
+                    &DiskEntry {
+                        id: dest.id as u32,
+                        name: Str256::from(".."),
+                    },
+                )?;
                 self.nlinks_dec();
-                dest.nlinks_inc();
             }
         }
 
@@ -423,7 +760,43 @@ impl vfs::INode for INodeImpl {
             return Err(FsError::EntryNotFound);
         };
         let entry = self.file.read_direntry(id)?;
-        Ok(String::from(entry.name.as_ref()))
+        self.fs.entry_name(&entry)
+    }
+    fn get_entry_with_metadata(&self, id: usize) -> vfs::Result<(vfs::Metadata, String)> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if id >= self.disk_inode.read().blocks as usize {
+            return Err(FsError::EntryNotFound);
+        };
+        let entry = self.file.read_direntry(id)?;
+        Ok((
+            self.fs.get_inode(entry.id as usize).metadata()?,
+            self.fs.entry_name(&entry)?,
+        ))
+    }
+    fn read_dir_plus(&self, id: usize) -> vfs::Result<(usize, String)> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if id >= self.disk_inode.read().blocks as usize {
+            return Err(FsError::EntryNotFound);
+        };
+        let entry = self.file.read_direntry(id)?;
+        Ok((entry.id as usize, self.fs.entry_name(&entry)?))
+    }
+    fn snapshot_entries(&self) -> vfs::Result<Vec<(String, usize)>> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let _dir_lock = self.dir_lock.lock();
+        let count = self.disk_inode.read().blocks as usize;
+        (0..count)
+            .map(|id| {
+                let entry = self.file.read_direntry(id)?;
+                Ok((self.fs.entry_name(&entry)?, entry.id as usize))
+            })
+            .collect()
     }
     fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
         Err(FsError::NotSupported)
@@ -439,19 +812,60 @@ impl vfs::INode for INodeImpl {
     }
 }
 
+/// Split a `Timespec` into the on-disk low, high 32 bits of seconds and nanoseconds
+fn split_time(time: Timespec) -> (u32, u32, u32) {
+    (time.sec as u32, (time.sec >> 32) as u32, time.nsec as u32)
+}
+
+/// Join the on-disk parts of a time into a `Timespec`
+fn join_time(sec: u32, sec_hi: u32, nsec: u32) -> Timespec {
+    Timespec {
+        sec: ((sec_hi as u64) << 32 | sec as u64) as i64,
+        nsec: nsec as i32,
+    }
+}
+
 impl Drop for INodeImpl {
-    /// Auto sync when drop
+    /// Auto sync when drop, the meta file is flushed by `SEFS::sync`
     fn drop(&mut self) {
-        self.sync_all()
+        self.write_disk_inode()
+            .expect("Failed to sync when dropping the SEFS Inode");
+        self.file
+            .flush()
             .expect("Failed to sync when dropping the SEFS Inode");
         if self.disk_inode.read().nlinks == 0 {
             self.disk_inode.write().sync();
             self.fs.free_block(self.id);
             self.fs.device.remove(self.id).unwrap();
+            let xattr = self.disk_inode.read().xattr as usize;
+            if xattr != 0 {
+                self.fs.free_block(xattr);
+                self.fs.device.remove(xattr).unwrap();
+            }
         }
     }
 }
 
+/// Encoding of entry names on disk, e.g. to encrypt them apart from the meta file
+pub trait NameCipher: Send + Sync {
+    /// Encode `name` to be stored. The result must not contain NUL, nor be `.` or `..`.
+    fn encrypt(&self, name: &str) -> String;
+    /// Decode a stored name, `None` if it is invalid
+    fn decrypt(&self, name: &str) -> Option<String>;
+}
+
+/// Names stored as they are
+pub struct PlainName;
+
+impl NameCipher for PlainName {
+    fn encrypt(&self, name: &str) -> String {
+        String::from(name)
+    }
+    fn decrypt(&self, name: &str) -> Option<String> {
+        Some(String::from(name))
+    }
+}
+
 /// Simple Encrypted File System
 pub struct SEFS {
     /// on-disk superblock
@@ -468,14 +882,39 @@ pub struct SEFS {
     time_provider: &'static dyn TimeProvider,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SEFS>,
+    /// Shared by operations modifying INodes, held exclusively by `remount`
+    remount_lock: RwLock<()>,
+    /// Mounted read-only, modifications fail with `ReadOnlyFs`
+    read_only: AtomicBool,
+    /// Device id in metadata of INodes
+    dev: usize,
+    /// Bits cleared from the mode of created INodes
+    umask: AtomicU32,
+    /// Encoding of entry names
+    name_cipher: Box<dyn NameCipher>,
+    /// Counts of operations, see `stats`
+    stats: Arc<StatCounters>,
 }
 
 impl SEFS {
     /// Load SEFS
     pub fn open(
-        device: Box<dyn Storage>,
+        device: Arc<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
     ) -> vfs::Result<Arc<Self>> {
+        Self::open_with_name_cipher(device, time_provider, Box::new(PlainName))
+    }
+    /// Load SEFS whose entry names are encoded by `name_cipher`
+    pub fn open_with_name_cipher(
+        device: Arc<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        name_cipher: Box<dyn NameCipher>,
+    ) -> vfs::Result<Arc<Self>> {
+        let stats = Arc::new(StatCounters::default());
+        let device = Box::new(CountedStorage {
+            inner: device,
+            stats: stats.clone(),
+        });
         let meta_file = device.open(0)?;
         let super_block = meta_file.load_struct::<SuperBlock>(BLKN_SUPER)?;
         if !super_block.check() {
@@ -483,17 +922,12 @@ impl SEFS {
         }
 
         // load free map
-        let mut free_map = BitVec::with_capacity(BLKBITS * super_block.groups as usize);
-        unsafe {
-            free_map.set_len(BLKBITS * super_block.groups as usize);
-        }
+        let mut bytes = vec![0u8; BLKSIZE * super_block.groups as usize];
         for i in 0..super_block.groups as usize {
             let block_id = Self::get_freemap_block_id_of_group(i);
-            meta_file.read_block(
-                block_id,
-                &mut free_map.as_raw_mut_slice()[BLKSIZE * i..BLKSIZE * (i + 1)],
-            )?;
+            meta_file.read_block(block_id, &mut bytes[BLKSIZE * i..BLKSIZE * (i + 1)])?;
         }
+        let free_map = free_map_from_bytes(&bytes);
 
         Ok(SEFS {
             super_block: RwLock::new(Dirty::new(super_block)),
@@ -503,13 +937,27 @@ impl SEFS {
             meta_file,
             time_provider,
             self_ptr: Weak::default(),
+            remount_lock: RwLock::new(()),
+            read_only: AtomicBool::new(false),
+            dev: vfs::new_dev_id(),
+            umask: AtomicU32::new(0),
+            name_cipher,
+            stats,
         }
         .wrap())
     }
     /// Create a new SEFS
     pub fn create(
-        device: Box<dyn Storage>,
+        device: Arc<dyn Storage>,
         time_provider: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::create_with_name_cipher(device, time_provider, Box::new(PlainName))
+    }
+    /// Create a new SEFS encoding entry names by `name_cipher`
+    pub fn create_with_name_cipher(
+        device: Arc<dyn Storage>,
+        time_provider: &'static dyn TimeProvider,
+        name_cipher: Box<dyn NameCipher>,
     ) -> vfs::Result<Arc<Self>> {
         let blocks = BLKBITS;
 
@@ -527,6 +975,11 @@ impl SEFS {
             }
             bitset
         };
+        let stats = Arc::new(StatCounters::default());
+        let device = Box::new(CountedStorage {
+            inner: device,
+            stats: stats.clone(),
+        });
         let meta_file = device.create(0)?;
         meta_file.set_len(blocks * BLKSIZE)?;
 
@@ -538,6 +991,12 @@ impl SEFS {
             meta_file,
             time_provider,
             self_ptr: Weak::default(),
+            remount_lock: RwLock::new(()),
+            read_only: AtomicBool::new(false),
+            dev: vfs::new_dev_id(),
+            umask: AtomicU32::new(0),
+            name_cipher,
+            stats,
         }
         .wrap();
 
@@ -545,8 +1004,8 @@ impl SEFS {
         let root = sefs.new_inode(FileType::Dir, 0o777)?;
         assert_eq!(root.id, BLKN_ROOT);
         root.dirent_init(BLKN_ROOT)?;
-        root.nlinks_inc(); //for .
-        root.nlinks_inc(); //for ..(root's parent is itself)
+        root.nlinks_inc()?; //for .
+        root.nlinks_inc()?; //for ..(root's parent is itself)
         root.sync_all()?;
 
         Ok(sefs)
@@ -565,6 +1024,59 @@ impl SEFS {
         unsafe { Arc::from_raw(ptr) }
     }
 
+    /// Start modifying INodes.
+    /// Return a guard blocking `remount`, or `ReadOnlyFs`.
+    fn begin_write(&self) -> vfs::Result<RwLockReadGuard<'_, ()>> {
+        let guard = self.remount_lock.read();
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(FsError::ReadOnlyFs);
+        }
+        Ok(guard)
+    }
+    /// Make a `DiskEntry`, storing a long `name` in its own file
+    fn new_entry(&self, id: INodeId, name: &str) -> vfs::Result<DiskEntry> {
+        if name.len() > MAX_FNAME_LEN {
+            return Err(FsError::NameTooLong);
+        }
+        let name = self.name_cipher.encrypt(name);
+        let name = if name.len() <= MAX_INLINE_FNAME_LEN {
+            Str256::from(name.as_str())
+        } else {
+            let file_id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+            let file = self.device.create(file_id)?;
+            file.write_all_at(name.as_bytes(), 0)?;
+            file.flush()?;
+            Str256::long_name(file_id, name.len())
+        };
+        Ok(DiskEntry {
+            id: id as u32,
+            name,
+        })
+    }
+    /// Get the name of `entry`, reading the file of a long name if needed
+    fn entry_name(&self, entry: &DiskEntry) -> vfs::Result<String> {
+        let name = match entry.name.as_long_name() {
+            None => String::from(entry.name.as_ref()),
+            Some((file_id, len)) => {
+                let mut name = vec![0u8; len];
+                self.device.open(file_id)?.read_exact_at(&mut name, 0)?;
+                String::from_utf8(name).map_err(|_| FsError::WrongFs)?
+            }
+        };
+        // '.' and '..' are stored as they are
+        match name.as_str() {
+            "." | ".." => Ok(name),
+            _ => self.name_cipher.decrypt(&name).ok_or(FsError::WrongFs),
+        }
+    }
+    /// Free the file of a long name in `entry`, if any
+    fn free_entry_name(&self, entry: &DiskEntry) -> vfs::Result<()> {
+        if let Some((file_id, _)) = entry.name.as_long_name() {
+            self.device.remove(file_id)?;
+            self.free_block(file_id);
+        }
+        Ok(())
+    }
     /// Allocate a block, return block id
     fn alloc_block(&self) -> Option<usize> {
         let mut free_map = self.free_map.write();
@@ -585,6 +1097,7 @@ impl SEFS {
         });
         assert!(id.is_some(), "allocate block should always success");
         super_block.unused_blocks -= 1;
+        self.stats.blocks_allocated.fetch_add(1, Ordering::Relaxed);
         id
     }
     /// Free a block
@@ -593,6 +1106,7 @@ impl SEFS {
         assert!(!free_map[block_id]);
         free_map.set(block_id, true);
         self.super_block.write().unused_blocks += 1;
+        self.stats.blocks_freed.fetch_add(1, Ordering::Relaxed);
     }
 
     /// Create a new INode struct, then insert it to self.inodes
@@ -606,10 +1120,14 @@ impl SEFS {
         let inode = Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
-            file: match create {
-                true => self.device.create(id).unwrap(),
-                false => self.device.open(id).unwrap(),
-            },
+            dir_lock: Mutex::new(()),
+            file: Box::new(TrackedFile::new(
+                match create {
+                    true => self.device.create(id).unwrap(),
+                    false => self.device.open(id).unwrap(),
+                },
+                create,
+            )),
             fs: self.self_ptr.upgrade().unwrap(),
         });
         self.inodes.write().insert(id, Arc::downgrade(&inode));
@@ -628,12 +1146,13 @@ impl SEFS {
         }
         // Load if not in set, or is weak ref.
         let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id).unwrap());
+        self.stats.inode_loads.fetch_add(1, Ordering::Relaxed);
         self._new_inode(id, disk_inode, false)
     }
     /// Create a new INode file
     fn new_inode(&self, type_: FileType, mode: u16) -> vfs::Result<Arc<INodeImpl>> {
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let time = self.time_provider.current_time().sec as u32;
+        let (time, time_hi, time_nsec) = split_time(self.time_provider.current_time());
         let disk_inode = Dirty::new_dirty(DiskINode {
             size: 0,
             type_,
@@ -645,6 +1164,13 @@ impl SEFS {
             atime: time,
             mtime: time,
             ctime: time,
+            atime_hi: time_hi,
+            mtime_hi: time_hi,
+            ctime_hi: time_hi,
+            atime_nsec: time_nsec,
+            mtime_nsec: time_nsec,
+            ctime_nsec: time_nsec,
+            xattr: 0,
         });
         Ok(self._new_inode(id, disk_inode, true))
     }
@@ -677,8 +1203,9 @@ impl vfs::FileSystem for SEFS {
         // sync free_map
         let mut free_map = self.free_map.write();
         if free_map.dirty() {
+            let bytes = free_map_to_bytes(&free_map);
             for i in 0..super_block.groups as usize {
-                let slice = &free_map.as_raw_slice()[BLKSIZE * i..BLKSIZE * (i + 1)];
+                let slice = &bytes[BLKSIZE * i..BLKSIZE * (i + 1)];
                 self.meta_file
                     .write_all_at(slice, BLKSIZE * Self::get_freemap_block_id_of_group(i))?;
             }
@@ -688,10 +1215,12 @@ impl vfs::FileSystem for SEFS {
         self.flush_weak_inodes();
         for inode in self.inodes.read().values() {
             if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
+                inode.write_disk_inode()?;
+                inode.file.flush()?;
             }
         }
         self.meta_file.flush()?;
+        self.stats.syncs.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
@@ -712,6 +1241,46 @@ impl vfs::FileSystem for SEFS {
             namemax: MAX_FNAME_LEN,
         }
     }
+
+    fn is_dirty(&self) -> bool {
+        self.super_block.read().dirty()
+            || self.free_map.read().dirty()
+            || self
+                .inodes
+                .read()
+                .values()
+                .filter_map(Weak::upgrade)
+                .any(|inode| inode.disk_inode.read().dirty())
+    }
+
+    fn remount(&self, options: vfs::MountOptions) -> vfs::Result<()> {
+        // wait for ongoing modifications, and block new ones until switched
+        let _remount = self.remount_lock.write();
+        if options.read_only {
+            self.sync()?;
+        }
+        self.read_only.store(options.read_only, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn mount_options(&self) -> vfs::MountOptions {
+        vfs::MountOptions {
+            read_only: self.read_only.load(Ordering::SeqCst),
+        }
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "sefs"
+    }
+
+    fn stats(&self) -> vfs::Statistics {
+        self.stats.snapshot()
+    }
+
+    fn set_umask(&self, umask: u32) -> vfs::Result<()> {
+        self.umask.store(umask, Ordering::SeqCst);
+        Ok(())
+    }
 }
 
 impl Drop for SEFS {
@@ -736,13 +1305,21 @@ impl BitsetAlloc for BitVec<u8, Lsb0> {
     }
 }
 
-impl AsBuf for BitVec<u8, Lsb0> {
-    fn as_buf(&self) -> &[u8] {
-        self.as_raw_slice()
-    }
-    fn as_buf_mut(&mut self) -> &mut [u8] {
-        self.as_raw_mut_slice()
+/// The free map as stored on disk: bit `i` is bit `i % 8` of byte `i / 8`,
+/// regardless of how `BitVec` lays it out in memory
+fn free_map_to_bytes(free_map: &BitSlice<u8, Lsb0>) -> Vec<u8> {
+    let mut bytes = vec![0u8; free_map.len().div_ceil(8)];
+    for i in free_map.iter_ones() {
+        bytes[i / 8] |= 1 << (i % 8);
     }
+    bytes
+}
+
+/// Inverse of `free_map_to_bytes`
+fn free_map_from_bytes(bytes: &[u8]) -> BitVec<u8, Lsb0> {
+    (0..bytes.len() * 8)
+        .map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+        .collect()
 }
 
 impl AsBuf for [u8; BLKSIZE] {}
@@ -1,6 +1,7 @@
 //! On-disk structures in SEFS
 
 use alloc::str;
+use core::convert::TryInto;
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::{size_of, size_of_val};
 use core::slice;
@@ -37,9 +38,22 @@ pub struct DiskINode {
     pub blocks: u32,
     pub uid: u16,
     pub gid: u8,
+    /// low 32 bits of seconds of times
     pub atime: u32,
     pub mtime: u32,
     pub ctime: u32,
+    /// high 32 bits of seconds of times
+    /// Note: images created before these fields leave them 0
+    pub atime_hi: u32,
+    pub mtime_hi: u32,
+    pub ctime_hi: u32,
+    /// nanoseconds of times, 0 in old images as above
+    pub atime_nsec: u32,
+    pub mtime_nsec: u32,
+    pub ctime_nsec: u32,
+    /// file holding the extended attributes, 0 if none
+    /// Note: images created before this field leave it 0
+    pub xattr: u32,
 }
 
 /// On-disk file entry
@@ -48,7 +62,7 @@ pub struct DiskINode {
 pub struct DiskEntry {
     /// inode number
     pub id: u32,
-    /// file name
+    /// file name, or a reference to the file holding a long name
     pub name: Str256,
 }
 
@@ -62,6 +76,27 @@ impl AsRef<str> for Str256 {
     }
 }
 
+impl Str256 {
+    /// Refer to a long name of `len` bytes stored in file `file_id`.
+    /// It is an empty string followed by `file_id` and `len`.
+    pub fn long_name(file_id: usize, len: usize) -> Self {
+        let mut ret = [0u8; 256];
+        ret[1..5].copy_from_slice(&(file_id as u32).to_le_bytes());
+        ret[5..9].copy_from_slice(&(len as u32).to_le_bytes());
+        Str256(ret)
+    }
+
+    /// The `(file_id, len)` of the long name it refers to, if any
+    pub fn as_long_name(&self) -> Option<(usize, usize)> {
+        if self.0[0] != 0 {
+            return None;
+        }
+        let file_id = u32::from_le_bytes(self.0[1..5].try_into().unwrap());
+        let len = u32::from_le_bytes(self.0[5..9].try_into().unwrap());
+        Some((file_id as usize, len as usize))
+    }
+}
+
 impl Debug for Str256 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         write!(f, "{}", self.as_ref())
@@ -114,7 +149,11 @@ pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
 pub const BLKSIZE_LOG2: u8 = 7;
 /// max length of filename
-pub const MAX_FNAME_LEN: usize = 255;
+pub const MAX_FNAME_LEN: usize = 1023;
+/// max length of filename stored in `DiskEntry`, longer ones are stored in their own files
+pub const MAX_INLINE_FNAME_LEN: usize = 255;
+/// max size of a file, as `DiskINode::size` is u32
+pub const MAX_FILE_SIZE: usize = u32::MAX as usize;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
 /// location of the root dir inode
@@ -125,6 +164,10 @@ pub const BLKN_FREEMAP: BlockId = 1;
 pub const BLKBITS: usize = BLKSIZE * 8;
 /// size of a dirent used in the size field
 pub const DIRENT_SIZE: usize = 260;
+/// max length of the name of an extended attribute
+pub const MAX_XATTR_NAME_LEN: usize = 255;
+/// max size of all extended attributes of an INode, as stored
+pub const MAX_XATTR_SIZE: usize = 4096;
 
 /// file types
 #[repr(u16)]
@@ -0,0 +1,540 @@
+extern crate std;
+
+use crate::*;
+use rcore_fs::{
+    dev::ZeroTimeProvider,
+    testing::*,
+    vfs::{FileSystem, FileType, FsError, Result},
+};
+use std::format;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn _create_new_sefs(path: &std::path::Path) -> Arc<SEFS> {
+    SEFS::create(Arc::new(StdStorage::new(path)), &ZeroTimeProvider).expect("failed to create SEFS")
+}
+
+/// A `Storage` counting reads and flushes of all its files, and writes of the meta file
+struct CountingStorage {
+    inner: StdStorage,
+    reads: Arc<AtomicUsize>,
+    flushes: Arc<AtomicUsize>,
+    meta_writes: Arc<AtomicUsize>,
+}
+
+struct CountingFile {
+    inner: Box<dyn File>,
+    reads: Arc<AtomicUsize>,
+    flushes: Arc<AtomicUsize>,
+    /// counting writes, only for the meta file
+    writes: Option<Arc<AtomicUsize>>,
+}
+
+impl CountingStorage {
+    fn new(path: &std::path::Path) -> Self {
+        CountingStorage {
+            inner: StdStorage::new(path),
+            reads: Arc::new(AtomicUsize::new(0)),
+            flushes: Arc::new(AtomicUsize::new(0)),
+            meta_writes: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    fn wrap(&self, file_id: usize, inner: Box<dyn File>) -> Box<dyn File> {
+        Box::new(CountingFile {
+            inner,
+            reads: self.reads.clone(),
+            flushes: self.flushes.clone(),
+            writes: (file_id == 0).then(|| self.meta_writes.clone()),
+        })
+    }
+}
+
+impl Storage for CountingStorage {
+    fn open(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        Ok(self.wrap(file_id, self.inner.open(file_id)?))
+    }
+    fn create(&self, file_id: usize) -> DevResult<Box<dyn File>> {
+        Ok(self.wrap(file_id, self.inner.create(file_id)?))
+    }
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        self.inner.remove(file_id)
+    }
+}
+
+impl File for CountingFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        if let Some(writes) = &self.writes {
+            writes.fetch_add(1, Ordering::SeqCst);
+        }
+        self.inner.write_at(buf, offset)
+    }
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        self.inner.set_len(len)
+    }
+    fn flush(&self) -> DevResult<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn long_file_name() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let long_name = "x".repeat(300);
+    {
+        let sefs = _create_new_sefs(dir.path());
+        let root = sefs.root_inode();
+        root.create(&long_name, FileType::File, 0o777)?;
+        root.create("short", FileType::File, 0o777)?;
+        assert_eq!(
+            root.create(&"y".repeat(MAX_FNAME_LEN + 1), FileType::File, 0o777)
+                .err(),
+            Some(FsError::NameTooLong)
+        );
+        sefs.sync()?;
+    }
+
+    let sefs = SEFS::open(Arc::new(StdStorage::new(dir.path())), &ZeroTimeProvider)?;
+    let root = sefs.root_inode();
+    assert!(root.find(&long_name).is_ok());
+    assert_eq!(root.get_entry(2)?, long_name);
+
+    // rename to another long name, then back to a short one
+    let other_name = format!("{}z", long_name);
+    root.move_(&long_name, &root, &other_name)?;
+    assert!(root.find(&other_name).is_ok());
+    root.move_(&other_name, &root, "renamed")?;
+    assert_eq!(root.list()?, [".", "..", "renamed", "short"]);
+    root.unlink("renamed")?;
+    assert_eq!(root.list()?, [".", "..", "short"]);
+
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn move_dir_updates_dotdot() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let sefs = _create_new_sefs(dir.path());
+    let root = sefs.root_inode();
+    let a = root.create("a", FileType::Dir, 0o777)?;
+    let b = root.create("b", FileType::Dir, 0o777)?;
+    a.create("sub", FileType::Dir, 0o777)?;
+
+    a.move_("sub", &b, "sub")?;
+    let sub = root.lookup("b/sub")?;
+    assert_eq!(sub.lookup("..")?.metadata()?.inode, b.metadata()?.inode);
+
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn umask() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let sefs = _create_new_sefs(dir.path());
+        let root = sefs.root_inode();
+        root.create("before", FileType::File, 0o777)?;
+        sefs.set_umask(0o022)?;
+        root.create("after", FileType::File, 0o777)?;
+        sefs.sync()?;
+    }
+
+    let sefs = SEFS::open(Arc::new(StdStorage::new(dir.path())), &ZeroTimeProvider)?;
+    let root = sefs.root_inode();
+    assert_eq!(root.find("before")?.metadata()?.mode, 0o777);
+    assert_eq!(root.find("after")?.metadata()?.mode, 0o755);
+    Ok(())
+}
+
+#[test]
+fn get_entry_with_metadata() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = CountingStorage::new(dir.path());
+    let reads = storage.reads.clone();
+    let sefs = SEFS::create(Arc::new(storage), &ZeroTimeProvider)?;
+    let root = sefs.root_inode();
+    for i in 0..8 {
+        root.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+    sefs.sync()?;
+
+    // the default implementation: get_entry, find, then metadata
+    reads.store(0, Ordering::SeqCst);
+    let name = root.get_entry(9)?;
+    let expected = root.find(&name)?.metadata()?;
+    let slow_reads = reads.load(Ordering::SeqCst);
+
+    reads.store(0, Ordering::SeqCst);
+    let (metadata, name) = root.get_entry_with_metadata(9)?;
+    let fast_reads = reads.load(Ordering::SeqCst);
+    assert_eq!(name, "file7");
+    assert_eq!(metadata.inode, expected.inode);
+    assert!(fast_reads < slow_reads);
+
+    sefs.sync()?;
+    Ok(())
+}
+
+/// Rotates letters by 13, a toy cipher for tests
+struct Rot13;
+
+impl Rot13 {
+    fn rotate(name: &str) -> String {
+        name.chars()
+            .map(|c| match c {
+                'a'..='z' => ((c as u8 - b'a' + 13) % 26 + b'a') as char,
+                'A'..='Z' => ((c as u8 - b'A' + 13) % 26 + b'A') as char,
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+impl NameCipher for Rot13 {
+    fn encrypt(&self, name: &str) -> String {
+        Self::rotate(name)
+    }
+    fn decrypt(&self, name: &str) -> Option<String> {
+        Some(Self::rotate(name))
+    }
+}
+
+#[test]
+fn name_cipher() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let long_name = "x".repeat(300);
+    {
+        let sefs = SEFS::create_with_name_cipher(
+            Arc::new(StdStorage::new(dir.path())),
+            &ZeroTimeProvider,
+            Box::new(Rot13),
+        )?;
+        let root = sefs.root_inode();
+        let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+        dir1.create("hello", FileType::File, 0o777)?;
+        root.create(&long_name, FileType::File, 0o777)?;
+        sefs.sync()?;
+    }
+
+    let sefs = SEFS::open_with_name_cipher(
+        Arc::new(StdStorage::new(dir.path())),
+        &ZeroTimeProvider,
+        Box::new(Rot13),
+    )?;
+    let root = sefs.root_inode();
+    let dir1 = root.lookup("dir1")?;
+    assert!(dir1.find("hello").is_ok());
+    assert_eq!(dir1.lookup("..")?.metadata()?.inode, BLKN_ROOT);
+    assert!(root.find(&long_name).is_ok());
+    assert_eq!(root.get_entry(2)?, "dir1");
+    drop((dir1, root, sefs));
+
+    // names are stored encrypted
+    let sefs = SEFS::open(Arc::new(StdStorage::new(dir.path())), &ZeroTimeProvider)?;
+    let root = sefs.root_inode();
+    assert_eq!(root.get_entry(2)?, "qve1");
+    assert!(root.lookup("qve1/uryyb").is_ok());
+    assert!(root.find(&"k".repeat(300)).is_ok());
+    assert_eq!(root.find("dir1").err(), Some(FsError::EntryNotFound));
+    Ok(())
+}
+
+#[test]
+fn sync_skips_clean_files() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = CountingStorage::new(dir.path());
+    let flushes = storage.flushes.clone();
+    let sefs = SEFS::create(Arc::new(storage), &ZeroTimeProvider)?;
+    let root = sefs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    file1.write_at(0, b"file1")?;
+    file2.write_at(0, b"file2")?;
+    sefs.sync()?;
+
+    // only the meta file, when nothing changed
+    flushes.store(0, Ordering::SeqCst);
+    sefs.sync()?;
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+
+    flushes.store(0, Ordering::SeqCst);
+    file1.write_at(0, b"FILE1")?;
+    sefs.sync()?;
+    assert_eq!(flushes.load(Ordering::SeqCst), 2);
+
+    flushes.store(0, Ordering::SeqCst);
+    file2.resize(2)?;
+    file2.sync_data()?;
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    sefs.sync()?;
+    assert_eq!(flushes.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[test]
+fn free_map_bytes() -> Result<()> {
+    let mut free_map = bitvec![u8, Lsb0; 0; 32];
+    for i in [0, 9, 17, 31] {
+        free_map.set(i, true);
+    }
+    let bytes = free_map_to_bytes(&free_map);
+    assert_eq!(bytes, [0x01, 0x02, 0x02, 0x80]);
+    assert_eq!(free_map_from_bytes(&bytes), free_map);
+
+    // the same bits are set after reloading
+    let dir = tempfile::tempdir().unwrap();
+    let expected = {
+        let sefs = _create_new_sefs(dir.path());
+        let root = sefs.root_inode();
+        for i in 0..10 {
+            root.create(&format!("file{}", i), FileType::File, 0o777)?;
+        }
+        root.unlink("file3")?;
+        sefs.sync()?;
+        let free_map = sefs.free_map.read();
+        BitVec::clone(&free_map)
+    };
+    let mut block = [0u8; BLKSIZE];
+    let meta_file = StdStorage::new(dir.path()).open(0)?;
+    meta_file.read_block(BLKN_FREEMAP, &mut block)?;
+    assert_eq!(free_map_from_bytes(&block), expected);
+
+    let sefs = SEFS::open(Arc::new(StdStorage::new(dir.path())), &ZeroTimeProvider)?;
+    assert_eq!(**sefs.free_map.read(), expected);
+    Ok(())
+}
+
+#[test]
+fn too_many_links() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let sefs = _create_new_sefs(dir.path());
+    let root = sefs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    let inode = file.downcast_ref::<INodeImpl>().unwrap();
+    inode.disk_inode.write().nlinks = u16::MAX - 1;
+    root.link("last", &file)?;
+    assert_eq!(root.link("one_more", &file), Err(FsError::TooManyLinks));
+    assert_eq!(file.metadata()?.nlinks, u16::MAX as usize);
+    assert_eq!(root.list()?, [".", "..", "file", "last"]);
+
+    let sub = root.create("sub", FileType::Dir, 0o777)?;
+    sub.create("inner", FileType::Dir, 0o777)?;
+    let root_inode = root.downcast_ref::<INodeImpl>().unwrap();
+    root_inode.disk_inode.write().nlinks = u16::MAX;
+    assert_eq!(
+        root.create("dir", FileType::Dir, 0o777).err(),
+        Some(FsError::TooManyLinks)
+    );
+    assert_eq!(
+        sub.move_("inner", &root, "inner"),
+        Err(FsError::TooManyLinks)
+    );
+    assert!(sub.find("inner").is_ok());
+    root_inode.disk_inode.write().nlinks = 3;
+
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let sefs = _create_new_sefs(dir.path());
+    let root = sefs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"file1")?;
+    sefs.sync()?;
+
+    let stats = sefs.stats();
+    let mut buf = [0u8; 5];
+    file1.read_at(0, &mut buf)?;
+    assert_eq!(sefs.stats().block_reads, stats.block_reads + 1);
+    file1.write_at(0, b"FILE1")?;
+    assert_eq!(sefs.stats().block_writes, stats.block_writes + 1);
+
+    drop(file1);
+    let stats = sefs.stats();
+    let file1 = root.find("file1")?;
+    root.find("file1")?;
+    assert_eq!(sefs.stats().inode_loads, stats.inode_loads + 1);
+
+    let stats = sefs.stats();
+    root.unlink("file1")?;
+    drop(file1);
+    root.create("file2", FileType::File, 0o777)?;
+    sefs.sync()?;
+    let after = sefs.stats();
+    assert_eq!(after.blocks_freed, stats.blocks_freed + 1);
+    assert_eq!(after.blocks_allocated, stats.blocks_allocated + 1);
+    assert_eq!(after.syncs, stats.syncs + 1);
+    Ok(())
+}
+
+#[test]
+fn move_onto_existing() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let sefs = _create_new_sefs(dir.path());
+    let root = sefs.root_inode();
+    root.create("a", FileType::Dir, 0o777)?
+        .create("x", FileType::File, 0o777)?;
+    root.create("empty", FileType::Dir, 0o777)?;
+    root.create("full", FileType::Dir, 0o777)?
+        .create("y", FileType::File, 0o777)?;
+    root.create("file", FileType::File, 0o777)?;
+    assert_eq!(root.metadata()?.nlinks, 5);
+
+    // in the same directory
+    assert_eq!(root.move_("a", &root, "full"), Err(FsError::DirNotEmpty));
+    assert_eq!(root.move_("a", &root, "file"), Err(FsError::NotDir));
+    assert_eq!(root.move_("file", &root, "empty"), Err(FsError::IsDir));
+    root.move_("a", &root, "empty")?;
+    assert!(root.lookup("empty/x").is_ok());
+    assert_eq!(root.find("a").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.metadata()?.nlinks, 4);
+
+    // across directories
+    let sub = root.create("sub", FileType::Dir, 0o777)?;
+    sub.create("empty", FileType::Dir, 0o777)?;
+    sub.create("full", FileType::Dir, 0o777)?
+        .create("z", FileType::File, 0o777)?;
+    sub.create("file", FileType::File, 0o777)?;
+    assert_eq!(root.move_("empty", &sub, "full"), Err(FsError::DirNotEmpty));
+    assert_eq!(root.move_("empty", &sub, "file"), Err(FsError::NotDir));
+    assert_eq!(root.move_("file", &sub, "empty"), Err(FsError::IsDir));
+    root.move_("empty", &sub, "empty")?;
+    assert!(sub.lookup("empty/x").is_ok());
+    assert_eq!(
+        sub.lookup("empty/..")?.metadata()?.inode,
+        sub.metadata()?.inode
+    );
+    assert_eq!(sub.metadata()?.nlinks, 4);
+    assert_eq!(root.metadata()?.nlinks, 4);
+
+    // a file replaces a file
+    let file = root.find("file")?;
+    root.move_("file", &sub, "file")?;
+    assert_eq!(sub.find("file")?.metadata()?.inode, file.metadata()?.inode);
+    assert_eq!(sub.list()?.len(), 5);
+
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn xattr() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let sefs = _create_new_sefs(dir.path());
+        let root = sefs.root_inode();
+        let file = root.create("file", FileType::File, 0o777)?;
+        assert_eq!(file.list_xattr()?, Vec::<String>::new());
+        assert_eq!(
+            file.get_xattr("security.selinux"),
+            Err(FsError::EntryNotFound)
+        );
+        file.set_xattr("security.selinux", b"system_u:object_r:etc_t:s0")?;
+        file.set_xattr("user.note", b"old")?;
+        file.set_xattr("user.note", b"new")?;
+        file.set_xattr("user.empty", b"")?;
+        assert_eq!(file.remove_xattr("user.none"), Err(FsError::EntryNotFound));
+        file.remove_xattr("user.empty")?;
+
+        // limited in total
+        assert_eq!(
+            file.set_xattr("user.big", &[0; MAX_XATTR_SIZE]),
+            Err(FsError::NoDeviceSpace)
+        );
+        assert_eq!(file.set_xattr("", b""), Err(FsError::InvalidParam));
+        sefs.sync()?;
+    }
+
+    let sefs = SEFS::open(Arc::new(StdStorage::new(dir.path())), &ZeroTimeProvider)?;
+    let root = sefs.root_inode();
+    let file = root.find("file")?;
+    assert_eq!(
+        file.list_xattr()?,
+        ["security.selinux", "user.note"].map(String::from)
+    );
+    assert_eq!(
+        file.get_xattr("security.selinux")?,
+        b"system_u:object_r:etc_t:s0"
+    );
+    assert_eq!(file.get_xattr("user.note")?, b"new");
+
+    // the file of xattrs is freed with the last one, or with the INode
+    let free = sefs.info().bfree;
+    file.remove_xattr("user.note")?;
+    file.remove_xattr("security.selinux")?;
+    assert_eq!(sefs.info().bfree, free + 1);
+    file.set_xattr("user.note", b"again")?;
+    drop(file);
+    root.unlink("file")?;
+    assert_eq!(sefs.info().bfree, free + 2);
+    sefs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn sync_data_skips_inode() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = CountingStorage::new(dir.path());
+    let meta_writes = storage.meta_writes.clone();
+    let flushes = storage.flushes.clone();
+    let sefs = SEFS::create(Arc::new(storage), &ZeroTimeProvider)?;
+    let file = sefs.root_inode().create("file", FileType::File, 0o777)?;
+    file.write_at(0, &[1; 100])?;
+    file.sync_all()?;
+
+    // a pure data write: only the data file is flushed
+    meta_writes.store(0, Ordering::SeqCst);
+    flushes.store(0, Ordering::SeqCst);
+    file.write_at(10, &[2; 10])?;
+    file.sync_data()?;
+    assert_eq!(meta_writes.load(Ordering::SeqCst), 0);
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+
+    // the inode block, with the new size, waits for sync_all
+    file.write_at(100, &[3; 10])?;
+    file.sync_data()?;
+    assert_eq!(meta_writes.load(Ordering::SeqCst), 0);
+    assert_eq!(flushes.load(Ordering::SeqCst), 2);
+    file.sync_all()?;
+    assert_eq!(meta_writes.load(Ordering::SeqCst), 1);
+    // the meta file, the data file is clean
+    assert_eq!(flushes.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[test]
+fn short_reads() {
+    let dir = tempfile::tempdir().unwrap();
+    check_short_reads(_create_new_sefs(dir.path()).root_inode());
+}
+
+#[test]
+fn invalid_names() {
+    let dir = tempfile::tempdir().unwrap();
+    check_invalid_names(_create_new_sefs(dir.path()).root_inode());
+}
+
+#[test]
+fn write_at_offset_overflow() -> Result<()> {
+    let dir = tempfile::tempdir().unwrap();
+    let root = _create_new_sefs(dir.path()).root_inode();
+    check_write_at_offset_overflow(root.clone());
+    let file = root.find("file")?;
+    assert_eq!(file.resize(MAX_FILE_SIZE + 1), Err(FsError::InvalidParam));
+    assert_eq!(file.metadata()?.size, 7);
+    Ok(())
+}
+
+#[test]
+fn list_while_unlinking() {
+    let dir = tempfile::tempdir().unwrap();
+    check_list_while_unlinking(_create_new_sefs(dir.path()).root_inode());
+}
@@ -5,7 +5,7 @@ extern crate alloc;
 extern crate log;
 
 use alloc::{
-    collections::BTreeMap,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
     string::String,
     sync::{Arc, Weak},
     vec,
@@ -13,17 +13,21 @@ use alloc::{
 };
 use core::{
     any::Any,
+    convert::TryFrom,
     fmt::{Debug, Error, Formatter},
+    mem::offset_of,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use bitvec::prelude::*;
-use spin::RwLock;
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
 
 use rcore_fs::{
-    dev::Device,
+    dev::{DevError, DevErrorKind, Device, Result as DevResult, TimeProvider, ZeroTimeProvider},
     dirty::Dirty,
     util::*,
-    vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata},
+    vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata, StatCounters},
 };
 
 pub use structs::*;
@@ -37,14 +41,37 @@ trait DeviceExt: Device {
         debug_assert!(offset + buf.len() <= BLKSIZE);
         match self.read_at(id * BLKSIZE + offset, buf) {
             Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot read block {} offset {} from device", id, offset),
+            result => {
+                error!(
+                    "cannot read block {} offset {} from device: {:?}",
+                    id, offset, result
+                );
+                Err(FsError::DeviceError)
+            }
         }
     }
     fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
         match self.write_at(id * BLKSIZE + offset, buf) {
             Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot write block {} offset {} to device", id, offset),
+            result => {
+                error!(
+                    "cannot write block {} offset {} to device: {:?}",
+                    id, offset, result
+                );
+                Err(FsError::DeviceError)
+            }
+        }
+    }
+    /// Write `buf` to the blocks from `id` on, a multiple of the block size
+    fn write_blocks(&self, id: BlockId, buf: &[u8]) -> vfs::Result<()> {
+        debug_assert!(buf.len().is_multiple_of(BLKSIZE));
+        match self.write_at(id * BLKSIZE, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            result => {
+                error!("cannot write blocks from {} to device: {:?}", id, result);
+                Err(FsError::DeviceError)
+            }
         }
     }
     /// Load struct `T` from given block in device
@@ -57,17 +84,140 @@ trait DeviceExt: Device {
 
 impl DeviceExt for dyn Device {}
 
+impl DeviceExt for TxDevice {}
+
+/// Device wrapper holding back writes while a transaction is open
+struct TxDevice {
+    inner: Arc<dyn Device>,
+    /// number of open transactions
+    depth: AtomicUsize,
+    /// blocks written but not yet flushed to `inner`
+    pending: RwLock<BTreeMap<BlockId, Vec<u8>>>,
+    /// counting requests to `inner`, shared with the SFS
+    stats: Arc<StatCounters>,
+}
+
+impl TxDevice {
+    fn new(inner: Arc<dyn Device>, stats: Arc<StatCounters>) -> Self {
+        TxDevice {
+            inner,
+            depth: AtomicUsize::new(0),
+            pending: RwLock::new(BTreeMap::new()),
+            stats,
+        }
+    }
+    fn read_inner(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        self.stats.block_reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_at(offset, buf)
+    }
+    fn write_inner(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        self.stats.block_writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.write_at(offset, buf)
+    }
+    fn in_transaction(&self) -> bool {
+        self.depth.load(Ordering::SeqCst) > 0
+    }
+    /// Write all pending blocks to the inner device
+    fn flush_pending(&self) -> DevResult<()> {
+        let mut pending = self.pending.write();
+        while let Some((block_id, data)) = pending.pop_first() {
+            if let Err(e) = self.write_inner(block_id * BLKSIZE, &data) {
+                pending.insert(block_id, data);
+                return Err(e.at(block_id * BLKSIZE, BLKSIZE));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for TxDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let pending = self.pending.read();
+        if pending.is_empty() {
+            return self.read_inner(offset, buf);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        for range in iter {
+            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            match pending.get(&range.block) {
+                Some(data) => buf.copy_from_slice(&data[range.begin..range.end]),
+                None => {
+                    let len = self.read_inner(range.origin_begin(), buf)?;
+                    if len != buf.len() {
+                        return Ok(range.origin_begin() - offset + len);
+                    }
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let mut pending = self.pending.write();
+        if pending.is_empty() && !self.in_transaction() {
+            return self.write_inner(offset, buf);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        for range in iter {
+            let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let data = match pending.entry(range.block) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut data = vec![0u8; BLKSIZE];
+                    if !range.is_full() {
+                        self.read_inner(range.block * BLKSIZE, &mut data)?;
+                    }
+                    entry.insert(data)
+                }
+            };
+            data[range.begin..range.end].copy_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+    /// Deferred until the outermost transaction finishes
+    fn sync(&self) -> DevResult<()> {
+        if self.in_transaction() {
+            return Ok(());
+        }
+        let sync_failed = |e: DevError| e.with_kind(DevErrorKind::SyncFailed);
+        self.flush_pending().map_err(sync_failed)?;
+        self.inner.sync().map_err(sync_failed)
+    }
+    fn fadvise(&self, offset: usize, len: usize, advice: vfs::Advice) -> DevResult<()> {
+        self.inner.fadvise(offset, len, advice)
+    }
+    fn size(&self) -> Option<usize> {
+        self.inner.size()
+    }
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
 /// INode for SFS
 pub struct INodeImpl {
     /// INode number
     id: INodeId,
     /// On-disk INode
     disk_inode: RwLock<Dirty<DiskINode>>,
+    /// Serialize modifications of directory entries
+    dir_lock: Mutex<()>,
     /// Reference to SFS, used by almost all operations
     fs: Arc<SimpleFileSystem>,
     /// Char/block device id (major, minor)
     /// e.g. crw-rw-rw- 1 root wheel 3, 2 May 13 16:40 /dev/null
     device_inode_id: usize,
+    /// Number of open handles, see `INode::open`
+    open_count: AtomicUsize,
+    /// The blocks have been freed after unlinked and closed
+    released: AtomicBool,
 }
 
 impl Debug for INodeImpl {
@@ -81,7 +231,7 @@ impl Debug for INodeImpl {
 }
 
 impl INodeImpl {
-    /// Map file block id to disk block id
+    /// Map file block id to disk block id, 0 for a hole
     fn get_disk_block_id(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
         let disk_inode = self.disk_inode.read();
         match file_block_id {
@@ -112,7 +262,6 @@ impl INodeImpl {
                     ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
                     disk_block_id.as_buf_mut(),
                 )?;
-                assert!(disk_block_id > 0);
                 Ok(disk_block_id as BlockId)
             }
             _ => unimplemented!("triple indirect blocks is not supported"),
@@ -155,6 +304,102 @@ impl INodeImpl {
             _ => unimplemented!("triple indirect blocks is not supported"),
         }
     }
+    /// Map file block id to disk block id like `get_disk_block_id`, to write the block.
+    /// A block shared with a snapshot is copied to a new block first, see
+    /// `SimpleFileSystem::snapshot`.
+    fn get_disk_block_id_mut(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
+        let refcounts = self.fs.super_block.read().refcounts as INodeId;
+        // the reference counts themselves are never shared
+        if refcounts == 0 || refcounts == self.id {
+            return self.get_disk_block_id(file_block_id);
+        }
+        let refcounts = self.fs.get_inode(refcounts)?;
+        let _refcount_lock = self.fs.refcount_lock.lock();
+        let disk_block_id = self.get_disk_block_id(file_block_id)?;
+        if disk_block_id == 0 || SimpleFileSystem::extra_refs(&refcounts, disk_block_id)? == 0 {
+            return Ok(disk_block_id);
+        }
+        let new_block_id = self
+            .fs
+            .alloc_block(disk_block_id)
+            .ok_or(FsError::NoDeviceSpace)?;
+        let mut buf = [0u8; BLKSIZE];
+        let copied = self
+            .fs
+            .device
+            .read_block(disk_block_id, 0, &mut buf)
+            .and_then(|()| self.fs.device.write_block(new_block_id, 0, &buf))
+            .and_then(|()| self.set_disk_block_id(file_block_id, new_block_id));
+        if let Err(e) = copied {
+            self.fs.free_block(new_block_id);
+            return Err(e);
+        }
+        self.fs.unref_block(&refcounts, disk_block_id)?;
+        Ok(new_block_id)
+    }
+    /// Write a copy of this INode to block `id` for a snapshot.
+    /// Each content block is mapped by `f`, called with its file block id and disk block id,
+    /// and the indirect blocks are copied to blocks taken from `new_blocks`.
+    fn copy_to(
+        &self,
+        id: INodeId,
+        new_blocks: &mut impl Iterator<Item = BlockId>,
+        mut f: impl FnMut(BlockId, BlockId) -> vfs::Result<BlockId>,
+    ) -> vfs::Result<()> {
+        let mut copy: DiskINode = unsafe { uninit_memory() };
+        copy.as_buf_mut()
+            .copy_from_slice(self.disk_inode.read().as_buf());
+        copy.generation = self.fs.read_disk_generation(id)?.wrapping_add(1);
+        let blocks = copy.blocks as usize;
+        let mut map = |i| -> vfs::Result<u32> {
+            match self.get_disk_block_id(i)? {
+                0 => Ok(0),
+                disk_block_id => Ok(f(i, disk_block_id)? as u32),
+            }
+        };
+        for i in 0..blocks.min(NDIRECT) {
+            copy.direct[i] = map(i)?;
+        }
+        // indirect blocks exist from `MAX_NBLOCK_DIRECT` blocks on, see `_resize`
+        if blocks >= MAX_NBLOCK_DIRECT {
+            copy.indirect = new_blocks.next().unwrap() as u32;
+            let mut indirect = IndirectBlock {
+                entries: [0; BLK_NENTRY],
+            };
+            for i in NDIRECT..blocks.min(MAX_NBLOCK_INDIRECT) {
+                indirect.entries[i - NDIRECT] = map(i)?;
+            }
+            self.fs
+                .device
+                .write_block(copy.indirect as usize, 0, indirect.as_buf())?;
+        }
+        if blocks >= MAX_NBLOCK_INDIRECT {
+            copy.db_indirect = new_blocks.next().unwrap() as u32;
+            let mut db_indirect = IndirectBlock {
+                entries: [0; BLK_NENTRY],
+            };
+            let count = (blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
+            for j in 0..count {
+                let begin = MAX_NBLOCK_INDIRECT + j * BLK_NENTRY;
+                let mut indirect = IndirectBlock {
+                    entries: [0; BLK_NENTRY],
+                };
+                for i in begin..blocks.min(begin + BLK_NENTRY) {
+                    indirect.entries[i - begin] = map(i)?;
+                }
+                db_indirect.entries[j] = new_blocks.next().unwrap() as u32;
+                self.fs.device.write_block(
+                    db_indirect.entries[j] as usize,
+                    0,
+                    indirect.as_buf(),
+                )?;
+            }
+            self.fs
+                .device
+                .write_block(copy.db_indirect as usize, 0, db_indirect.as_buf())?;
+        }
+        self.fs.device.write_block(id, 0, copy.as_buf())
+    }
     /// Only for Dir
     fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
         (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
@@ -199,8 +444,15 @@ impl INodeImpl {
     fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
         let size = self.disk_inode.read().size as usize;
         let dirent_count = size / DIRENT_SIZE;
-        self._resize(size + DIRENT_SIZE)?;
-        self.write_direntry(dirent_count, direntry)?;
+        let ret = self
+            ._resize(size + DIRENT_SIZE)
+            .and_then(|()| self.write_direntry(dirent_count, direntry));
+        if let Err(e) = ret {
+            // do not leave a half-written entry behind
+            self._resize(size)?;
+            return Err(e);
+        }
+        self.touch();
         Ok(())
     }
     /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
@@ -212,8 +464,90 @@ impl INodeImpl {
         let last_dirent = self.read_direntry(dirent_count - 1)?;
         self.write_direntry(id, &last_dirent)?;
         self._resize(size - DIRENT_SIZE)?;
+        self.touch();
+        Ok(())
+    }
+    /// Lock directory entries of `self` and `other` in the order of inode id,
+    /// so that two concurrent `move_` between them can not deadlock.
+    fn lock_dirs<'a>(
+        &'a self,
+        other: &'a INodeImpl,
+    ) -> (MutexGuard<'a, ()>, Option<MutexGuard<'a, ()>>) {
+        use core::cmp::Ordering;
+        match self.id.cmp(&other.id) {
+            Ordering::Equal => (self.dir_lock.lock(), None),
+            Ordering::Less => {
+                let first = self.dir_lock.lock();
+                (first, Some(other.dir_lock.lock()))
+            }
+            Ordering::Greater => {
+                let first = other.dir_lock.lock();
+                (self.dir_lock.lock(), Some(first))
+            }
+        }
+    }
+    /// Rename entry `old_name` to `new_name` in place, replacing the entry `new_name` if any.
+    /// The caller must hold `dir_lock`.
+    fn rename_atomic_same_dir(&self, old_name: &str, new_name: &str) -> vfs::Result<()> {
+        let (inode_id, entry_id) = self
+            .get_file_inode_and_entry_id(old_name)
+            .ok_or(FsError::EntryNotFound)?;
+        // both names link to the same INode, nothing to do
+        if self.get_file_inode_id(new_name) == Some(inode_id) {
+            return Ok(());
+        }
+        let type_ = self.fs.get_inode(inode_id)?.disk_inode.read().type_;
+        let victim = self.rename_victim(new_name, type_)?;
+
+        self.write_direntry(
+            entry_id,
+            &DiskEntry {
+                id: inode_id as u32,
+                name: Str256::from(new_name),
+            },
+        )?;
+        if let Some((victim, victim_entry_id)) = victim {
+            self.remove_direntry(victim_entry_id)?;
+            self.release_victim(&victim);
+        }
+        self.touch();
         Ok(())
     }
+    /// Find the entry `name` which moving an INode of `type_` here would replace.
+    /// As POSIX `rename`, a directory only replaces an empty directory,
+    /// and anything else only a non-directory.
+    fn rename_victim(
+        &self,
+        name: &str,
+        type_: FileType,
+    ) -> vfs::Result<Option<(Arc<INodeImpl>, usize)>> {
+        let (victim_id, entry_id) = match self.get_file_inode_and_entry_id(name) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let victim = self.fs.get_inode(victim_id)?;
+        let victim_type = victim.disk_inode.read().type_;
+        match (type_, victim_type) {
+            (FileType::Dir, FileType::Dir) => {
+                // only . and ..
+                if victim.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
+                    return Err(FsError::DirNotEmpty);
+                }
+            }
+            (FileType::Dir, _) => return Err(FsError::NotDir),
+            (_, FileType::Dir) => return Err(FsError::IsDir),
+            _ => {}
+        }
+        Ok(Some((victim, entry_id)))
+    }
+    /// Drop the links of `victim` replaced by a move, once its entry here is gone
+    fn release_victim(&self, victim: &INodeImpl) {
+        victim.nlinks_dec();
+        if victim.disk_inode.read().type_ == FileType::Dir {
+            victim.nlinks_dec(); //for .
+            self.nlinks_dec(); //for ..
+        }
+    }
     /// Resize content size, no matter what type it is.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
         if len > MAX_FILE_SIZE {
@@ -230,17 +564,20 @@ impl INodeImpl {
                 self.disk_inode.write().size = len as u32;
             }
             Ordering::Greater => {
+                // continue from the last block, or start near the INode
+                let goal = match old_blocks {
+                    0 => self.id,
+                    n => match self.get_disk_block_id(n as usize - 1)? {
+                        0 => self.id,
+                        last => last + 1,
+                    },
+                };
                 let mut disk_inode = self.disk_inode.write();
-                disk_inode.blocks = blocks;
-                // allocate indirect block if needed
-                if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
-                }
-                // allocate double indirect block if needed
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    if disk_inode.db_indirect == 0 {
-                        disk_inode.db_indirect = self.fs.alloc_block().expect("no space") as u32;
-                    }
+                let alloc_indirect =
+                    old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32;
+                let alloc_db_indirect =
+                    blocks >= MAX_NBLOCK_INDIRECT as u32 && disk_inode.db_indirect == 0;
+                let indirect_range = if blocks >= MAX_NBLOCK_INDIRECT as u32 {
                     let indirect_begin = {
                         if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
                             0
@@ -249,19 +586,36 @@ impl INodeImpl {
                         }
                     };
                     let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let indirect = self.fs.alloc_block().expect("no space") as u32;
-                        self.fs.device.write_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf(),
-                        )?;
-                    }
+                    indirect_begin..indirect_end
+                } else {
+                    0..0
+                };
+                // allocate all blocks at once, so the INode is left unchanged if out of space
+                let count = alloc_indirect as usize
+                    + alloc_db_indirect as usize
+                    + indirect_range.len()
+                    + (blocks - old_blocks) as usize;
+                let mut new_blocks = self.fs.alloc_blocks(count, goal)?.into_iter();
+                disk_inode.blocks = blocks;
+                // set indirect block if needed
+                if alloc_indirect {
+                    disk_inode.indirect = new_blocks.next().unwrap() as u32;
+                }
+                // set double indirect block if needed
+                if alloc_db_indirect {
+                    disk_inode.db_indirect = new_blocks.next().unwrap() as u32;
+                }
+                for i in indirect_range {
+                    let indirect = new_blocks.next().unwrap() as u32;
+                    self.fs.device.write_block(
+                        disk_inode.db_indirect as usize,
+                        ENTRY_SIZE * i,
+                        indirect.as_buf(),
+                    )?;
                 }
                 drop(disk_inode);
-                // allocate extra blocks
-                for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
+                // set extra blocks
+                for (i, disk_block_id) in (old_blocks..blocks).zip(new_blocks) {
                     self.set_disk_block_id(i as usize, disk_block_id)?;
                 }
                 // clean up
@@ -271,13 +625,27 @@ impl INodeImpl {
                 drop(disk_inode);
                 self._clean_at(old_size, len)?;
             }
+            Ordering::Less if len >= self.disk_inode.read().size as usize => {
+                // growing into blocks reserved by `reserve_entries`
+                let mut disk_inode = self.disk_inode.write();
+                let old_size = disk_inode.size as usize;
+                disk_inode.size = len as u32;
+                drop(disk_inode);
+                self._clean_at(old_size, len)?;
+            }
             Ordering::Less => {
                 // free extra blocks
+                let mut holes = 0;
                 for i in blocks..old_blocks {
                     let disk_block_id = self.get_disk_block_id(i as usize)?;
-                    self.fs.free_block(disk_block_id);
+                    if disk_block_id != 0 {
+                        self.fs.release_block(disk_block_id)?;
+                    } else {
+                        holes += 1;
+                    }
                 }
                 let mut disk_inode = self.disk_inode.write();
+                disk_inode.holes = disk_inode.holes.saturating_sub(holes);
                 // free indirect block if needed
                 if blocks < MAX_NBLOCK_DIRECT as u32
                     && disk_inode.blocks >= MAX_NBLOCK_DIRECT as u32
@@ -319,10 +687,11 @@ impl INodeImpl {
         Ok(())
     }
     // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
-    /// Read/Write content, no matter what type it is
-    fn _io_at<F>(&self, begin: usize, end: usize, mut f: F) -> vfs::Result<usize>
+    /// Read/Write content, no matter what type it is.
+    /// `f` is called with block 0 for holes, and with unshared blocks if `write`.
+    fn _io_at<F>(&self, begin: usize, end: usize, write: bool, mut f: F) -> vfs::Result<usize>
     where
-        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+        F: FnMut(&TxDevice, &BlockRange, usize) -> vfs::Result<()>,
     {
         let size = self.disk_inode.read().size as usize;
         let iter = BlockIter {
@@ -334,7 +703,10 @@ impl INodeImpl {
         // For each block
         let mut buf_offset = 0usize;
         for mut range in iter {
-            range.block = self.get_disk_block_id(range.block)?;
+            range.block = match write {
+                true => self.get_disk_block_id_mut(range.block)?,
+                false => self.get_disk_block_id(range.block)?,
+            };
             f(&self.fs.device, &range, buf_offset)?;
             buf_offset += range.len();
         }
@@ -342,37 +714,200 @@ impl INodeImpl {
     }
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
-                range.block,
-                range.begin,
-                &mut buf[offset..offset + range.len()],
-            )
-        })
+        self._io_at(
+            offset,
+            offset.saturating_add(buf.len()),
+            false,
+            |device, range, offset| {
+                let buf = &mut buf[offset..offset + range.len()];
+                match range.block {
+                    0 => {
+                        buf.fill(0);
+                        Ok(())
+                    }
+                    block => device.read_block(block, range.begin, buf),
+                }
+            },
+        )
     }
     /// Write content, no matter what type it is
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
+        self._fill_holes(offset, offset + buf.len())?;
+        self._io_at(offset, offset + buf.len(), true, |device, range, offset| {
             device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
         })
     }
     /// Clean content, no matter what type it is
     fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<usize> {
         static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
-        self._io_at(begin, end, |device, range, _| {
-            device.write_block(range.block, range.begin, &ZEROS[..range.len()])
+        self._io_at(begin, end, true, |device, range, _| match range.block {
+            0 => Ok(()),
+            block => device.write_block(block, range.begin, &ZEROS[..range.len()]),
         })
     }
-    fn nlinks_inc(&self) {
-        self.disk_inode.write().nlinks += 1;
+    /// Allocate zeroed blocks for holes in `begin..end`
+    fn _fill_holes(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
+        let size = self.disk_inode.read().size as usize;
+        let iter = BlockIter {
+            begin: size.min(begin),
+            end: size.min(end),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        for range in iter {
+            if self.get_disk_block_id(range.block)? != 0 {
+                continue;
+            }
+            let disk_block_id = self.fs.alloc_block(self.id).ok_or(FsError::NoDeviceSpace)?;
+            self.fs.device.write_block(disk_block_id, 0, &ZEROS)?;
+            self.set_disk_block_id(range.block, disk_block_id)?;
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.holes = disk_inode.holes.saturating_sub(1);
+        }
+        Ok(())
+    }
+    /// Free the blocks fully covered by `begin..end`, and zero the partial ones
+    fn _punch_hole(&self, begin: usize, end: usize) -> vfs::Result<()> {
+        let size = self.disk_inode.read().size as usize;
+        let end = size.min(end);
+        if begin >= end {
+            return Ok(());
+        }
+        let first_block = begin.div_ceil(BLKSIZE);
+        // the last block is fully covered if the hole reaches the end of file
+        let end_block = match end == size {
+            true => end.div_ceil(BLKSIZE),
+            false => end / BLKSIZE,
+        };
+        if first_block >= end_block {
+            self._clean_at(begin, end)?;
+            return Ok(());
+        }
+        self._clean_at(begin, first_block * BLKSIZE)?;
+        self._clean_at(end_block * BLKSIZE, end)?;
+        for i in first_block..end_block {
+            let disk_block_id = self.get_disk_block_id(i)?;
+            if disk_block_id != 0 {
+                self.set_disk_block_id(i, 0)?;
+                self.fs.release_block(disk_block_id)?;
+                self.disk_inode.write().holes += 1;
+            }
+        }
+        Ok(())
+    }
+    /// Fails with `TooManyLinks` instead of overflowing the on-disk count
+    fn nlinks_inc(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.nlinks = disk_inode
+            .nlinks
+            .checked_add(1)
+            .ok_or(FsError::TooManyLinks)?;
+        Ok(())
     }
     fn nlinks_dec(&self) {
         let mut disk_inode = self.disk_inode.write();
         assert!(disk_inode.nlinks > 0);
         disk_inode.nlinks -= 1;
     }
+    /// Stamp mtime and ctime after modifying content
+    fn touch(&self) {
+        let now = self.fs.time_provider.current_time();
+        let mut disk_inode = self.disk_inode.write();
+        // do not dirty the INode if the time does not change
+        if disk_inode.mtime != now || disk_inode.ctime != now {
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+        }
+    }
+
+    /// Free the content and the INode itself, once unlinked. Only the first call does.
+    fn release(&self) -> vfs::Result<()> {
+        if self.released.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self._resize(0)?;
+        self.disk_inode.write().sync();
+        self.fs
+            .device
+            .write_block(self.id, offset_of!(DiskINode, magic), 0u32.as_buf())?;
+        self.released.store(true, Ordering::SeqCst);
+        self.fs.free_block(self.id);
+        Ok(())
+    }
+
+    /// Write the INode to the device if dirty, into memory inside a transaction
+    fn write_back(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        // the block may belong to another INode now
+        if disk_inode.dirty() && !self.released.load(Ordering::SeqCst) {
+            self.fs
+                .device
+                .write_block(self.id, 0, disk_inode.as_buf())?;
+            disk_inode.sync();
+        }
+        Ok(())
+    }
+
+    /// Fail with `StaleFileHandle` once released, as the block may belong to another INode now
+    fn check_released(&self) -> vfs::Result<()> {
+        match self.released.load(Ordering::SeqCst) {
+            true => Err(FsError::StaleFileHandle),
+            false => Ok(()),
+        }
+    }
+
+    /// Undo creating this INode, before it is linked anywhere: free its content and itself.
+    /// Unlike `release`, its block is freed even if it can not be cleared on the device,
+    /// as nothing refers to it.
+    fn abandon(&self) {
+        if let Err(e) = self._resize(0) {
+            error!(
+                "failed to free the content of abandoned inode {}: {:?}",
+                self.id, e
+            );
+        }
+        self.disk_inode.write().sync();
+        if let Err(e) =
+            self.fs
+                .device
+                .write_block(self.id, offset_of!(DiskINode, magic), 0u32.as_buf())
+        {
+            warn!("failed to clear abandoned inode {}: {:?}", self.id, e);
+        }
+        self.released.store(true, Ordering::SeqCst);
+        self.fs.free_block(self.id);
+    }
+
+    /// Generation number of this INode, see `SimpleFileSystem::validate_handle`
+    pub fn generation(&self) -> u32 {
+        self.disk_inode.read().generation
+    }
+
+    /// Disk blocks of the content, merged into contiguous ranges in file order.
+    /// Holes are skipped.
+    pub fn extents(&self) -> vfs::Result<Vec<Range<BlockId>>> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        let mut extents: Vec<Range<BlockId>> = Vec::new();
+        for i in 0..blocks {
+            let disk_block_id = self.get_disk_block_id(i)?;
+            if disk_block_id == 0 {
+                continue;
+            }
+            match extents.last_mut() {
+                Some(last) if last.end == disk_block_id => last.end += 1,
+                _ => extents.push(disk_block_id..disk_block_id + 1),
+            }
+        }
+        Ok(extents)
+    }
 
     pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
+        self.link_child(name, other)
+    }
+
+    /// Link `child` as `name` in this directory, shared by `link` and `link_inodeimpl`
+    fn link_child(&self, name: &str, child: &INodeImpl) -> vfs::Result<()> {
+        validate_name(name, MAX_FNAME_LEN)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -380,25 +915,25 @@ impl INodeImpl {
         if info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
+        let _freeze = self.fs.begin_write()?;
+        let _dir_lock = self.dir_lock.lock();
         if self.get_file_inode_id(name).is_some() {
             return Err(FsError::EntryExist);
         }
-        let child = other;
         if !Arc::ptr_eq(&self.fs, &child.fs) {
             return Err(FsError::NotSameFs);
         }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
+        if child.file_type()? == vfs::FileType::Dir {
             return Err(FsError::IsDir);
         }
-        let entry = DiskEntry {
+        child.nlinks_inc()?;
+        if let Err(e) = self.append_direntry(&DiskEntry {
             id: child.id as u32,
             name: Str256::from(name),
-        };
-        let disk_inode = self.disk_inode.write();
-        let old_size = disk_inode.size as usize;
-        self._resize(old_size + BLKSIZE)?;
-        self._write_at(old_size, entry.as_buf()).unwrap();
-        child.nlinks_inc();
+        }) {
+            child.nlinks_dec();
+            return Err(e);
+        }
         Ok(())
     }
 }
@@ -408,7 +943,7 @@ impl vfs::INode for INodeImpl {
         match self.disk_inode.read().type_ {
             FileType::File => self._read_at(offset, buf),
             FileType::SymLink => self._read_at(offset, buf),
-            FileType::CharDevice => {
+            FileType::CharDevice | FileType::BlockDevice => {
                 let device_inodes = self.fs.device_inodes.read();
                 let device_inode = device_inodes.get(&self.device_inode_id);
                 match device_inode {
@@ -423,13 +958,17 @@ impl vfs::INode for INodeImpl {
         let DiskINode { type_, size, .. } = **self.disk_inode.read();
         match type_ {
             FileType::File | FileType::SymLink => {
-                let end_offset = offset + buf.len();
+                let _freeze = self.fs.begin_write()?;
+                self.check_released()?;
+                let end_offset = checked_end(offset, buf.len(), MAX_FILE_SIZE)?;
                 if (size as usize) < end_offset {
                     self._resize(end_offset)?;
                 }
-                self._write_at(offset, buf)
+                let len = self._write_at(offset, buf)?;
+                self.touch();
+                Ok(len)
             }
-            FileType::CharDevice => {
+            FileType::CharDevice | FileType::BlockDevice => {
                 let device_inodes = self.fs.device_inodes.write();
                 let device_inode = device_inodes.get(&self.device_inode_id);
                 match device_inode {
@@ -449,46 +988,26 @@ impl vfs::INode for INodeImpl {
     }
     /// the size returned here is logical size(entry num for directory), not the disk space used.
     fn metadata(&self) -> vfs::Result<vfs::Metadata> {
-        let disk_inode = self.disk_inode.read();
-        Ok(vfs::Metadata {
-            dev: 0,
-            inode: self.id,
-            size: match disk_inode.type_ {
-                FileType::File | FileType::SymLink => disk_inode.size as usize,
-                FileType::Dir => disk_inode.size as usize,
-                FileType::CharDevice => 0,
-                FileType::BlockDevice => 0,
-                _ => panic!("Unknown file type"),
-            },
-            mode: 0o777,
-            type_: vfs::FileType::from(disk_inode.type_),
-            blocks: disk_inode.blocks as usize,
-            atime: disk_inode.atime,
-            mtime: disk_inode.mtime,
-            ctime: disk_inode.ctime,
-            nlinks: disk_inode.nlinks as usize,
-            uid: 0,
-            gid: 0,
-            blk_size: BLKSIZE,
-            rdev: self.device_inode_id,
-        })
+        self.disk_inode.read().metadata(self.fs.dev, self.id)
+    }
+    fn file_type(&self) -> vfs::Result<vfs::FileType> {
+        vfs::FileType::try_from(self.disk_inode.read().type_)
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        let _freeze = self.fs.begin_write()?;
+        self.check_released()?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.atime = metadata.atime;
         disk_inode.mtime = metadata.mtime;
         disk_inode.ctime = metadata.ctime;
         Ok(())
     }
+    /// Fail with `Busy` while a transaction is open, as nothing would reach the device
     fn sync_all(&self) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        if disk_inode.dirty() {
-            self.fs
-                .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
-            disk_inode.sync();
+        if self.fs.device.in_transaction() {
+            return Err(FsError::Busy);
         }
-        Ok(())
+        self.write_back()
     }
     fn sync_data(&self) -> vfs::Result<()> {
         self.sync_all()
@@ -499,7 +1018,11 @@ impl vfs::INode for INodeImpl {
         {
             return Err(FsError::NotFile);
         }
-        self._resize(len)
+        let _freeze = self.fs.begin_write()?;
+        self.check_released()?;
+        self._resize(len)?;
+        self.touch();
+        Ok(())
     }
     fn create2(
         &self,
@@ -508,6 +1031,7 @@ impl vfs::INode for INodeImpl {
         _mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        validate_name(name, MAX_FNAME_LEN)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -517,34 +1041,51 @@ impl vfs::INode for INodeImpl {
         }
 
         // Ensure the name is not exist
+        let _freeze = self.fs.begin_write()?;
+        let _dir_lock = self.dir_lock.lock();
         if self.get_file_inode_id(name).is_some() {
             return Err(FsError::EntryExist);
         }
 
         // Create new INode
         let inode = match type_ {
-            vfs::FileType::File => self.fs.new_inode_file()?,
-            vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
+            vfs::FileType::File => self.fs.new_inode_file(self.id)?,
+            vfs::FileType::SymLink => self.fs.new_inode_symlink(self.id)?,
             vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
             vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
+            vfs::FileType::BlockDevice => self.fs.new_inode_blockdevice(data)?,
             _ => return Err(vfs::FsError::InvalidParam),
         };
+        if type_ == vfs::FileType::Dir {
+            self.nlinks_inc()?; //for ..
+        }
 
         // Write new entry
-        self.append_direntry(&DiskEntry {
+        if let Err(e) = self.append_direntry(&DiskEntry {
             id: inode.id as u32,
             name: Str256::from(name),
-        })?;
-        inode.nlinks_inc();
+        }) {
+            if type_ == vfs::FileType::Dir {
+                self.nlinks_dec();
+            }
+            inode.abandon();
+            return Err(e);
+        }
+        inode.nlinks_inc()?;
         if type_ == vfs::FileType::Dir {
-            inode.nlinks_inc(); //for .
-            self.nlinks_inc(); //for ..
+            inode.nlinks_inc()?; //for .
         }
 
         Ok(inode)
     }
-
-    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+    /// In a transaction, so the new INode, its content and the entry reach the device together
+    fn create_with_content(
+        &self,
+        name: &str,
+        _mode: u32,
+        data: &[u8],
+    ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        validate_name(name, MAX_FNAME_LEN)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -552,24 +1093,38 @@ impl vfs::INode for INodeImpl {
         if info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if self.get_file_inode_id(name).is_some() {
-            return Err(FsError::EntryExist);
-        }
+
+        self.fs.transaction(|| {
+            let _freeze = self.fs.begin_write()?;
+            let _dir_lock = self.dir_lock.lock();
+            if self.get_file_inode_id(name).is_some() {
+                return Err(FsError::EntryExist);
+            }
+
+            let inode = self.fs.new_inode_file(self.id)?;
+            if let Err(e) = inode
+                ._resize(data.len())
+                .and_then(|_| inode._write_at(0, data))
+                .and_then(|_| {
+                    self.append_direntry(&DiskEntry {
+                        id: inode.id as u32,
+                        name: Str256::from(name),
+                    })
+                })
+            {
+                inode.abandon();
+                return Err(e);
+            }
+            inode.nlinks_inc()?;
+            Ok(inode as Arc<dyn vfs::INode>)
+        })
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
         let child = other
             .downcast_ref::<INodeImpl>()
             .ok_or(FsError::NotSameFs)?;
-        if !Arc::ptr_eq(&self.fs, &child.fs) {
-            return Err(FsError::NotSameFs);
-        }
-        if child.metadata()?.type_ == vfs::FileType::Dir {
-            return Err(FsError::IsDir);
-        }
-        self.append_direntry(&DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        })?;
-        child.nlinks_inc();
-        Ok(())
+        self.link_child(name, child)
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
         let info = self.metadata()?;
@@ -586,10 +1141,13 @@ impl vfs::INode for INodeImpl {
             return Err(FsError::IsDir);
         }
 
+        // hold the lock until the entry is removed, or `entry_id` may be moved
+        let _freeze = self.fs.begin_write()?;
+        let _dir_lock = self.dir_lock.lock();
         let (inode_id, entry_id) = self
             .get_file_inode_and_entry_id(name)
             .ok_or(FsError::EntryNotFound)?;
-        let inode = self.fs.get_inode(inode_id);
+        let inode = self.fs.get_inode(inode_id)?;
 
         let type_ = inode.disk_inode.read().type_;
         if type_ == FileType::Dir {
@@ -608,6 +1166,7 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        validate_name(new_name, MAX_FNAME_LEN)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -621,6 +1180,9 @@ impl vfs::INode for INodeImpl {
         if old_name == ".." {
             return Err(FsError::IsDir);
         }
+        if new_name == "." || new_name == ".." {
+            return Err(FsError::EntryExist);
+        }
 
         let dest = target
             .downcast_ref::<INodeImpl>()
@@ -635,35 +1197,86 @@ impl vfs::INode for INodeImpl {
         if dest_info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if let Some((_, id)) = dest.get_file_inode_and_entry_id(new_name) {
-            dest.remove_direntry(id)?;
+        let _freeze = self.fs.begin_write()?;
+        let _dir_locks = self.lock_dirs(dest);
+        if info.inode == dest_info.inode {
+            return self.rename_atomic_same_dir(old_name, new_name);
         }
-
         let (inode_id, entry_id) = self
             .get_file_inode_and_entry_id(old_name)
             .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
-            // rename: in place modify name
-            self.write_direntry(
-                entry_id,
+        // both names link to the same INode, nothing to do
+        if dest.get_file_inode_id(new_name) == Some(inode_id) {
+            return Ok(());
+        }
+        let inode = self.fs.get_inode(inode_id)?;
+        let type_ = inode.disk_inode.read().type_;
+        let is_dir = type_ == FileType::Dir;
+        let victim = dest.rename_victim(new_name, type_)?;
+        if is_dir {
+            dest.nlinks_inc()?; //for .. of the moved dir
+        }
+        let entry = DiskEntry {
+            id: inode_id as u32,
+            name: Str256::from(new_name),
+        };
+        let written = match &victim {
+            Some((_, victim_entry_id)) => dest.write_direntry(*victim_entry_id, &entry),
+            None => dest.append_direntry(&entry),
+        };
+        if let Err(e) = written {
+            if is_dir {
+                dest.nlinks_dec();
+            }
+            return Err(e);
+        }
+        if let Err(e) = self.remove_direntry(entry_id) {
+            // linked from both directories, drop the new entry
+            let undone = match &victim {
+                Some((victim, victim_entry_id)) => {
+                    let entry = DiskEntry {
+                        id: victim.id as u32,
+                        name: Str256::from(new_name),
+                    };
+                    dest.write_direntry(*victim_entry_id, &entry)
+                }
+                None => {
+                    let last = dest.disk_inode.read().size as usize / DIRENT_SIZE - 1;
+                    dest.remove_direntry(last)
+                }
+            };
+            if let Err(undo_e) = undone {
+                // keep both names then, each counted as a link
+                error!(
+                    "failed to undo the entry {} of a move: {:?}",
+                    new_name, undo_e
+                );
+                if let Some((victim, _)) = &victim {
+                    dest.release_victim(victim);
+                }
+                if let Err(inc_e) = inode.nlinks_inc() {
+                    error!("failed to count the link {}: {:?}", new_name, inc_e);
+                }
+            }
+            if is_dir {
+                dest.nlinks_dec();
+            }
+            return Err(e);
+        }
+        if let Some((victim, _)) = victim {
+            dest.release_victim(&victim);
+        }
+
+        if is_dir {
+            // '..' of the moved dir refers to the new parent
+            inode.write_direntry(
+                1,
                 &DiskEntry {
-                    id: inode_id as u32,
-                    name: Str256::from(new_name),
+                    id: dest.id as u32,
+                    name: Str256::from(".."),
                 },
             )?;
-        } else {
-            // move
-            dest.append_direntry(&DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            })?;
-            self.remove_direntry(entry_id)?;
-
-            let inode = self.fs.get_inode(inode_id);
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
-                self.nlinks_dec();
-                dest.nlinks_inc();
-            }
+            self.nlinks_dec();
         }
         Ok(())
     }
@@ -673,7 +1286,7 @@ impl vfs::INode for INodeImpl {
             return Err(FsError::NotDir);
         }
         let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
-        Ok(self.fs.get_inode(inode_id))
+        Ok(self.fs.get_inode(inode_id)?)
     }
     fn get_entry(&self, id: usize) -> vfs::Result<String> {
         if self.disk_inode.read().type_ != FileType::Dir {
@@ -695,14 +1308,56 @@ impl vfs::INode for INodeImpl {
         };
         let entry = self.read_direntry(id)?;
         Ok((
-            self.fs.get_inode(entry.id as usize).metadata()?,
+            self.fs.get_inode(entry.id as usize)?.metadata()?,
             String::from(entry.name.as_ref()),
         ))
     }
 
-    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
-        if self.metadata().unwrap().type_ != vfs::FileType::CharDevice {
-            return Err(FsError::IOCTLError);
+    fn iterate_dir(&self, f: &mut dyn FnMut(usize, &str) -> vfs::Result<bool>) -> vfs::Result<()> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        let mut entry: DiskEntry = unsafe { uninit_memory() };
+        for id in 0..count {
+            self._read_at(DIRENT_SIZE * id, entry.as_buf_mut())?;
+            if !f(id, entry.name.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_dir_plus(&self, id: usize) -> vfs::Result<(usize, String)> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
+            return Err(FsError::EntryNotFound);
+        };
+        let entry = self.read_direntry(id)?;
+        Ok((entry.id as usize, String::from(entry.name.as_ref())))
+    }
+
+    fn snapshot_entries(&self) -> vfs::Result<Vec<(String, usize)>> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        // entries are only modified with it held
+        let _dir_lock = self.dir_lock.lock();
+        let count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        (0..count)
+            .map(|id| {
+                let entry = self.read_direntry(id)?;
+                Ok((String::from(entry.name.as_ref()), entry.id as usize))
+            })
+            .collect()
+    }
+
+    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        if type_ != FileType::CharDevice && type_ != FileType::BlockDevice {
+            return Err(FsError::IOCTLError);
         }
         let device_inodes = self.fs.device_inodes.read();
         let device_inode = device_inodes.get(&self.device_inode_id);
@@ -717,6 +1372,76 @@ impl vfs::INode for INodeImpl {
     fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
         Err(FsError::NotSupported)
     }
+    /// Pass the hint to the device for each disk block covered
+    fn fadvise(&self, offset: usize, len: usize, advice: vfs::Advice) -> vfs::Result<()> {
+        let end = match len {
+            0 => usize::MAX,
+            len => offset.saturating_add(len),
+        };
+        self._io_at(offset, end, false, |device, range, _| {
+            if range.block != 0 {
+                device.fadvise(range.origin_begin(), range.len(), advice)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+    /// Ask the device to load the covered disk blocks
+    fn readahead(&self, offset: usize, len: usize) -> vfs::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.fadvise(offset, len, vfs::Advice::WillNeed)
+    }
+    fn punch_hole(&self, offset: usize, len: usize) -> vfs::Result<()> {
+        if self.disk_inode.read().type_ != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        let _freeze = self.fs.begin_write()?;
+        self._punch_hole(offset, offset.saturating_add(len))?;
+        self.touch();
+        Ok(())
+    }
+    /// Allocate the blocks for `count` more entries beyond the size,
+    /// kept as the directory grows until it shrinks again
+    fn reserve_entries(&self, count: usize) -> vfs::Result<()> {
+        if self.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let _freeze = self.fs.begin_write()?;
+        let _dir_lock = self.dir_lock.lock();
+        let DiskINode { size, blocks, .. } = **self.disk_inode.read();
+        let capacity = count
+            .saturating_mul(DIRENT_SIZE)
+            .saturating_add(size as usize);
+        if capacity.div_ceil(BLKSIZE) <= blocks as usize {
+            return Ok(());
+        }
+        self._resize(capacity)?;
+        self.disk_inode.write().size = size;
+        Ok(())
+    }
+    fn open(&self) -> vfs::Result<()> {
+        self.open_count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+    /// Free the INode on the last close if unlinked, instead of waiting for the last reference
+    fn close(&self) -> vfs::Result<()> {
+        let count = self
+            .open_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .map_err(|_| FsError::InvalidParam)?;
+        if count == 1 && self.disk_inode.read().nlinks == 0 {
+            let _freeze = self.fs.begin_write()?;
+            self.release()?;
+        }
+        Ok(())
+    }
+    fn open_count(&self) -> usize {
+        self.open_count.load(Ordering::SeqCst)
+    }
     fn fs(&self) -> Arc<dyn vfs::FileSystem> {
         self.fs.clone()
     }
@@ -726,14 +1451,15 @@ impl vfs::INode for INodeImpl {
 }
 
 impl Drop for INodeImpl {
-    /// Auto sync when drop
+    /// Auto sync when drop. Errors are only logged, as panicking here may abort.
     fn drop(&mut self) {
-        self.sync_all()
-            .expect("Failed to sync when dropping the SimpleFileSystem Inode");
+        if let Err(e) = self.write_back() {
+            error!("failed to sync when dropping inode {}: {:?}", self.id, e);
+        }
         if self.disk_inode.read().nlinks == 0 {
-            self._resize(0).unwrap();
-            self.disk_inode.write().sync();
-            self.fs.free_block(self.id);
+            if let Err(e) = self.release() {
+                error!("failed to free the unlinked inode {}: {:?}", self.id, e);
+            }
         }
     }
 }
@@ -751,20 +1477,98 @@ pub struct SimpleFileSystem {
     free_map: RwLock<Dirty<BitVec<u8, Lsb0>>>,
     /// inode list
     inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
+    /// root INode, `BLKN_ROOT` unless mounted from a snapshot
+    root: INodeId,
+    /// Serialize updates of the reference counts of blocks
+    refcount_lock: Mutex<()>,
     /// device
-    device: Arc<dyn Device>,
+    device: TxDevice,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SimpleFileSystem>,
     /// device inode
     device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>,
+    /// Shared by operations modifying INodes,
+    /// held exclusively by `defragment` and `remount`
+    freeze: RwLock<()>,
+    /// Mounted read-only, modifications fail with `ReadOnlyFs`
+    read_only: AtomicBool,
+    /// Time provider, stamping times of INodes
+    time_provider: &'static dyn TimeProvider,
+    /// Device id in metadata of INodes
+    dev: usize,
+    /// Counts of operations, see `stats`
+    stats: Arc<StatCounters>,
+    /// Bumped whenever the count of free blocks changes
+    info_epoch: AtomicUsize,
+    /// `FsInfo` with the `info_epoch` it was computed at, returned while still current
+    info_cache: RwLock<(usize, vfs::FsInfo)>,
+}
+
+/// Options of `SimpleFileSystem::open_with_options`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Check that entries 0 and 1 of every directory are `.` and `..` to the right INodes,
+    /// rewriting them if not. Ignored on read-only devices.
+    pub repair_dots: bool,
+}
+
+/// What a block is used for, see `SimpleFileSystem::block_usage_map`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum BlockUsage {
+    Free,
+    SuperBlock,
+    FreeMap,
+    INode,
+    /// content of files, or indirect blocks
+    Data,
 }
 
 impl SimpleFileSystem {
-    /// Load SFS from device
+    /// Load SFS from device, leaving times of modified INodes at the epoch
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        Self::open_with_time_provider(device, &ZeroTimeProvider)
+    }
+    /// Load SFS from device, stamping times of INodes from `time_provider`
+    pub fn open_with_time_provider(
+        device: Arc<dyn Device>,
+        time_provider: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::open_with_options(device, time_provider, OpenOptions::default())
+    }
+    /// Load SFS from device with extra checks in `options`
+    pub fn open_with_options(
+        device: Arc<dyn Device>,
+        time_provider: &'static dyn TimeProvider,
+        options: OpenOptions,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::load(device, time_provider, options, BLKN_ROOT)
+    }
+    /// Mount the snapshot with root INode `id` from device, read-only, see `snapshot`
+    pub fn open_snapshot(device: Arc<dyn Device>, id: INodeId) -> vfs::Result<Arc<Self>> {
+        let sfs = Self::load(device, &ZeroTimeProvider, OpenOptions::default(), id)?;
+        if !sfs.snapshots()?.contains(&id) {
+            return Err(FsError::EntryNotFound);
+        }
+        Ok(sfs)
+    }
+    /// Load SFS with root INode `root` from device
+    fn load(
+        device: Arc<dyn Device>,
+        time_provider: &'static dyn TimeProvider,
+        options: OpenOptions,
+        root: INodeId,
+    ) -> vfs::Result<Arc<Self>> {
+        // mount read-only if the device cannot be written, or from a snapshot
+        let read_only = device.is_read_only() || root != BLKN_ROOT;
+        let mut super_block = Dirty::new(device.load_struct::<SuperBlock>(BLKN_SUPER)?);
         if !super_block.check() {
-            return Err(FsError::WrongFs);
+            let backup = Self::load_backup_super_block(&device).ok_or(FsError::WrongFs)?;
+            warn!("superblock is corrupted, using the backup");
+            // rewrite the primary one on sync if possible
+            super_block = match read_only {
+                true => Dirty::new(backup),
+                false => Dirty::new_dirty(backup),
+            };
         }
         let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
         for i in 0..super_block.freemap_blocks as usize {
@@ -775,18 +1579,324 @@ impl SimpleFileSystem {
             )?;
         }
 
-        Ok(SimpleFileSystem {
-            super_block: RwLock::new(Dirty::new(super_block)),
+        let stats = Arc::new(StatCounters::default());
+        let sfs = SimpleFileSystem {
+            super_block: RwLock::new(super_block),
             free_map: RwLock::new(Dirty::new(BitVec::from_vec(freemap_disk))),
             inodes: RwLock::new(BTreeMap::new()),
-            device,
+            root,
+            refcount_lock: Mutex::new(()),
+            device: TxDevice::new(device, stats.clone()),
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            freeze: RwLock::new(()),
+            read_only: AtomicBool::new(read_only),
+            time_provider,
+            dev: vfs::new_dev_id(),
+            stats,
+            info_epoch: AtomicUsize::new(1),
+            info_cache: RwLock::new((0, vfs::FsInfo::default())),
+        }
+        .wrap();
+        if options.repair_dots && !read_only {
+            sfs.repair_dots()?;
+        }
+        Ok(sfs)
+    }
+    /// Walk all directories from the root, rewriting wrong `.` and `..` entries
+    fn repair_dots(&self) -> vfs::Result<()> {
+        let mut visited = BTreeSet::new();
+        // (directory, its parent)
+        let mut dirs = vec![(BLKN_ROOT, BLKN_ROOT)];
+        while let Some((id, parent)) = dirs.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let dir = self.get_inode(id)?;
+            let count = dir.disk_inode.read().size as usize / DIRENT_SIZE;
+            if count < 2 {
+                warn!("directory {} has no room for '.' and '..'", id);
+                continue;
+            }
+            for (i, name, target) in [(0, ".", id), (1, "..", parent)] {
+                let entry = dir.read_direntry(i)?;
+                // compare raw bytes, as a corrupted name may be invalid UTF-8
+                let expected = DiskEntry {
+                    id: target as u32,
+                    name: Str256::from(name),
+                };
+                if entry.id != expected.id
+                    || entry.name.0[..=name.len()] != expected.name.0[..=name.len()]
+                {
+                    warn!(
+                        "repair '{}' of directory {}, it was to {}",
+                        name, id, entry.id
+                    );
+                    dir.write_direntry(i, &expected)?;
+                }
+            }
+            for i in 2..count {
+                let child = dir.read_direntry(i)?.id as INodeId;
+                if self.get_inode(child)?.disk_inode.read().type_ == FileType::Dir {
+                    dirs.push((child, id));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Create a read-only snapshot of the current content on the device, and mount it.
+    ///
+    /// The INodes and directories are copied, while the content of files and symlinks is
+    /// shared by counting the extra references to each block. Before this SFS writes
+    /// a shared block, it copies the block to a new one, leaving the old one to the snapshots.
+    /// So a snapshot takes space and time in proportion to the INodes only, and writes of
+    /// this SFS never fail for it, unless the device is full.
+    ///
+    /// Snapshots survive a restart: `snapshots` lists their root INodes,
+    /// which `open_snapshot` mounts, until `delete_snapshot`.
+    pub fn snapshot(&self) -> vfs::Result<Arc<Self>> {
+        let id = {
+            // block modifications, so the tree is copied as a whole
+            let _freeze = self.freeze.write();
+            if self.read_only.load(Ordering::SeqCst) {
+                return Err(FsError::ReadOnlyFs);
+            }
+            // the snapshot is mounted from the synced device below
+            if self.device.in_transaction() {
+                return Err(FsError::Busy);
+            }
+            let id = self.copy_tree()?;
+            self.sync()?;
+            id
+        };
+        Self::open_snapshot(self.device.inner.clone(), id)
+    }
+    /// Root INodes of the snapshots on the device, see `snapshot`
+    pub fn snapshots(&self) -> vfs::Result<Vec<INodeId>> {
+        let id = self.super_block.read().snapshots as INodeId;
+        if id == 0 {
+            return Ok(Vec::new());
+        }
+        let list = self.get_inode(id)?;
+        let count = list.disk_inode.read().size as usize / ENTRY_SIZE;
+        let mut snapshots = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut root: u32 = 0;
+            list._read_at(i * ENTRY_SIZE, root.as_buf_mut())?;
+            snapshots.push(root as INodeId);
+        }
+        Ok(snapshots)
+    }
+    /// Delete the snapshot with root INode `id`, freeing the blocks no longer shared.
+    /// It must not be mounted.
+    pub fn delete_snapshot(&self, id: INodeId) -> vfs::Result<()> {
+        let _freeze = self.freeze.write();
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(FsError::ReadOnlyFs);
+        }
+        let mut snapshots = self.snapshots()?;
+        let index = snapshots
+            .iter()
+            .position(|&root| root == id)
+            .ok_or(FsError::EntryNotFound)?;
+        let inodes = self.collect_tree(id)?;
+        // forget the snapshot first, so a failure below leaks blocks instead of breaking it
+        snapshots.remove(index);
+        self.set_snapshots(&snapshots)?;
+        // the ones left by an error are released when dropped
+        for inode in &inodes {
+            inode.disk_inode.write().nlinks = 0;
+        }
+        for inode in &inodes {
+            inode.release()?;
+        }
+        Ok(())
+    }
+    /// Record `snapshots` as the root INodes of snapshots
+    fn set_snapshots(&self, snapshots: &[INodeId]) -> vfs::Result<()> {
+        let id = self.super_block.read().snapshots as INodeId;
+        let list = match id {
+            0 => {
+                let list = self.new_hidden_file()?;
+                self.super_block.write().snapshots = list.id as u32;
+                list
+            }
+            id => self.get_inode(id)?,
+        };
+        list._resize(snapshots.len() * ENTRY_SIZE)?;
+        for (i, &root) in snapshots.iter().enumerate() {
+            list._write_at(i * ENTRY_SIZE, (root as u32).as_buf())?;
+        }
+        Ok(())
+    }
+    /// Create a file referred to by the superblock instead of a directory entry.
+    /// Its link count is 1, so it is never released.
+    fn new_hidden_file(&self) -> vfs::Result<Arc<INodeImpl>> {
+        let inode = self.new_inode_file(BLKN_ROOT)?;
+        inode.nlinks_inc()?;
+        inode.write_back()?;
+        Ok(inode)
+    }
+    /// All INodes in the tree of directory `root`, each once
+    fn collect_tree(&self, root: INodeId) -> vfs::Result<Vec<Arc<INodeImpl>>> {
+        let mut inodes = vec![self.get_inode(root)?];
+        let mut ids = BTreeSet::from([root]);
+        let mut i = 0;
+        while let Some(inode) = inodes.get(i).cloned() {
+            i += 1;
+            let disk_inode = inode.disk_inode.read();
+            if disk_inode.type_ != FileType::Dir {
+                continue;
+            }
+            let count = disk_inode.size as usize / DIRENT_SIZE;
+            drop(disk_inode);
+            // skip '.' and '..'
+            for j in 2..count {
+                let id = inode.read_direntry(j)?.id as INodeId;
+                if ids.insert(id) {
+                    inodes.push(self.get_inode(id)?);
+                }
+            }
+        }
+        Ok(inodes)
+    }
+    /// Copy the tree from `BLKN_ROOT` for a snapshot and record it in `snapshots`.
+    /// Content blocks of directories are copied too, with the entries to the copies.
+    /// Return the root of the copy.
+    fn copy_tree(&self) -> vfs::Result<INodeId> {
+        let refcounts = match self.super_block.read().refcounts as INodeId {
+            0 => None,
+            id => Some(self.get_inode(id)?),
+        };
+        let refcounts = match refcounts {
+            Some(refcounts) => refcounts,
+            None => {
+                let refcounts = self.new_hidden_file()?;
+                self.super_block.write().refcounts = refcounts.id as u32;
+                refcounts
+            }
+        };
+        // cover all blocks, so counting references does not run out of space
+        let len = self.super_block.read().blocks as usize * ENTRY_SIZE;
+        if (refcounts.disk_inode.read().size as usize) < len {
+            refcounts._resize(len)?;
+        }
+
+        // (INode, number of content blocks to copy)
+        let mut inodes = Vec::new();
+        for inode in self.collect_tree(BLKN_ROOT)? {
+            let DiskINode { type_, blocks, .. } = **inode.disk_inode.read();
+            let mut copied = 0;
+            if type_ == FileType::Dir {
+                for i in 0..blocks as usize {
+                    copied += (inode.get_disk_block_id(i)? != 0) as usize;
+                }
+            }
+            inodes.push((inode, copied));
+        }
+        // allocate all blocks at once, so nothing is changed if out of space
+        let count = inodes
+            .iter()
+            .map(|(inode, copied)| {
+                1 + copied + indirect_blocks(inode.disk_inode.read().blocks as usize)
+            })
+            .sum();
+        let new_blocks = self.alloc_blocks(count, BLKN_ROOT)?;
+        let ids: BTreeMap<INodeId, INodeId> = inodes
+            .iter()
+            .map(|(inode, _)| inode.id)
+            .zip(new_blocks.iter().copied())
+            .collect();
+        let root = ids[&BLKN_ROOT];
+
+        let refcount_lock = self.refcount_lock.lock();
+        let mut shared = Vec::new();
+        let mut rest = new_blocks[inodes.len()..].iter().copied();
+        let result = inodes.iter().try_for_each(|(inode, copied)| {
+            let id = ids[&inode.id];
+            if inode.disk_inode.read().type_ != FileType::Dir {
+                return inode.copy_to(id, &mut rest, |_, block_id| {
+                    let refs = Self::extra_refs(&refcounts, block_id)?
+                        .checked_add(1)
+                        .ok_or(FsError::TooManyLinks)?;
+                    Self::set_extra_refs(&refcounts, block_id, refs)?;
+                    shared.push(block_id);
+                    Ok(block_id)
+                });
+            }
+            let size = inode.disk_inode.read().size as usize;
+            let mut content = vec![0u8; size];
+            inode._read_at(0, &mut content)?;
+            for chunk in content.chunks_exact_mut(DIRENT_SIZE) {
+                let mut entry: DiskEntry = unsafe { uninit_memory() };
+                entry.as_buf_mut().copy_from_slice(chunk);
+                entry.id = ids[&(entry.id as INodeId)] as u32;
+                chunk.copy_from_slice(entry.as_buf());
+            }
+            let blocks = inode.disk_inode.read().blocks as usize;
+            content.resize(blocks * BLKSIZE, 0);
+            let mut dir_blocks = rest.by_ref().take(*copied).collect::<Vec<_>>().into_iter();
+            inode.copy_to(id, &mut rest, |i, _| {
+                let block_id = dir_blocks.next().unwrap();
+                self.device
+                    .write_block(block_id, 0, &content[i * BLKSIZE..(i + 1) * BLKSIZE])?;
+                Ok(block_id)
+            })
+        });
+        drop(refcount_lock);
+        let result = result.and_then(|()| {
+            let mut snapshots = self.snapshots()?;
+            snapshots.push(root);
+            self.set_snapshots(&snapshots)
+        });
+        if let Err(e) = result {
+            let _refcount_lock = self.refcount_lock.lock();
+            for block_id in shared {
+                if let Err(e) = self.unref_block(&refcounts, block_id) {
+                    warn!("failed to undo sharing block {}: {:?}", block_id, e);
+                }
+            }
+            for block_id in new_blocks {
+                self.free_block(block_id);
+            }
+            return Err(e);
+        }
+        Ok(root)
+    }
+    /// Sync and drop this reference, returning the error which dropping would only log
+    pub fn close(self: Arc<Self>) -> vfs::Result<()> {
+        self.sync()
+    }
+    /// Load the backup superblock from the last block of device.
+    /// An image file may end right after it, so the last block can be partial.
+    fn load_backup_super_block(device: &Arc<dyn Device>) -> Option<SuperBlock> {
+        let id = device.size()?.checked_sub(1)? / BLKSIZE;
+        if id <= BLKN_FREEMAP {
+            return None;
+        }
+        // images created before the snapshot fields may end right after `crc`
+        let mut super_block: SuperBlock = unsafe { core::mem::zeroed() };
+        let len = device
+            .read_at(id * BLKSIZE, super_block.as_buf_mut())
+            .ok()?;
+        if len < offset_of!(SuperBlock, refcounts) {
+            return None;
         }
-        .wrap())
+        if !super_block.check() || super_block.backup as usize != id {
+            return None;
+        }
+        Some(super_block)
     }
-    /// Create a new SFS on blank disk
+    /// Create a new SFS on blank disk, leaving times of INodes at the epoch
     pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+        Self::create_with_time_provider(device, space, &ZeroTimeProvider)
+    }
+    /// Create a new SFS on blank disk, stamping times of INodes from `time_provider`
+    pub fn create_with_time_provider(
+        device: Arc<dyn Device>,
+        space: usize,
+        time_provider: &'static dyn TimeProvider,
+    ) -> vfs::Result<Arc<Self>> {
         let blocks = (space + BLKSIZE - 1) / BLKSIZE;
         let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
         assert!(blocks >= 16, "space too small");
@@ -794,34 +1904,58 @@ impl SimpleFileSystem {
         let super_block = SuperBlock {
             magic: MAGIC,
             blocks: blocks as u32,
-            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks) as u32,
+            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks - 1) as u32,
             info: Str32::from(DEFAULT_INFO),
             freemap_blocks: freemap_blocks as u32,
+            backup: (blocks - 1) as u32,
+            crc: 0,
+            refcounts: 0,
+            snapshots: 0,
         };
         let free_map = {
             let mut bitset = BitVec::with_capacity(freemap_blocks * BLKBITS);
             bitset.extend(core::iter::repeat(false).take(freemap_blocks * BLKBITS));
-            for i in (BLKN_FREEMAP + freemap_blocks)..blocks {
+            // the last block is kept for the backup superblock
+            for i in (BLKN_FREEMAP + freemap_blocks)..blocks - 1 {
                 bitset.set(i, true);
             }
             bitset
         };
 
+        let stats = Arc::new(StatCounters::default());
         let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
             free_map: RwLock::new(Dirty::new_dirty(free_map)),
             inodes: RwLock::new(BTreeMap::new()),
-            device,
+            root: BLKN_ROOT,
+            refcount_lock: Mutex::new(()),
+            device: TxDevice::new(device, stats.clone()),
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            freeze: RwLock::new(()),
+            read_only: AtomicBool::new(false),
+            time_provider,
+            dev: vfs::new_dev_id(),
+            stats,
+            info_epoch: AtomicUsize::new(1),
+            info_cache: RwLock::new((0, vfs::FsInfo::default())),
         }
         .wrap();
 
         // Init root INode
-        let root = sfs._new_inode(BLKN_ROOT, Dirty::new_dirty(DiskINode::new_dir()));
+        let now = time_provider.current_time();
+        let root = sfs._new_inode(
+            BLKN_ROOT,
+            Dirty::new_dirty(DiskINode {
+                atime: now,
+                mtime: now,
+                ctime: now,
+                ..DiskINode::new_dir()
+            }),
+        );
         root.init_direntry(BLKN_ROOT)?;
-        root.nlinks_inc(); //for .
-        root.nlinks_inc(); //for ..(root's parent is itself)
+        root.nlinks_inc()?; //for .
+        root.nlinks_inc()?; //for ..(root's parent is itself)
         root.sync_all()?;
 
         Ok(sfs)
@@ -840,10 +1974,11 @@ impl SimpleFileSystem {
         unsafe { Arc::from_raw(ptr) }
     }
 
-    /// Allocate a block, return block id
-    fn alloc_block(&self) -> Option<usize> {
+    /// Allocate the free block nearest to `goal`, return block id.
+    /// Related blocks are kept together by passing e.g. the parent directory as `goal`.
+    fn alloc_block(&self, goal: BlockId) -> Option<usize> {
         let mut free_map = self.free_map.write();
-        let id = free_map.alloc();
+        let id = free_map.alloc(goal);
         if let Some(block_id) = id {
             let mut super_block = self.super_block.write();
             if super_block.unused_blocks == 0 {
@@ -851,22 +1986,236 @@ impl SimpleFileSystem {
                 return None;
             }
             super_block.unused_blocks -= 1; // will not underflow
+            self.info_changed();
+            self.stats.blocks_allocated.fetch_add(1, Ordering::Relaxed);
             trace!("alloc block {:#x}", block_id);
         } else {
             let super_block = self.super_block.read();
-            panic!("{:?}", super_block)
+            if super_block.unused_blocks != 0 {
+                panic!("{:?}", super_block)
+            }
         }
         id
     }
+    /// Allocate `count` blocks from `goal` on, or none of them if out of space
+    fn alloc_blocks(&self, count: usize, goal: BlockId) -> vfs::Result<Vec<BlockId>> {
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let goal = blocks.last().map_or(goal, |&block_id| block_id + 1);
+            match self.alloc_block(goal) {
+                Some(block_id) => blocks.push(block_id),
+                None => {
+                    for block_id in blocks {
+                        self.free_block(block_id);
+                    }
+                    return Err(FsError::NoDeviceSpace);
+                }
+            }
+        }
+        Ok(blocks)
+    }
+    /// Allocate `count` contiguous blocks, return the first block id
+    fn alloc_contiguous_blocks(&self, count: usize) -> Option<usize> {
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        if (super_block.unused_blocks as usize) < count {
+            return None;
+        }
+        let mut begin = 0;
+        for i in 0..super_block.blocks as usize {
+            if !free_map[i] {
+                begin = i + 1;
+            } else if i + 1 - begin == count {
+                for id in begin..=i {
+                    free_map.set(id, false);
+                }
+                super_block.unused_blocks -= count as u32;
+                self.info_changed();
+                self.stats
+                    .blocks_allocated
+                    .fetch_add(count, Ordering::Relaxed);
+                trace!("alloc blocks {:#x}..{:#x}", begin, i + 1);
+                return Some(begin);
+            }
+        }
+        None
+    }
+    /// Number of references to block `id` besides the first one, counted in `refcounts`
+    fn extra_refs(refcounts: &INodeImpl, id: BlockId) -> vfs::Result<u32> {
+        let mut refs: u32 = 0;
+        refcounts._read_at(id * ENTRY_SIZE, refs.as_buf_mut())?;
+        Ok(refs)
+    }
+    fn set_extra_refs(refcounts: &INodeImpl, id: BlockId, refs: u32) -> vfs::Result<()> {
+        let end = (id + 1) * ENTRY_SIZE;
+        if (refcounts.disk_inode.read().size as usize) < end {
+            refcounts._resize(end)?;
+        }
+        refcounts._write_at(id * ENTRY_SIZE, refs.as_buf())?;
+        Ok(())
+    }
+    /// Drop a reference to block `id`, freeing it if it was the last one.
+    /// Called with `refcount_lock` held.
+    fn unref_block(&self, refcounts: &INodeImpl, id: BlockId) -> vfs::Result<()> {
+        match Self::extra_refs(refcounts, id)? {
+            0 => self.free_block(id),
+            refs => Self::set_extra_refs(refcounts, id, refs - 1)?,
+        }
+        Ok(())
+    }
+    /// Free a content block, unless a snapshot still shares it
+    fn release_block(&self, block_id: usize) -> vfs::Result<()> {
+        let refcounts = self.super_block.read().refcounts as INodeId;
+        if refcounts == 0 {
+            self.free_block(block_id);
+            return Ok(());
+        }
+        let refcounts = self.get_inode(refcounts)?;
+        let _refcount_lock = self.refcount_lock.lock();
+        self.unref_block(&refcounts, block_id)
+    }
     /// Free a block
     fn free_block(&self, block_id: usize) {
         let mut free_map = self.free_map.write();
         assert!(!free_map[block_id]);
         free_map.set(block_id, true);
-        self.super_block.write().unused_blocks += 1;
+        let mut super_block = self.super_block.write();
+        super_block.unused_blocks += 1;
+        self.info_changed();
+        drop(super_block);
+        self.stats.blocks_freed.fetch_add(1, Ordering::Relaxed);
         trace!("free block {:#x}", block_id);
     }
 
+    /// Invalidate the cached `FsInfo`, called with the superblock locked for writing
+    fn info_changed(&self) {
+        self.info_epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Start modifying INodes.
+    /// Return a guard blocking `defragment` and `remount`, or `ReadOnlyFs`.
+    fn begin_write(&self) -> vfs::Result<RwLockReadGuard<'_, ()>> {
+        let freeze = self.freeze.read();
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(FsError::ReadOnlyFs);
+        }
+        Ok(freeze)
+    }
+
+    /// Grow the fs in place to `new_total_blocks`, e.g. after its device was enlarged,
+    /// then sync. Modifications of INodes are blocked until it returns.
+    ///
+    /// The freemap must stay contiguous after the superblock, so if it needs more blocks,
+    /// the blocks right after it must be free, or `NoDeviceSpace` is returned.
+    /// Shrinking is rejected with `InvalidParam`.
+    pub fn grow(&self, new_total_blocks: usize) -> vfs::Result<()> {
+        let _freeze = self.freeze.write();
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(FsError::ReadOnlyFs);
+        }
+        if new_total_blocks > u32::MAX as usize {
+            return Err(FsError::InvalidParam);
+        }
+        if let Some(size) = self.device.size() {
+            if size < new_total_blocks * BLKSIZE {
+                return Err(FsError::NoDeviceSpace);
+            }
+        }
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        let blocks = super_block.blocks as usize;
+        let backup = super_block.backup as usize;
+        if new_total_blocks < blocks {
+            return Err(FsError::InvalidParam);
+        }
+        if new_total_blocks == blocks {
+            return Ok(());
+        }
+        let freemap_blocks = super_block.freemap_blocks as usize;
+        let new_freemap_blocks = new_total_blocks.div_ceil(BLKBITS);
+        let new_freemap = BLKN_FREEMAP + freemap_blocks..BLKN_FREEMAP + new_freemap_blocks;
+        // the old backup is given up below, new blocks are free
+        if new_freemap
+            .clone()
+            .any(|id| id < blocks && id != backup && !free_map[id])
+        {
+            return Err(FsError::NoDeviceSpace);
+        }
+
+        let mut unused_blocks = super_block.unused_blocks as usize;
+        free_map.resize(new_freemap_blocks * BLKBITS, false);
+        for id in blocks..new_total_blocks {
+            free_map.set(id, true);
+        }
+        unused_blocks += new_total_blocks - blocks;
+        if backup != 0 {
+            free_map.set(backup, true);
+            free_map.set(new_total_blocks - 1, false);
+            super_block.backup = (new_total_blocks - 1) as u32;
+        }
+        for id in new_freemap {
+            free_map.set(id, false);
+            unused_blocks -= 1;
+        }
+        super_block.blocks = new_total_blocks as u32;
+        super_block.freemap_blocks = new_freemap_blocks as u32;
+        super_block.unused_blocks = unused_blocks as u32;
+        self.info_changed();
+        drop(super_block);
+        drop(free_map);
+        self.sync()
+    }
+
+    /// Relocate data blocks of every file to be contiguous where free space allows.
+    /// Modifications of INodes are blocked until it returns.
+    /// `progress` is called with the finished fraction of files.
+    pub fn defragment(&self, mut progress: impl FnMut(f32)) -> vfs::Result<()> {
+        let _freeze = self.freeze.write();
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(FsError::ReadOnlyFs);
+        }
+
+        // collect all files
+        let mut files = Vec::new();
+        let mut dirs = vec![self.get_inode(BLKN_ROOT)?];
+        while let Some(dir) = dirs.pop() {
+            let count = dir.disk_inode.read().size as usize / DIRENT_SIZE;
+            // skip '.' and '..'
+            for i in 2..count {
+                let inode = self.get_inode(dir.read_direntry(i)?.id as INodeId)?;
+                let type_ = inode.disk_inode.read().type_;
+                match type_ {
+                    FileType::Dir => dirs.push(inode),
+                    FileType::File => files.push(inode),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut buf = [0u8; BLKSIZE];
+        for (i, file) in files.iter().enumerate() {
+            let blocks = file.disk_inode.read().blocks as usize;
+            if file.extents()?.len() > 1 {
+                if let Some(begin) = self.alloc_contiguous_blocks(blocks) {
+                    for block in 0..blocks {
+                        let old_block_id = file.get_disk_block_id(block)?;
+                        if old_block_id == 0 {
+                            // keep the hole
+                            self.free_block(begin + block);
+                            continue;
+                        }
+                        self.device.read_block(old_block_id, 0, &mut buf)?;
+                        self.device.write_block(begin + block, 0, &buf)?;
+                        file.set_disk_block_id(block, begin + block)?;
+                        self.release_block(old_block_id)?;
+                    }
+                }
+            }
+            progress((i + 1) as f32 / files.len() as f32);
+        }
+        Ok(())
+    }
+
     pub fn new_device_inode(&self, device_inode_id: usize, device_inode: Arc<DeviceINode>) {
         self.device_inodes
             .write()
@@ -880,75 +2229,228 @@ impl SimpleFileSystem {
         let inode = Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
+            dir_lock: Mutex::new(()),
             fs: self.self_ptr.upgrade().unwrap(),
             device_inode_id,
+            open_count: AtomicUsize::new(0),
+            released: AtomicBool::new(false),
         });
         self.inodes.write().insert(id, Arc::downgrade(&inode));
         inode
     }
 
+    /// Allocate a block for a new INode, near `goal`.
+    /// Return the block id and the next generation number of the block.
+    fn alloc_inode_block(&self, goal: BlockId) -> vfs::Result<(INodeId, u32)> {
+        let id = self.alloc_block(goal).ok_or(FsError::NoDeviceSpace)?;
+        Ok((id, self.read_disk_generation(id)?.wrapping_add(1)))
+    }
+    /// Read the generation number left on disk at block `id`.
+    /// A block never written before (e.g. beyond the end of an image file) counts as 0.
+    fn read_disk_generation(&self, id: BlockId) -> vfs::Result<u32> {
+        let mut generation: u32 = 0;
+        self.device.read_at(
+            id * BLKSIZE + offset_of!(DiskINode, generation),
+            generation.as_buf_mut(),
+        )?;
+        Ok(generation)
+    }
+    /// Check whether a `(id, generation)` handle still refers to a live INode
+    pub fn validate_handle(&self, id: INodeId, generation: u32) -> bool {
+        if id >= self.super_block.read().blocks as usize || self.free_map.read()[id] {
+            return false;
+        }
+        if let Some(inode) = self.inodes.read().get(&id).and_then(Weak::upgrade) {
+            return inode.generation() == generation;
+        }
+        self.read_disk_generation(id) == Ok(generation)
+    }
+    /// Iterate over all allocated INodes by scanning the freemap, for offline inspection.
+    /// Data blocks are skipped by checking `DiskINode::magic`,
+    /// so INodes written by versions before it are not found.
+    pub fn iter_allocated_inodes(&self) -> impl Iterator<Item = (INodeId, Metadata)> + '_ {
+        let (blocks, freemap_blocks) = {
+            let super_block = self.super_block.read();
+            (
+                super_block.blocks as usize,
+                super_block.freemap_blocks as usize,
+            )
+        };
+        let freemap = BLKN_FREEMAP..BLKN_FREEMAP + freemap_blocks;
+        (BLKN_ROOT..blocks)
+            .filter(move |id| !freemap.contains(id) && !self.free_map.read()[*id])
+            .filter_map(move |id| Some((id, self.allocated_inode_metadata(id)?)))
+    }
+    /// Copy of the freemap, `true` for each used block.
+    pub fn free_map_snapshot(&self) -> Vec<bool> {
+        let blocks = self.super_block.read().blocks as usize;
+        let free_map = self.free_map.read();
+        (0..blocks).map(|id| !free_map[id]).collect()
+    }
+    /// Classify each block by walking the freemap and the INodes in it,
+    /// with the same caveat on old INodes as `iter_allocated_inodes`.
+    pub fn block_usage_map(&self) -> Vec<BlockUsage> {
+        let mut map: Vec<BlockUsage> = self
+            .free_map_snapshot()
+            .into_iter()
+            .map(|used| match used {
+                true => BlockUsage::Data,
+                false => BlockUsage::Free,
+            })
+            .collect();
+        let (freemap_blocks, backup) = {
+            let super_block = self.super_block.read();
+            (
+                super_block.freemap_blocks as usize,
+                super_block.backup as usize,
+            )
+        };
+        map[BLKN_SUPER] = BlockUsage::SuperBlock;
+        if backup != 0 {
+            map[backup] = BlockUsage::SuperBlock;
+        }
+        for usage in &mut map[BLKN_FREEMAP..BLKN_FREEMAP + freemap_blocks] {
+            *usage = BlockUsage::FreeMap;
+        }
+        for (id, _) in self.iter_allocated_inodes() {
+            map[id] = BlockUsage::INode;
+        }
+        map
+    }
+    /// Metadata of the INode at allocated block `id`, or `None` if it holds data.
+    fn allocated_inode_metadata(&self, id: BlockId) -> Option<Metadata> {
+        let inode = self.inodes.read().get(&id).and_then(Weak::upgrade);
+        if let Some(inode) = inode {
+            return inode.metadata().ok();
+        }
+        // a data block may be never written as a whole, so do not use `read_block`
+        let mut magic: u32 = 0;
+        self.device
+            .read_at(
+                id * BLKSIZE + offset_of!(DiskINode, magic),
+                magic.as_buf_mut(),
+            )
+            .ok()?;
+        if magic != INODE_MAGIC {
+            return None;
+        }
+        let disk_inode = self.load_disk_inode(id).ok()?;
+        disk_inode.metadata(self.dev, id).ok()
+    }
     /// Get inode by id. Load if not in memory.
-    /// ** Must ensure it's a valid INode **
-    fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        assert!(!self.free_map.read()[id]);
+    /// Return `StaleFileHandle` if the block of `id` has been freed.
+    fn get_inode(&self, id: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        if id >= self.super_block.read().blocks as usize || self.free_map.read()[id] {
+            return Err(FsError::StaleFileHandle);
+        }
 
         // In the BTreeSet and not weak.
         if let Some(inode) = self.inodes.read().get(&id) {
             if let Some(inode) = inode.upgrade() {
-                return inode;
+                return Ok(inode);
             }
         }
         // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(id).unwrap());
-        self._new_inode(id, disk_inode)
+        let disk_inode = Dirty::new(self.load_disk_inode(id)?);
+        self.stats.inode_loads.fetch_add(1, Ordering::Relaxed);
+        Ok(self._new_inode(id, disk_inode))
     }
-    /// Create a new INode file
-    fn new_inode_file(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_file());
+    /// Load the INode at block `id` from device.
+    /// Return `WrongFs` if its type is corrupted.
+    fn load_disk_inode(&self, id: INodeId) -> vfs::Result<DiskINode> {
+        // check the raw value first, as an invalid `FileType` must not be created
+        let mut type_: u16 = 0;
+        self.device
+            .read_block(id, offset_of!(DiskINode, type_), type_.as_buf_mut())?;
+        if FileType::from_raw(type_).is_none() {
+            return Err(FsError::WrongFs);
+        }
+        self.device.load_struct::<DiskINode>(id)
+    }
+    /// Create a new INode file near its directory `parent`
+    fn new_inode_file(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        let (id, generation) = self.alloc_inode_block(parent)?;
+        let now = self.time_provider.current_time();
+        let disk_inode = Dirty::new_dirty(DiskINode {
+            generation,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            ..DiskINode::new_file()
+        });
         Ok(self._new_inode(id, disk_inode))
     }
-    /// Create a new INode symlink
-    fn new_inode_symlink(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_symlink());
+    /// Create a new INode symlink near its directory `parent`
+    fn new_inode_symlink(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        let (id, generation) = self.alloc_inode_block(parent)?;
+        let now = self.time_provider.current_time();
+        let disk_inode = Dirty::new_dirty(DiskINode {
+            generation,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            ..DiskINode::new_symlink()
+        });
         Ok(self._new_inode(id, disk_inode))
     }
     /// Create a new INode dir
     fn new_inode_dir(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_dir());
+        let (id, generation) = self.alloc_inode_block(parent)?;
+        let now = self.time_provider.current_time();
+        let disk_inode = Dirty::new_dirty(DiskINode {
+            generation,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            ..DiskINode::new_dir()
+        });
         let inode = self._new_inode(id, disk_inode);
-        inode.init_direntry(parent)?;
+        if let Err(e) = inode.init_direntry(parent) {
+            inode.abandon();
+            return Err(e);
+        }
         Ok(inode)
     }
     /// Create a new INode chardevice
     pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
+        let (id, generation) = self.alloc_inode_block(BLKN_ROOT)?;
+        let now = self.time_provider.current_time();
+        let disk_inode = Dirty::new_dirty(DiskINode {
+            generation,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            ..DiskINode::new_chardevice(device_inode_id)
+        });
         let new_inode = self._new_inode(id, disk_inode);
         Ok(new_inode)
     }
-    fn flush_weak_inodes(&self) {
-        let mut inodes = self.inodes.write();
-        let remove_ids: Vec<_> = inodes
-            .iter()
-            .filter(|(_, inode)| inode.upgrade().is_none())
-            .map(|(&id, _)| id)
-            .collect();
-        for id in remove_ids.iter() {
-            inodes.remove(id);
-        }
+    /// Create a new INode blockdevice, whose reads and writes go to the device INode
+    /// registered as `device_inode_id` by `new_device_inode`
+    pub fn new_inode_blockdevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
+        let (id, generation) = self.alloc_inode_block(BLKN_ROOT)?;
+        let now = self.time_provider.current_time();
+        let disk_inode = Dirty::new_dirty(DiskINode {
+            generation,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            ..DiskINode::new_blockdevice(device_inode_id)
+        });
+        let new_inode = self._new_inode(id, disk_inode);
+        Ok(new_inode)
     }
-}
-
-impl vfs::FileSystem for SimpleFileSystem {
-    /// Write back super block if dirty
-    fn sync(&self) -> vfs::Result<()> {
+    /// Write back the superblock and freemap if dirty
+    fn sync_meta(&self) -> vfs::Result<()> {
         // order is important, see issue #18
         let mut free_map = self.free_map.write();
         let mut super_block = self.super_block.write();
         if super_block.dirty() {
+            if super_block.backup != 0 {
+                super_block.crc = super_block.checksum();
+                self.device
+                    .write_at(BLKSIZE * super_block.backup as usize, super_block.as_buf())?;
+            }
             self.device
                 .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
             super_block.sync();
@@ -963,26 +2465,135 @@ impl vfs::FileSystem for SimpleFileSystem {
             }
             free_map.sync();
         }
+        Ok(())
+    }
+    /// Write back the superblock and freemap, then at most `max_inodes` dirty INodes.
+    /// Return `true` once nothing is left dirty, so a caller can spread a long `sync`
+    /// over several calls.
+    pub fn sync_budget(&self, max_inodes: usize) -> vfs::Result<bool> {
+        if self.device.in_transaction() {
+            return Err(FsError::Busy);
+        }
+        self.sync_meta()?;
         self.flush_weak_inodes();
-        for inode in self.inodes.read().values() {
-            if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
+        let dirty_inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .filter(|inode| inode.disk_inode.read().dirty())
+            .take(max_inodes + 1)
+            .collect();
+        let done = dirty_inodes.len() <= max_inodes;
+        let dirty_inodes = dirty_inodes.into_iter().take(max_inodes).collect();
+        self.sync_inodes(dirty_inodes, &AtomicBool::new(false))?;
+        if done {
+            self.device.sync()?;
+            self.stats.syncs.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(done)
+    }
+    /// Write back dirty `inodes`, one device write per run of them in adjacent blocks.
+    /// Stop with `Interrupted` between runs once `cancel` is set.
+    fn sync_inodes(&self, mut inodes: Vec<Arc<INodeImpl>>, cancel: &AtomicBool) -> vfs::Result<()> {
+        inodes.sort_by_key(|inode| inode.id);
+        for run in inodes.chunk_by(|a, b| b.id == a.id + 1) {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(FsError::Interrupted);
+            }
+            // locked in the order of id, held until written
+            let mut disk_inodes: Vec<_> =
+                run.iter().map(|inode| inode.disk_inode.write()).collect();
+            // the block may belong to another INode now
+            let mut dirty: Vec<_> = run
+                .iter()
+                .zip(disk_inodes.iter_mut())
+                .filter(|(inode, disk_inode)| {
+                    disk_inode.dirty() && !inode.released.load(Ordering::SeqCst)
+                })
+                .collect();
+            for part in dirty.chunk_by_mut(|(a, _), (b, _)| b.id == a.id + 1) {
+                let mut buf = vec![0u8; part.len() * BLKSIZE];
+                for (block, (_, disk_inode)) in buf.chunks_mut(BLKSIZE).zip(part.iter()) {
+                    let data = disk_inode.as_buf();
+                    block[..data.len()].copy_from_slice(data);
+                }
+                self.device.write_blocks(part[0].0.id, &buf)?;
+                for (_, disk_inode) in part.iter_mut() {
+                    disk_inode.sync();
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Write back the superblock, freemap and dirty INodes, without syncing the device
+    fn write_back(&self, cancel: &AtomicBool) -> vfs::Result<()> {
+        self.sync_meta()?;
+        self.flush_weak_inodes();
+        let inodes = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        self.sync_inodes(inodes, cancel)
+    }
+    fn flush_weak_inodes(&self) {
+        let mut inodes = self.inodes.write();
+        let remove_ids: Vec<_> = inodes
+            .iter()
+            .filter(|(_, inode)| inode.upgrade().is_none())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in remove_ids.iter() {
+            inodes.remove(id);
+        }
+    }
+}
+
+impl vfs::FileSystem for SimpleFileSystem {
+    /// Write back super block if dirty
+    fn sync(&self) -> vfs::Result<()> {
+        self.sync_cancellable(&AtomicBool::new(false))
+    }
+
+    /// Fail with `Busy` while a transaction is open, in any thread,
+    /// as its writes are held in memory until it finishes
+    fn sync_cancellable(&self, cancel: &AtomicBool) -> vfs::Result<()> {
+        if self.device.in_transaction() {
+            return Err(FsError::Busy);
+        }
+        if let Err(e) = self.write_back(cancel) {
+            if e == FsError::Interrupted {
+                // keep the INodes written so far
+                self.device.sync()?;
             }
+            return Err(e);
         }
         self.device.sync()?;
+        self.stats.syncs.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
-        self.get_inode(BLKN_ROOT)
+        self.get_inode(self.root)
+            .expect("failed to load root inode")
         // let root = self.get_inode(BLKN_ROOT);
         // root.create("dev", vfs::FileType::Dir, 0).expect("fail to create dev"); // what's mode?
         // return root;
     }
 
+    /// Cached until the count of free blocks changes, so polling it does not
+    /// contend the superblock lock with allocations
     fn info(&self) -> vfs::FsInfo {
+        let epoch = self.info_epoch.load(Ordering::SeqCst);
+        let cache = self.info_cache.read();
+        if cache.0 == epoch {
+            return cache.1;
+        }
+        drop(cache);
         let sb = self.super_block.read();
-        vfs::FsInfo {
+        let info = vfs::FsInfo {
             bsize: BLKSIZE,
             frsize: BLKSIZE,
             blocks: sb.blocks as usize,
@@ -991,26 +2602,122 @@ impl vfs::FileSystem for SimpleFileSystem {
             files: sb.blocks as usize,        // inaccurate
             ffree: sb.unused_blocks as usize, // inaccurate
             namemax: MAX_FNAME_LEN,
+        };
+        drop(sb);
+        *self.info_cache.write() = (epoch, info);
+        info
+    }
+
+    fn preferred_io_size(&self) -> usize {
+        BLKSIZE
+    }
+
+    fn supports_holes(&self) -> bool {
+        true
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.super_block.read().dirty()
+            || self.free_map.read().dirty()
+            || !self.device.pending.read().is_empty()
+            || self
+                .inodes
+                .read()
+                .values()
+                .filter_map(Weak::upgrade)
+                .any(|inode| inode.disk_inode.read().dirty())
+    }
+
+    /// Nothing written inside `f` reaches the device until it returns `Ok`.
+    /// INodes dropped inside `f` are written back into memory only,
+    /// and if `f` fails they reach the device on the next `sync`.
+    ///
+    /// While a transaction is open, `sync` and `INode::sync_all` fail with `Busy`
+    /// in every thread, as they could not make anything durable.
+    fn transaction<T>(&self, f: impl FnOnce() -> vfs::Result<T>) -> vfs::Result<T>
+    where
+        Self: Sized,
+    {
+        self.device.depth.fetch_add(1, Ordering::SeqCst);
+        let ret = f().and_then(|ret| {
+            self.write_back(&AtomicBool::new(false))?;
+            Ok(ret)
+        });
+        self.device.depth.fetch_sub(1, Ordering::SeqCst);
+        let ret = ret?;
+        self.device.sync()?;
+        self.stats.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(ret)
+    }
+
+    /// In a single transaction, so nothing reaches the device until all entries are created
+    fn import_tree(&self, entries: &mut dyn Iterator<Item = vfs::ImportEntry>) -> vfs::Result<()> {
+        let root = self.root_inode();
+        self.transaction(|| root.import_tree(entries))
+    }
+
+    fn remount(&self, options: vfs::MountOptions) -> vfs::Result<()> {
+        // a snapshot stays read-only
+        if !options.read_only && (self.device.is_read_only() || self.root != BLKN_ROOT) {
+            return Err(FsError::ReadOnlyFs);
+        }
+        // wait for ongoing modifications, and block new ones until switched
+        let _freeze = self.freeze.write();
+        if options.read_only {
+            self.sync()?;
+        }
+        self.read_only.store(options.read_only, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn mount_options(&self) -> vfs::MountOptions {
+        vfs::MountOptions {
+            read_only: self.read_only.load(Ordering::SeqCst),
         }
     }
+
+    fn fs_type(&self) -> &'static str {
+        "sfs"
+    }
+
+    fn stats(&self) -> vfs::Statistics {
+        self.stats.snapshot()
+    }
 }
 
 impl Drop for SimpleFileSystem {
-    /// Auto sync when drop
+    /// Auto sync when drop. Errors are only logged, use `close` to get them.
     fn drop(&mut self) {
-        self.sync()
-            .expect("Failed to sync when dropping the SimpleFileSystem");
+        if let Err(e) = self.sync() {
+            error!("failed to sync when dropping the SimpleFileSystem: {:?}", e);
+        }
+    }
+}
+
+/// Number of indirect blocks of an INode with `blocks` blocks, see `INodeImpl::_resize`
+fn indirect_blocks(blocks: usize) -> usize {
+    match blocks {
+        blocks if blocks < MAX_NBLOCK_DIRECT => 0,
+        blocks if blocks < MAX_NBLOCK_INDIRECT => 1,
+        blocks => 2 + (blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1,
     }
 }
 
 trait BitsetAlloc {
-    fn alloc(&mut self) -> Option<usize>;
+    /// Clear the set bit nearest to `goal`, return its index
+    fn alloc(&mut self, goal: usize) -> Option<usize>;
 }
 
 impl BitsetAlloc for BitVec<u8, Lsb0> {
-    fn alloc(&mut self) -> Option<usize> {
-        // TODO: more efficient
-        let id = (0..self.len()).find(|&i| self[i]);
+    fn alloc(&mut self, goal: usize) -> Option<usize> {
+        let goal = goal.min(self.len());
+        let after = self[goal..].first_one().map(|i| goal + i);
+        let before = self[..goal].last_one();
+        let id = match (before, after) {
+            (Some(before), Some(after)) if goal - before < after - goal => Some(before),
+            (before, None) => before,
+            (_, after) => after,
+        };
         if let Some(id) = id {
             self.set(id, false);
         }
@@ -1029,15 +2736,17 @@ impl AsBuf for BitVec<u8, Lsb0> {
 
 impl AsBuf for [u8; BLKSIZE] {}
 
-impl From<FileType> for vfs::FileType {
-    fn from(t: FileType) -> Self {
+impl TryFrom<FileType> for vfs::FileType {
+    type Error = FsError;
+
+    fn try_from(t: FileType) -> vfs::Result<Self> {
         match t {
-            FileType::File => vfs::FileType::File,
-            FileType::SymLink => vfs::FileType::SymLink,
-            FileType::Dir => vfs::FileType::Dir,
-            FileType::CharDevice => vfs::FileType::CharDevice,
-            FileType::BlockDevice => vfs::FileType::BlockDevice,
-            _ => panic!("unknown file type"),
+            FileType::File => Ok(vfs::FileType::File),
+            FileType::SymLink => Ok(vfs::FileType::SymLink),
+            FileType::Dir => Ok(vfs::FileType::Dir),
+            FileType::CharDevice => Ok(vfs::FileType::CharDevice),
+            FileType::BlockDevice => Ok(vfs::FileType::BlockDevice),
+            FileType::Invalid => Err(FsError::WrongFs),
         }
     }
 }
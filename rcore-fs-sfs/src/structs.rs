@@ -3,10 +3,11 @@
 use crate::vfs;
 use alloc::str;
 
+use core::convert::TryFrom;
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::{size_of, size_of_val};
+use core::mem::{offset_of, size_of, size_of_val};
 use core::slice;
-use rcore_fs::vfs::Timespec;
+use rcore_fs::{util::crc32, vfs::Timespec};
 use static_assertions::const_assert;
 
 /// On-disk superblock
@@ -23,6 +24,14 @@ pub struct SuperBlock {
     pub info: Str32,
     /// number of freemap blocks
     pub freemap_blocks: u32,
+    /// block of the backup superblock, 0 if there is none
+    pub backup: u32,
+    /// CRC-32 of the fields above, 0 along with `backup` in images created before it
+    pub crc: u32,
+    /// hidden file of extra reference counts of blocks shared with snapshots, 0 if there is none
+    pub refcounts: u32,
+    /// hidden file of the root INodes of snapshots, 0 if there is none
+    pub snapshots: u32,
 }
 
 /// inode (on disk)
@@ -53,6 +62,13 @@ pub struct DiskINode {
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// generation number, bumped each time the block is reused as an inode
+    pub generation: u32,
+    /// INODE_MAGIC, tells inode blocks apart from data blocks.
+    /// Cleared when the inode is freed.
+    pub magic: u32,
+    /// number of blocks within `blocks` left unallocated as holes
+    pub holes: u32,
 }
 
 /*
@@ -129,11 +145,51 @@ impl<'a> From<&'a str> for Str32 {
 
 impl SuperBlock {
     pub fn check(&self) -> bool {
-        self.magic == MAGIC
+        let legacy = self.backup == 0 && self.crc == 0;
+        self.magic == MAGIC && (legacy || self.crc == self.checksum())
+    }
+    /// CRC-32 of the fields other than `crc`. The fields after it are left out while 0,
+    /// so images created before them keep their checksum.
+    pub fn checksum(&self) -> u32 {
+        let buf = self.as_buf();
+        let (before, after) = (
+            &buf[..offset_of!(SuperBlock, crc)],
+            &buf[offset_of!(SuperBlock, refcounts)..],
+        );
+        match after.iter().all(|&b| b == 0) {
+            true => crc32(before),
+            false => crc32(&[before, after].concat()),
+        }
     }
 }
 
 impl DiskINode {
+    /// Metadata of the inode at block `id` of the fs with device id `dev`
+    pub fn metadata(&self, dev: usize, id: INodeId) -> vfs::Result<vfs::Metadata> {
+        Ok(vfs::Metadata {
+            dev,
+            inode: id,
+            size: match self.type_ {
+                FileType::File | FileType::SymLink => self.size as usize,
+                FileType::Dir => self.size as usize,
+                FileType::CharDevice => 0,
+                FileType::BlockDevice => 0,
+                FileType::Invalid => return Err(vfs::FsError::WrongFs),
+            },
+            mode: 0o777,
+            type_: vfs::FileType::try_from(self.type_)?,
+            // holes are not allocated
+            blocks: self.blocks.saturating_sub(self.holes) as usize,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            nlinks: self.nlinks as usize,
+            uid: 0,
+            gid: 0,
+            blk_size: BLKSIZE,
+            rdev: self.device_inode_id,
+        })
+    }
     pub const fn new_file() -> Self {
         DiskINode {
             size: 0,
@@ -147,6 +203,9 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            generation: 0,
+            magic: INODE_MAGIC,
+            holes: 0,
         }
     }
     pub const fn new_symlink() -> Self {
@@ -162,6 +221,9 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            generation: 0,
+            magic: INODE_MAGIC,
+            holes: 0,
         }
     }
     pub const fn new_dir() -> Self {
@@ -177,6 +239,9 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            generation: 0,
+            magic: INODE_MAGIC,
+            holes: 0,
         }
     }
     pub const fn new_chardevice(device_inode_id: usize) -> Self {
@@ -192,6 +257,15 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            generation: 0,
+            magic: INODE_MAGIC,
+            holes: 0,
+        }
+    }
+    pub const fn new_blockdevice(device_inode_id: usize) -> Self {
+        DiskINode {
+            type_: FileType::BlockDevice,
+            ..DiskINode::new_chardevice(device_inode_id)
         }
     }
 }
@@ -212,6 +286,10 @@ impl AsBuf for DiskINode {}
 
 impl AsBuf for DiskEntry {}
 
+impl AsBuf for IndirectBlock {}
+
+impl AsBuf for u16 {}
+
 impl AsBuf for u32 {}
 
 /*
@@ -225,6 +303,8 @@ pub const NODEVICE: usize = 100;
 
 /// magic number for sfs
 pub const MAGIC: u32 = 0x2f8dbe2b;
+/// magic number for inode
+pub const INODE_MAGIC: u32 = 0x696e6f64;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
@@ -273,6 +353,20 @@ pub enum FileType {
     BlockDevice = 5,
 }
 
+impl FileType {
+    /// Parse the raw value of an in-use INode, `None` if invalid
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        match raw {
+            1 => Some(FileType::File),
+            2 => Some(FileType::Dir),
+            3 => Some(FileType::SymLink),
+            4 => Some(FileType::CharDevice),
+            5 => Some(FileType::BlockDevice),
+            _ => None,
+        }
+    }
+}
+
 const_assert!(size_of::<SuperBlock>() <= BLKSIZE);
 const_assert!(size_of::<DiskINode>() <= BLKSIZE);
 const_assert!(size_of::<DiskEntry>() <= BLKSIZE);
@@ -2,12 +2,23 @@ extern crate std;
 
 use crate::*;
 use rcore_fs::{
-    util::uninit_memory,
-    vfs::{FileSystem, FileType, Metadata, Result, Timespec},
+    dev::{
+        self, block_cache::BlockCache, std_impl::TestDevice, BlockDevice, Device,
+        MonotonicTimeProvider,
+    },
+    testing::*,
+    util::{uninit_memory, YieldEvery},
+    vfs::{
+        Advice, FileSystem, FileType, FsError, INode, ImportEntry, Metadata, MountOptions,
+        PollStatus, Result, Timespec,
+    },
 };
 use std::{
-    fs::{self, OpenOptions},
-    sync::{Arc, Mutex},
+    fs::{self, File, OpenOptions},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 fn _open_sample_file() -> Arc<SimpleFileSystem> {
@@ -20,6 +31,11 @@ fn _open_sample_file() -> Arc<SimpleFileSystem> {
     SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
 }
 
+fn _new_test_device() -> Arc<TestDevice> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    Arc::new(TestDevice::new(file))
+}
+
 fn _create_new_sfs() -> Arc<SimpleFileSystem> {
     let file = tempfile::tempfile().expect("failed to create file");
     SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096 * 4096)
@@ -59,7 +75,7 @@ fn create_file() -> Result<()> {
             ctime: Timespec { sec: 0, nsec: 0 },
             gid: 0,
             blk_size: 4096,
-            dev: 0,
+            dev: root.metadata()?.dev,
             rdev: 100, // dummo why 100 here, maybe legacy data?
         }
     );
@@ -563,3 +579,1691 @@ fn create_then_get_entry() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+#[test]
+fn inode_generation() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let id = file1.metadata()?.inode;
+    let generation = file1.downcast_ref::<INodeImpl>().unwrap().generation();
+    assert!(sfs.validate_handle(id, generation));
+
+    // free the inode, then reuse its block
+    root.unlink("file1")?;
+    drop(file1);
+    assert!(!sfs.validate_handle(id, generation));
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    assert_eq!(file2.metadata()?.inode, id, "block should be reused");
+    let new_generation = file2.downcast_ref::<INodeImpl>().unwrap().generation();
+    assert_ne!(new_generation, generation);
+    assert!(!sfs.validate_handle(id, generation));
+    assert!(sfs.validate_handle(id, new_generation));
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn is_dirty() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    assert!(sfs.is_dirty());
+
+    sfs.sync()?;
+    assert!(!sfs.is_dirty());
+    file1.write_at(0, b"hello")?;
+    assert!(sfs.is_dirty());
+
+    sfs.sync()?;
+    assert!(!sfs.is_dirty());
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let device = _new_test_device();
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    sfs.sync()?;
+    let writes = device.writes.load(Ordering::SeqCst);
+
+    let root = sfs.root_inode();
+    sfs.transaction(|| {
+        let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+        let file1 = dir1.create("file1", FileType::File, 0o777)?;
+        dir1.create("file2", FileType::File, 0o777)?;
+        dir1.link("file3", &file1)?;
+        file1.write_at(0, b"hello")?;
+        // nothing would reach the device, from any thread
+        std::thread::scope(|s| {
+            assert_eq!(s.spawn(|| sfs.sync()).join().unwrap(), Err(FsError::Busy));
+            assert_eq!(
+                s.spawn(|| file1.sync_all()).join().unwrap(),
+                Err(FsError::Busy)
+            );
+        });
+        assert_eq!(device.writes.load(Ordering::SeqCst), writes);
+        Ok(())
+    })?;
+    assert!(device.writes.load(Ordering::SeqCst) > writes);
+    assert!(!sfs.is_dirty());
+
+    // reopen and check everything is persisted
+    drop(root);
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    let file3 = sfs.root_inode().lookup("dir1/file3")?;
+    let mut buf = [0u8; 5];
+    assert_eq!(file3.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+    assert!(sfs.root_inode().lookup("dir1/file2").is_ok());
+    Ok(())
+}
+
+#[test]
+fn sync_coalesces_inodes() -> Result<()> {
+    let device = _new_test_device();
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let files = (0..8)
+        .map(|i| root.create(&format!("file{}", i), FileType::File, 0o777))
+        .collect::<Result<Vec<_>>>()?;
+    let ids: Vec<_> = files.iter().map(|f| f.metadata().unwrap().inode).collect();
+    assert!(ids.windows(2).all(|w| w[1] == w[0] + 1));
+    sfs.sync()?;
+
+    // 8 dirty INodes in adjacent blocks, written at once
+    for file in files.iter() {
+        let mut metadata = file.metadata()?;
+        metadata.mtime = Timespec { sec: 42, nsec: 0 };
+        file.set_metadata(&metadata)?;
+    }
+    let writes = device.writes.load(Ordering::SeqCst);
+    sfs.sync()?;
+    assert_eq!(device.writes.load(Ordering::SeqCst), writes + 1);
+    assert!(!sfs.is_dirty());
+
+    drop(files);
+    drop(root);
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    for i in 0..8 {
+        let file = sfs.root_inode().lookup(&format!("file{}", i))?;
+        assert_eq!(file.metadata()?.mtime.sec, 42);
+    }
+    Ok(())
+}
+
+/// A block device counting the block reads reaching it
+struct CountingBlockDevice {
+    file: Mutex<File>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl BlockDevice for CountingBlockDevice {
+    const BLOCK_SIZE_LOG2: u8 = 9;
+    fn read_at(&self, block_id: dev::BlockId, buf: &mut [u8]) -> dev::Result<()> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let len = Device::read_at(&self.file, block_id << 9, &mut buf[..512])?;
+        buf[len..512].fill(0);
+        Ok(())
+    }
+    fn write_at(&self, block_id: dev::BlockId, buf: &[u8]) -> dev::Result<()> {
+        Device::write_at(&self.file, block_id << 9, &buf[..512])?;
+        Ok(())
+    }
+    fn sync(&self) -> dev::Result<()> {
+        Device::sync(&self.file)
+    }
+}
+
+#[test]
+fn fadvise() -> Result<()> {
+    let reads = Arc::new(AtomicUsize::new(0));
+    let device = BlockCache::new(
+        CountingBlockDevice {
+            file: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+            reads: reads.clone(),
+        },
+        256,
+    );
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1u8; 2 * BLKSIZE])?;
+    sfs.sync()?;
+
+    let mut buf = [0u8; 2 * BLKSIZE];
+    let count = reads.load(Ordering::SeqCst);
+    file1.read_at(0, &mut buf)?;
+    assert_eq!(
+        reads.load(Ordering::SeqCst),
+        count,
+        "blocks should be cached"
+    );
+
+    // drop the second block only
+    file1.fadvise(BLKSIZE, BLKSIZE, Advice::DontNeed)?;
+    file1.read_at(0, &mut buf[..BLKSIZE])?;
+    assert_eq!(reads.load(Ordering::SeqCst), count);
+    file1.read_at(BLKSIZE, &mut buf[BLKSIZE..])?;
+    assert_eq!(reads.load(Ordering::SeqCst), count + 8);
+    assert_eq!(buf, [1u8; 2 * BLKSIZE]);
+
+    // prefetch the whole file
+    file1.fadvise(0, 0, Advice::DontNeed)?;
+    file1.fadvise(0, 0, Advice::WillNeed)?;
+    let count = reads.load(Ordering::SeqCst);
+    file1.read_at(0, &mut buf)?;
+    assert_eq!(reads.load(Ordering::SeqCst), count);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn stale_file_handle() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let id = file1.metadata()?.inode;
+    root.unlink("file1")?;
+    drop(file1);
+    assert_eq!(sfs.get_inode(id).err(), Some(FsError::StaleFileHandle));
+
+    // a dangling entry left in a directory
+    let root_impl = root.downcast_ref::<INodeImpl>().unwrap();
+    root_impl.append_direntry(&DiskEntry {
+        id: id as u32,
+        name: Str256::from("dangling"),
+    })?;
+    assert_eq!(root.find("dangling").err(), Some(FsError::StaleFileHandle));
+    assert_eq!(
+        root.unlink("dangling").err(),
+        Some(FsError::StaleFileHandle)
+    );
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn concurrent_unlink() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    const NTHREAD: usize = 8;
+    const NFILE: usize = 16;
+    for i in 0..NTHREAD * NFILE {
+        dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+
+    // every thread unlinks its own files, keeping the even ones
+    let threads: Vec<_> = (0..NTHREAD)
+        .map(|t| {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                for i in (t * NFILE..(t + 1) * NFILE).filter(|i| i % 2 == 1) {
+                    dir.unlink(&format!("file{}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut names = dir.list()?;
+    names.sort();
+    let mut expected: Vec<String> = (0..NTHREAD * NFILE)
+        .filter(|i| i % 2 == 0)
+        .map(|i| format!("file{}", i))
+        .chain([String::from("."), String::from("..")])
+        .collect();
+    expected.sort();
+    assert_eq!(names, expected);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn defragment() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    // interleave the blocks of two files
+    for i in 0..4 {
+        file1.write_at(i * BLKSIZE, &[i as u8 + 1; BLKSIZE])?;
+        file2.write_at(i * BLKSIZE, &[0xff; BLKSIZE])?;
+    }
+    root.unlink("file2")?;
+    drop(file2);
+    let file1_impl = file1.downcast_ref::<INodeImpl>().unwrap();
+    assert_eq!(file1_impl.extents()?.len(), 4);
+
+    let mut progress = Vec::new();
+    sfs.defragment(|p| progress.push(p))?;
+    assert_eq!(progress, [1.0]);
+    let extents = file1_impl.extents()?;
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[0].len(), 4);
+    for i in 0..4 {
+        let mut buf = [0u8; BLKSIZE];
+        file1.read_at(i * BLKSIZE, &mut buf)?;
+        assert!(buf.iter().all(|&b| b == i as u8 + 1));
+    }
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn nanosecond_times() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let reopen_file = file.try_clone().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    let time = Timespec {
+        sec: 0x1_2345_6789,
+        nsec: 987_654_321,
+    };
+    {
+        let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+        let mut metadata = file1.metadata()?;
+        metadata.atime = time;
+        metadata.mtime = time;
+        metadata.ctime = time;
+        file1.set_metadata(&metadata)?;
+    }
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(reopen_file)))?;
+    let metadata = sfs.root_inode().lookup("file1")?.metadata()?;
+    assert_eq!(metadata.atime, time);
+    assert_eq!(metadata.mtime, time);
+    assert_eq!(metadata.ctime, time);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn for_each_entry() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    for name in ["file1", "file2", "file3"] {
+        root.create(name, FileType::File, 0o777)?;
+    }
+    let mut names = Vec::new();
+    root.for_each_entry(|id, name| {
+        names.push(String::from(name));
+        Ok(id < 2)
+    })?;
+    assert_eq!(names, [".", "..", "file1"]);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn remount_read_only() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"hello")?;
+
+    sfs.remount(MountOptions { read_only: true })?;
+    assert!(!sfs.is_dirty());
+    assert_eq!(file1.write_at(0, b"world"), Err(FsError::ReadOnlyFs));
+    assert_eq!(file1.resize(0), Err(FsError::ReadOnlyFs));
+    assert_eq!(
+        root.create("file2", FileType::File, 0o777).err(),
+        Some(FsError::ReadOnlyFs)
+    );
+    assert_eq!(root.unlink("file1"), Err(FsError::ReadOnlyFs));
+    let mut buf = [0u8; 5];
+    file1.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+
+    sfs.remount(MountOptions::default())?;
+    file1.write_at(0, b"world")?;
+    root.create("file2", FileType::File, 0o777)?;
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn time_provider() -> Result<()> {
+    static CLOCK: MonotonicTimeProvider = MonotonicTimeProvider::new(Timespec {
+        sec: 1_000_000,
+        nsec: 0,
+    });
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs =
+        SimpleFileSystem::create_with_time_provider(Arc::new(Mutex::new(file)), 32 * 4096, &CLOCK)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let created = file1.metadata()?.ctime;
+    assert_eq!(created, CLOCK.current_time());
+    assert_eq!(root.metadata()?.mtime, created);
+
+    CLOCK.advance(1_500_000_000);
+    file1.write_at(0, b"hello")?;
+    let metadata = file1.metadata()?;
+    assert!(metadata.ctime > created);
+    assert_eq!(metadata.mtime, CLOCK.current_time());
+    assert_eq!(metadata.atime, created);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn iter_allocated_inodes() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let reopen_file = file.try_clone().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    let mut expected = {
+        let root = sfs.root_inode();
+        let file1 = root.create("file1", FileType::File, 0o777)?;
+        let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+        let link1 = dir1.create("link1", FileType::SymLink, 0o777)?;
+        link1.write_at(0, b"../file1")?;
+        // the freed INode block is reused for data of file1
+        root.create("file2", FileType::File, 0o777)?;
+        root.unlink("file2")?;
+        file1.resize(4 * BLKSIZE)?;
+        vec![
+            (BLKN_ROOT, FileType::Dir),
+            (file1.metadata()?.inode, FileType::File),
+            (dir1.metadata()?.inode, FileType::Dir),
+            (link1.metadata()?.inode, FileType::SymLink),
+        ]
+    };
+    expected.sort_by_key(|(id, _)| *id);
+    let found: Vec<_> = sfs
+        .iter_allocated_inodes()
+        .map(|(id, metadata)| (id, metadata.type_))
+        .collect();
+    assert_eq!(found, expected);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(reopen_file)))?;
+    let found: Vec<_> = sfs
+        .iter_allocated_inodes()
+        .map(|(id, metadata)| {
+            assert_eq!(metadata.inode, id);
+            (id, metadata.type_)
+        })
+        .collect();
+    assert_eq!(found, expected);
+    Ok(())
+}
+
+#[test]
+fn backup_super_block() -> Result<()> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let file = tempfile::tempfile().expect("failed to create file");
+    let mut raw = file.try_clone().unwrap();
+    let reopen_file = file.try_clone().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    sfs.sync()?;
+    drop(sfs);
+
+    raw.seek(SeekFrom::Start(0)).unwrap();
+    raw.write_all(&[0u8; BLKSIZE]).unwrap();
+
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(reopen_file)))?;
+    sfs.root_inode().lookup("file1")?;
+    sfs.sync()?;
+
+    // the primary superblock is restored from the backup
+    let mut super_block: SuperBlock = unsafe { uninit_memory() };
+    raw.seek(SeekFrom::Start(0)).unwrap();
+    raw.read_exact(super_block.as_buf_mut()).unwrap();
+    assert!(super_block.check());
+    Ok(())
+}
+
+#[test]
+fn no_device_space() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let free = sfs.info().bfree;
+    // the indirect block takes one more
+    file1.resize((free - 2) * BLKSIZE)?;
+    assert_eq!(sfs.info().bfree, 1);
+
+    let size = file1.metadata()?.size;
+    assert_eq!(
+        file1.write_at(size, &[1u8; 2 * BLKSIZE]),
+        Err(FsError::NoDeviceSpace)
+    );
+    assert_eq!(file1.metadata()?.size, size);
+    assert_eq!(sfs.info().bfree, 1);
+
+    file1.write_at(size, &[1u8; BLKSIZE])?;
+    assert_eq!(sfs.info().bfree, 0);
+    assert_eq!(
+        file1.resize(size + 2 * BLKSIZE),
+        Err(FsError::NoDeviceSpace)
+    );
+    assert_eq!(
+        root.create("file2", FileType::File, 0o777).err(),
+        Some(FsError::NoDeviceSpace)
+    );
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn link_inodeimpl() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file = root.create("file", FileType::File, 0o777)?;
+    let dir_impl = sfs.get_inode(dir.metadata()?.inode)?;
+    let file_impl = sfs.get_inode(file.metadata()?.inode)?;
+
+    let size = dir.metadata()?.size;
+    dir_impl.link_inodeimpl("link", &file_impl)?;
+    assert_eq!(dir.metadata()?.size, size + DIRENT_SIZE);
+    assert_eq!(file.metadata()?.nlinks, 2);
+    assert_eq!(dir.find("link")?.metadata()?.inode, file.metadata()?.inode);
+    assert_eq!(
+        dir_impl.link_inodeimpl("link", &file_impl),
+        Err(FsError::EntryExist)
+    );
+
+    // fill up the last block of the directory, then the device
+    let mut nlinks = 2;
+    loop {
+        let size = dir.metadata()?.size;
+        if size.div_ceil(BLKSIZE) != (size + DIRENT_SIZE).div_ceil(BLKSIZE) {
+            break;
+        }
+        dir_impl.link_inodeimpl(&format!("link{}", nlinks), &file_impl)?;
+        nlinks += 1;
+    }
+    let fill = root.create("fill", FileType::File, 0o777)?;
+    // the indirect block takes one more
+    fill.resize((sfs.info().bfree - 1) * BLKSIZE)?;
+    assert_eq!(sfs.info().bfree, 0);
+    let size = dir.metadata()?.size;
+    assert_eq!(
+        dir_impl.link_inodeimpl("full", &file_impl),
+        Err(FsError::NoDeviceSpace)
+    );
+    assert_eq!(dir.metadata()?.size, size);
+    assert_eq!(file.metadata()?.nlinks, nlinks);
+    assert!(dir.find("full").is_err());
+    Ok(())
+}
+
+#[test]
+fn move_dir_updates_dotdot() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::Dir, 0o777)?;
+    let b = root.create("b", FileType::Dir, 0o777)?;
+    let sub = a.create("sub", FileType::Dir, 0o777)?;
+    assert!(Arc::ptr_eq(&sub.lookup("..")?, &a));
+
+    a.move_("sub", &b, "sub")?;
+    let sub = root.lookup("b/sub")?;
+    assert!(Arc::ptr_eq(&sub.lookup("..")?, &b));
+    assert!(Arc::ptr_eq(&root.lookup("b/sub/../..")?, &root));
+    assert_eq!(a.metadata()?.nlinks, 2);
+    assert_eq!(b.metadata()?.nlinks, 3);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn read_only_device() -> Result<()> {
+    let image = tempfile::NamedTempFile::new().expect("failed to create file");
+    {
+        let file = image.reopen().unwrap();
+        let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+        let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+        file1.write_at(0, b"hello")?;
+        sfs.sync()?;
+    }
+
+    let file = File::open(image.path()).unwrap();
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    assert!(device.is_read_only());
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    let mut buf = [0u8; 5];
+    root.lookup("file1")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    assert_eq!(
+        root.create("file2", FileType::File, 0o777).err(),
+        Some(FsError::ReadOnlyFs)
+    );
+    assert_eq!(
+        sfs.remount(MountOptions::default()),
+        Err(FsError::ReadOnlyFs)
+    );
+    Ok(())
+}
+
+#[test]
+fn block_usage_map() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.resize(2 * BLKSIZE)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    file2.write_at(0, b"hello")?;
+
+    let snapshot = sfs.free_map_snapshot();
+    let unused_blocks = snapshot.iter().filter(|&&used| !used).count();
+    assert_eq!(unused_blocks, sfs.info().bfree);
+
+    let map = sfs.block_usage_map();
+    assert_eq!(map.len(), snapshot.len());
+    assert_eq!(map[BLKN_SUPER], BlockUsage::SuperBlock);
+    assert_eq!(map[BLKN_ROOT], BlockUsage::INode);
+    assert_eq!(map[BLKN_FREEMAP], BlockUsage::FreeMap);
+    for file in [&file1, &file2] {
+        let inode = file.metadata()?.inode;
+        assert!(snapshot[inode]);
+        assert_eq!(map[inode], BlockUsage::INode);
+    }
+    let file1 = file1.downcast_ref::<INodeImpl>().unwrap();
+    for extent in file1.extents()? {
+        for id in extent {
+            assert!(snapshot[id]);
+            assert_eq!(map[id], BlockUsage::Data);
+        }
+    }
+    for (used, usage) in snapshot.iter().zip(&map) {
+        assert_eq!(*used, *usage != BlockUsage::Free);
+    }
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn sync_budget() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let mut inodes = (0..20)
+        .map(|i| root.create(&format!("file{}", i), FileType::File, 0o777))
+        .collect::<Result<Vec<_>>>()?;
+    inodes.push(root);
+    let is_dirty = |inode: &Arc<dyn INode>| {
+        let inode = inode.downcast_ref::<INodeImpl>().unwrap();
+        inode.disk_inode.read().dirty()
+    };
+    assert!(inodes.iter().all(is_dirty));
+
+    let mut calls = 0;
+    while !sfs.sync_budget(4)? {
+        calls += 1;
+        let count = inodes.iter().filter(|inode| is_dirty(inode)).count();
+        assert_eq!(count, 21 - 4 * calls);
+    }
+    assert_eq!(calls, 5);
+    assert!(!inodes.iter().any(is_dirty));
+    assert!(!sfs.is_dirty());
+    Ok(())
+}
+
+#[test]
+fn corrupted_file_type() -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let file = tempfile::tempfile().expect("failed to create file");
+    let mut raw = file.try_clone().unwrap();
+    let reopen_file = file.try_clone().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    let id = sfs
+        .root_inode()
+        .create("file1", FileType::File, 0o777)?
+        .metadata()?
+        .inode;
+    sfs.sync()?;
+    drop(sfs);
+
+    let offset = id * BLKSIZE + core::mem::offset_of!(DiskINode, type_);
+    raw.seek(SeekFrom::Start(offset as u64)).unwrap();
+    raw.write_all(&0x42u16.to_le_bytes()).unwrap();
+
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(reopen_file)))?;
+    let root = sfs.root_inode();
+    assert_eq!(root.find("file1").err(), Some(FsError::WrongFs));
+    assert_eq!(
+        root.get_entry_with_metadata(2).err(),
+        Some(FsError::WrongFs)
+    );
+    assert!(sfs.iter_allocated_inodes().all(|(inode, _)| inode != id));
+    Ok(())
+}
+
+#[test]
+fn punch_hole() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..8 * BLKSIZE).map(|i| (i % 251 + 1) as u8).collect();
+    file1.write_at(0, &data)?;
+
+    // blocks 2..5 are freed, parts of blocks 1 and 5 are zeroed
+    let free = sfs.info().bfree;
+    let (begin, end) = (BLKSIZE + 100, 5 * BLKSIZE + 200);
+    file1.punch_hole(begin, end - begin)?;
+    assert_eq!(sfs.info().bfree, free + 3);
+    assert_eq!(file1.metadata()?.size, data.len());
+    let file1_impl = file1.downcast_ref::<INodeImpl>().unwrap();
+    assert_eq!(file1_impl.extents()?.len(), 2);
+
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(file1.read_at(0, &mut buf)?, data.len());
+    assert_eq!(buf[..begin], data[..begin]);
+    assert!(buf[begin..end].iter().all(|&b| b == 0));
+    assert_eq!(buf[end..], data[end..]);
+
+    // writing into the hole allocates a block again
+    file1.write_at(3 * BLKSIZE, b"refill")?;
+    assert_eq!(sfs.info().bfree, free + 2);
+    file1.read_at(3 * BLKSIZE - 1, &mut buf[..8])?;
+    assert_eq!(&buf[..8], b"\0refill\0");
+
+    // punching to the end frees the last partial block, shrinking then frees the rest
+    file1.resize(7 * BLKSIZE + 10)?;
+    let free = sfs.info().bfree;
+    file1.punch_hole(6 * BLKSIZE, usize::MAX)?;
+    assert_eq!(sfs.info().bfree, free + 2);
+    file1.resize(0)?;
+    assert_eq!(
+        root.create("dir", FileType::Dir, 0o777)?.punch_hole(0, 1),
+        Err(FsError::NotFile)
+    );
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let reopen_file = file.try_clone().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 4096 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"old content")?;
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file2 = dir.create("file2", FileType::File, 0o777)?;
+    root.link("link2", &file2)?;
+    // beyond the direct and indirect blocks
+    let big = root.create("big", FileType::File, 0o777)?;
+    let old_big: Vec<u8> = (0..(MAX_NBLOCK_INDIRECT + 3) * BLKSIZE)
+        .map(|i| (i / BLKSIZE) as u8)
+        .collect();
+    big.write_at(0, &old_big)?;
+
+    let snapshot = sfs.snapshot()?;
+    let id = snapshot.root_inode().metadata()?.inode;
+    assert_eq!(sfs.snapshots()?, vec![id]);
+    file1.write_at(0, b"new")?;
+    file1.resize(2 * BLKSIZE)?;
+    dir.unlink("file2")?;
+    root.create("file3", FileType::File, 0o777)?;
+    big.write_at(BLKSIZE / 2, &[0xff; BLKSIZE])?;
+    big.punch_hole(NDIRECT * BLKSIZE, BLKSIZE)?;
+    big.write_at(old_big.len() - 1, b"x")?;
+    sfs.sync()?;
+
+    let check_old = |snapshot: &Arc<SimpleFileSystem>| -> Result<()> {
+        let snap_root = snapshot.root_inode();
+        let snap_file1 = snap_root.find("file1")?;
+        let mut buf = [0u8; 32];
+        assert_eq!(snap_file1.read_at(0, &mut buf)?, 11);
+        assert_eq!(&buf[..11], b"old content");
+        let snap_file2 = snap_root.lookup("dir/file2")?;
+        assert_eq!(snap_file2.metadata()?.nlinks, 2);
+        assert_eq!(
+            snap_file2.metadata()?.inode,
+            snap_root.find("link2")?.metadata()?.inode
+        );
+        assert_eq!(snap_root.find("file3").err(), Some(FsError::EntryNotFound));
+        let mut buf = vec![0u8; old_big.len()];
+        assert_eq!(snap_root.find("big")?.read_at(0, &mut buf)?, buf.len());
+        assert!(buf == old_big);
+        assert_eq!(
+            snap_root.create("file4", FileType::File, 0o777).err(),
+            Some(FsError::ReadOnlyFs)
+        );
+        assert_eq!(
+            snap_file1.write_at(0, b"x").err(),
+            Some(FsError::ReadOnlyFs)
+        );
+        Ok(())
+    };
+    check_old(&snapshot)?;
+
+    let mut buf = [0u8; 32];
+    assert_eq!(file1.read_at(0, &mut buf)?, 32);
+    assert_eq!(&buf[..11], b"new content");
+    let mut buf = [0u8; 2];
+    big.read_at(BLKSIZE / 2 * 3 - 1, &mut buf)?;
+    assert_eq!(buf, [0xff, 1]);
+    drop((file1, file2, dir, big, root));
+    drop(snapshot);
+    drop(sfs);
+
+    // the snapshot is kept on disk
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(reopen_file));
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    assert_eq!(sfs.snapshots()?, vec![id]);
+    let snapshot = SimpleFileSystem::open_snapshot(device.clone(), id)?;
+    check_old(&snapshot)?;
+    assert_eq!(
+        snapshot.remount(MountOptions { read_only: false }),
+        Err(FsError::ReadOnlyFs)
+    );
+    assert_eq!(
+        SimpleFileSystem::open_snapshot(device, BLKN_ROOT).err(),
+        Some(FsError::EntryNotFound)
+    );
+    drop(snapshot);
+    sfs.delete_snapshot(id)?;
+    assert_eq!(sfs.snapshots()?, vec![]);
+    assert_eq!(sfs.delete_snapshot(id), Err(FsError::EntryNotFound));
+    let mut buf = [0u8; 11];
+    sfs.root_inode().find("file1")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"new content");
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn snapshot_blocks_shared() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1u8; 64 * BLKSIZE])?;
+    // create the hidden files of snapshots
+    let snapshot = sfs.snapshot()?;
+    let id = snapshot.root_inode().metadata()?.inode;
+    drop(snapshot);
+    sfs.delete_snapshot(id)?;
+
+    let free = sfs.info().bfree;
+    let snapshot = sfs.snapshot()?;
+    // the INodes, the root directory, the indirect block of file1, and the list of snapshots
+    assert_eq!(sfs.info().bfree, free - 5);
+    // each block shared is copied once, never failing for the snapshot
+    file1.write_at(0, &[2u8; 64 * BLKSIZE])?;
+    file1.write_at(0, &[3u8; 64 * BLKSIZE])?;
+    assert_eq!(sfs.info().bfree, free - 5 - 64);
+
+    let snap_file1 = snapshot.root_inode().find("file1")?;
+    let mut buf = vec![0u8; 64 * BLKSIZE];
+    assert_eq!(snap_file1.read_at(0, &mut buf)?, buf.len());
+    assert!(buf.iter().all(|&b| b == 1));
+    drop(snap_file1);
+    let id = snapshot.root_inode().metadata()?.inode;
+    drop(snapshot);
+    sfs.delete_snapshot(id)?;
+    assert_eq!(sfs.info().bfree, free);
+
+    // unshared blocks are written in place
+    file1.write_at(0, &[4u8; 64 * BLKSIZE])?;
+    assert_eq!(sfs.info().bfree, free);
+    let snapshot = sfs.snapshot()?;
+    let id = snapshot.root_inode().metadata()?.inode;
+    drop(snapshot);
+    // freeing the file leaves the blocks to the snapshot
+    sfs.root_inode().unlink("file1")?;
+    drop(file1);
+    // the INode and indirect block of file1 are freed
+    assert_eq!(sfs.info().bfree, free - 5 + 2);
+    sfs.delete_snapshot(id)?;
+    assert_eq!(sfs.info().bfree, free + 2 + 64);
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn readahead() -> Result<()> {
+    let reads = Arc::new(AtomicUsize::new(0));
+    let device = BlockCache::new(
+        CountingBlockDevice {
+            file: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+            reads: reads.clone(),
+        },
+        256,
+    );
+    let sfs = SimpleFileSystem::create(Arc::new(device), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1u8; 4 * BLKSIZE])?;
+    sfs.sync()?;
+    file1.fadvise(0, 0, Advice::DontNeed)?;
+
+    let mut buf = [0u8; 2 * BLKSIZE];
+    let count = reads.load(Ordering::SeqCst);
+    file1.readahead(BLKSIZE, 2 * BLKSIZE)?;
+    assert_eq!(reads.load(Ordering::SeqCst), count + 16);
+    file1.read_at(BLKSIZE, &mut buf)?;
+    assert_eq!(reads.load(Ordering::SeqCst), count + 16);
+    assert_eq!(buf, [1u8; 2 * BLKSIZE]);
+    // blocks out of the range are not loaded
+    file1.read_at(3 * BLKSIZE, &mut buf[..BLKSIZE])?;
+    assert_eq!(reads.load(Ordering::SeqCst), count + 24);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn repair_dots() -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let file = tempfile::tempfile().expect("failed to create file");
+    let mut raw = file.try_clone().unwrap();
+    let reopen_file = file.try_clone().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096)?;
+    let root = sfs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let dir2 = dir1.create("dir2", FileType::Dir, 0o777)?;
+    let dir1_id = dir1.metadata()?.inode;
+    let dir2_id = dir2.metadata()?.inode;
+    let block = dir2.downcast_ref::<INodeImpl>().unwrap().extents()?[0].start;
+    drop((dir1, dir2, root));
+    sfs.sync()?;
+    drop(sfs);
+
+    // point '..' of dir2 to itself, with a garbled name
+    let mut entry = DiskEntry {
+        id: dir2_id as u32,
+        name: Str256::from(".."),
+    };
+    entry.name.0[1] = 0xff;
+    raw.seek(SeekFrom::Start((block * BLKSIZE + DIRENT_SIZE) as u64))
+        .unwrap();
+    raw.write_all(entry.as_buf()).unwrap();
+
+    let options = crate::OpenOptions { repair_dots: true };
+    let sfs = SimpleFileSystem::open_with_options(
+        Arc::new(Mutex::new(reopen_file)),
+        &dev::ZeroTimeProvider,
+        options,
+    )?;
+    let dir2 = sfs.root_inode().lookup("dir1/dir2")?;
+    assert_eq!(dir2.get_entry(1)?, "..");
+    assert_eq!(dir2.lookup("..")?.metadata()?.inode, dir1_id);
+    assert_eq!(dir2.lookup("../..")?.metadata()?.inode, BLKN_ROOT);
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn sparse_blocks() -> Result<()> {
+    let sfs = _create_new_sfs();
+    assert!(sfs.supports_holes());
+    let root = sfs.root_inode();
+    // create a file, write it, punch a hole, then stat it as FUSE getattr does
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1u8; 16 * BLKSIZE])?;
+    assert_eq!(file1.metadata()?.blocks, 16);
+    file1.punch_hole(2 * BLKSIZE, 10 * BLKSIZE)?;
+
+    let metadata = file1.metadata()?;
+    assert_eq!(metadata.size, 16 * BLKSIZE);
+    assert_eq!(metadata.blocks, 6);
+    assert!(metadata.blocks < metadata.size.div_ceil(BLKSIZE));
+
+    // filled again by writing, freed by shrinking
+    file1.write_at(3 * BLKSIZE, &[2u8; 2 * BLKSIZE])?;
+    assert_eq!(file1.metadata()?.blocks, 8);
+    file1.resize(8 * BLKSIZE)?;
+    assert_eq!(file1.metadata()?.blocks, 4);
+    file1.resize(16 * BLKSIZE)?;
+    assert_eq!(file1.metadata()?.blocks, 12);
+    let extents = file1.downcast_ref::<INodeImpl>().unwrap().extents()?;
+    assert_eq!(extents.iter().map(|extent| extent.len()).sum::<usize>(), 12);
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn rename_in_same_dir() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+
+    // onto a free name
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"file1")?;
+    root.move_("file1", &root, "file2")?;
+    assert_eq!(root.list()?, [".", "..", "file2"]);
+    assert_eq!(file1.metadata()?.nlinks, 1);
+
+    // onto an existing file, which is unlinked
+    let file3 = root.create("file3", FileType::File, 0o777)?;
+    root.link("file3_link", &file3)?;
+    root.move_("file2", &root, "file3")?;
+    assert_eq!(root.list()?, [".", "..", "file3", "file3_link"]);
+    assert_eq!(file3.metadata()?.nlinks, 1);
+    let mut buf = [0u8; 5];
+    root.find("file3")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"file1");
+
+    // onto itself or another link of the same file
+    root.link("file3_other", &root.find("file3")?)?;
+    root.move_("file3", &root, "file3")?;
+    root.move_("file3", &root, "file3_other")?;
+    assert_eq!(file1.metadata()?.nlinks, 2);
+
+    // onto an empty dir, but not a non-empty one or a non-dir
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let dir2 = root.create("dir2", FileType::Dir, 0o777)?;
+    dir2.create("file", FileType::File, 0o777)?;
+    let nlinks = root.metadata()?.nlinks;
+    assert_eq!(root.move_("dir1", &root, "dir2"), Err(FsError::DirNotEmpty));
+    assert_eq!(root.move_("dir1", &root, "file3"), Err(FsError::NotDir));
+    assert_eq!(root.move_("file3", &root, "dir1"), Err(FsError::IsDir));
+    assert_eq!(root.move_("dir2", &root, ".."), Err(FsError::EntryExist));
+    root.move_("dir2", &root, "dir1")?;
+    assert_eq!(dir1.metadata()?.nlinks, 0);
+    assert_eq!(root.metadata()?.nlinks, nlinks - 1);
+    assert!(root.lookup("dir1/file").is_ok());
+    assert_eq!(
+        root.move_("nothing", &root, "file3"),
+        Err(FsError::EntryNotFound)
+    );
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn free_on_last_close() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.open()?;
+    file1.open()?;
+    let data = [1u8; 3 * BLKSIZE];
+    file1.write_at(0, &data)?;
+    let free = sfs.info().bfree;
+
+    // still readable through the open handles after unlinked
+    root.unlink("file1")?;
+    assert_eq!(file1.metadata()?.nlinks, 0);
+    let mut buf = [0u8; 3 * BLKSIZE];
+    assert_eq!(file1.read_at(0, &mut buf)?, data.len());
+    assert_eq!(buf, data);
+    file1.close()?;
+    assert_eq!(file1.open_count(), 1);
+    assert_eq!(sfs.info().bfree, free);
+
+    // the content and the INode are freed on the last close, not on drop
+    file1.close()?;
+    assert_eq!(sfs.info().bfree, free + 4);
+    assert_eq!(file1.close(), Err(FsError::InvalidParam));
+
+    // other references can not allocate for the freed INode
+    let metadata = file1.metadata()?;
+    assert_eq!(
+        file1.write_at(0, &data).err(),
+        Some(FsError::StaleFileHandle)
+    );
+    assert_eq!(file1.resize(BLKSIZE).err(), Some(FsError::StaleFileHandle));
+    assert_eq!(
+        file1.set_metadata(&metadata).err(),
+        Some(FsError::StaleFileHandle)
+    );
+    assert_eq!(sfs.info().bfree, free + 4);
+    drop(file1);
+    assert_eq!(sfs.info().bfree, free + 4);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn probe() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096)?;
+    sfs.sync()?;
+    assert_eq!(rcore_fs::probe(device), Some("sfs"));
+
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    device.write_at(0, &[0x5a; 4096])?;
+    assert_eq!(rcore_fs::probe(device), None);
+    // too small to hold a magic number
+    let file = tempfile::tempfile().expect("failed to create file");
+    assert_eq!(rcore_fs::probe(Arc::new(Mutex::new(file))), None);
+    Ok(())
+}
+
+#[test]
+fn import_tree_yielding() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let files = (0..1000).map(|i| ImportEntry::File {
+        path: format!("file{}", i),
+        mode: 0o644,
+        data: Vec::new(),
+    });
+    let root = sfs.root_inode();
+    let mut yields = Vec::new();
+    let mut yield_now = || yields.push(root.list().unwrap().len() - 2);
+    sfs.import_tree(&mut YieldEvery::new(files, 64, &mut yield_now))?;
+    // after every 64 entries created
+    assert_eq!(yields, (1..=1000 / 64).map(|i| i * 64).collect::<Vec<_>>());
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn import_tree() -> Result<()> {
+    let device = _new_test_device();
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    sfs.sync()?;
+    let writes = device.writes.load(Ordering::SeqCst);
+    device.syncs.store(0, Ordering::SeqCst);
+
+    let dirs = (0..10).map(|i| ImportEntry::Dir {
+        path: format!("dir{}", i),
+        mode: 0o755,
+    });
+    let files = (0..1000).map(|i| ImportEntry::File {
+        path: format!("dir{}/file{}", i % 10, i),
+        mode: 0o644,
+        data: format!("content of file{}", i).into_bytes(),
+    });
+    let link = ImportEntry::SymLink {
+        path: String::from("dir0/link"),
+        mode: 0o777,
+        target: String::from("../dir1/file1"),
+    };
+    sfs.import_tree(&mut dirs.chain(files).chain(Some(link)))?;
+    assert_eq!(device.syncs.load(Ordering::SeqCst), 1);
+    assert!(device.writes.load(Ordering::SeqCst) > writes);
+    assert!(!sfs.is_dirty());
+
+    // parents must exist
+    let orphan = ImportEntry::Dir {
+        path: String::from("none/dir"),
+        mode: 0o755,
+    };
+    assert_eq!(
+        sfs.import_tree(&mut Some(orphan).into_iter()),
+        Err(FsError::EntryNotFound)
+    );
+
+    // reopen and check everything is persisted
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    for i in 0..1000 {
+        let file = root.lookup(&format!("dir{}/file{}", i % 10, i))?;
+        let data = format!("content of file{}", i);
+        let mut buf = vec![0u8; data.len() + 1];
+        assert_eq!(file.read_at(0, &mut buf)?, data.len());
+        assert_eq!(&buf[..data.len()], data.as_bytes());
+    }
+    assert_eq!(root.lookup("dir3")?.list()?.len(), 2 + 100);
+    assert_eq!(root.lookup("dir0/link")?.read_link()?, "../dir1/file1");
+    assert_eq!(root.lookup_follow("dir0/link", 1)?.metadata()?.size, 16);
+    Ok(())
+}
+
+#[test]
+fn sync_cancellable() -> Result<()> {
+    let device = _new_test_device();
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let mut inodes = (0..20)
+        .map(|i| root.create(&format!("file{}", i), FileType::File, 0o777))
+        .collect::<Result<Vec<_>>>()?;
+    inodes.push(root);
+    let dirty_count = |inodes: &[Arc<dyn INode>]| {
+        inodes
+            .iter()
+            .filter(|inode| {
+                let inode = inode.downcast_ref::<INodeImpl>().unwrap();
+                inode.disk_inode.read().dirty()
+            })
+            .count()
+    };
+    assert_eq!(dirty_count(&inodes), 21);
+
+    // interrupted in the middle, keeping the INodes written so far
+    device.writes_left.store(8, Ordering::SeqCst);
+    assert_eq!(
+        sfs.sync_cancellable(&device.cancel),
+        Err(FsError::Interrupted)
+    );
+    let count = dirty_count(&inodes);
+    assert!(0 < count && count < 21);
+
+    // not even started once set
+    assert_eq!(
+        sfs.sync_cancellable(&device.cancel),
+        Err(FsError::Interrupted)
+    );
+    assert_eq!(dirty_count(&inodes), count);
+
+    device.cancel.store(false, Ordering::SeqCst);
+    sfs.sync_cancellable(&device.cancel)?;
+    assert_eq!(dirty_count(&inodes), 0);
+    assert!(!sfs.is_dirty());
+    Ok(())
+}
+
+/// A block device kept in memory, to register as a device INode
+struct MemBlockDevice(Mutex<Vec<u8>>);
+
+impl INode for MemBlockDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let data = self.0.lock().unwrap();
+        let start = data.len().min(offset);
+        let end = data.len().min(offset + buf.len());
+        buf[..end - start].copy_from_slice(&data[start..end]);
+        Ok(end - start)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut data = self.0.lock().unwrap();
+        let end = data.len().min(offset + buf.len());
+        let len = end.saturating_sub(offset);
+        data[offset..end].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[test]
+fn block_device() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dev = root.create("dev", FileType::Dir, 0o777)?;
+    let sda = dev.create2("sda", FileType::BlockDevice, 0o666, 8)?;
+    assert_eq!(sda.metadata()?.type_, FileType::BlockDevice);
+    assert_eq!(sda.metadata()?.rdev, 8);
+
+    // nothing registered yet
+    let mut buf = [0u8; 4];
+    assert_eq!(sda.read_at(0, &mut buf), Err(FsError::DeviceError));
+
+    let disk = Arc::new(MemBlockDevice(Mutex::new((0..=255).collect())));
+    sfs.new_device_inode(8, disk.clone());
+    assert_eq!(sda.read_at(16, &mut buf)?, 4);
+    assert_eq!(buf, [16, 17, 18, 19]);
+    assert_eq!(sda.write_at(254, b"abcd")?, 2);
+    assert_eq!(&disk.0.lock().unwrap()[252..], &[252, 253, b'a', b'b']);
+    assert_eq!(sda.io_control(0, 0), Err(FsError::NotSupported));
+    assert_eq!(sda.resize(0), Err(FsError::NotFile));
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn reserve_entries() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file = root.create("file", FileType::File, 0o777)?;
+    assert_eq!(file.reserve_entries(1), Err(FsError::NotDir));
+
+    let bfree = sfs.info().bfree;
+    dir.reserve_entries(1000)?;
+    let blocks = dir.metadata()?.blocks;
+    assert!(blocks * BLKSIZE >= 1002 * DIRENT_SIZE);
+    assert!(sfs.info().bfree < bfree - 60);
+    assert_eq!(dir.metadata()?.size, 2 * DIRENT_SIZE);
+    assert_eq!(dir.list()?, [".", ".."]);
+    // already enough
+    dir.reserve_entries(500)?;
+    assert_eq!(dir.metadata()?.blocks, blocks);
+
+    // only the INode of each file is allocated
+    let bfree = sfs.info().bfree;
+    for i in 0..1000 {
+        dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+    assert_eq!(sfs.info().bfree, bfree - 1000);
+    assert_eq!(dir.metadata()?.blocks, blocks);
+    assert_eq!(dir.metadata()?.size, 1002 * DIRENT_SIZE);
+    assert_eq!(dir.get_entry(1001)?, "file999");
+
+    // shrinking frees the rest
+    dir.create("one_more", FileType::File, 0o777)?;
+    for i in 0..1000 {
+        dir.unlink(&format!("file{}", i))?;
+    }
+    assert_eq!(dir.metadata()?.blocks, 1);
+    assert_eq!(dir.list()?, [".", "..", "one_more"]);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn too_many_links() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let set_nlinks = |inode: &Arc<dyn INode>, nlinks| {
+        let inode = inode.downcast_ref::<INodeImpl>().unwrap();
+        inode.disk_inode.write().nlinks = nlinks;
+    };
+
+    // as if linked almost as many times as the on-disk count holds
+    let file = root.create("file", FileType::File, 0o777)?;
+    set_nlinks(&file, u16::MAX - 1);
+    root.link("last", &file)?;
+    assert_eq!(file.metadata()?.nlinks, u16::MAX as usize);
+    assert_eq!(root.link("one_more", &file), Err(FsError::TooManyLinks));
+    assert_eq!(file.metadata()?.nlinks, u16::MAX as usize);
+    assert_eq!(root.list()?, [".", "..", "file", "last"]);
+
+    // subdirectories link to the parent by ".."
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    root.create("other", FileType::Dir, 0o777)?;
+    set_nlinks(&dir, u16::MAX);
+    let bfree = sfs.info().bfree;
+    assert_eq!(
+        dir.create("sub", FileType::Dir, 0o777).err(),
+        Some(FsError::TooManyLinks)
+    );
+    assert_eq!(
+        root.move_("other", &dir, "other"),
+        Err(FsError::TooManyLinks)
+    );
+    assert_eq!(dir.list()?, [".", ".."]);
+    assert!(root.find("other").is_ok());
+    assert_eq!(sfs.info().bfree, bfree);
+    // files are fine
+    dir.create("file", FileType::File, 0o777)?;
+
+    set_nlinks(&dir, 2);
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn drop_with_failing_sync() -> Result<()> {
+    let device = _new_test_device();
+    device.fail_syncs.store(true, Ordering::SeqCst);
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, b"file1")?;
+    root.unlink("file1")?;
+    assert!(sfs.sync().is_err());
+    // logged instead of panicking
+    drop(file1);
+    drop(root);
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::create(device, 32 * 4096)?;
+    sfs.root_inode().create("file2", FileType::File, 0o777)?;
+    assert!(sfs.close().is_err());
+    Ok(())
+}
+
+#[test]
+fn create_rollback() -> Result<()> {
+    let device = _new_test_device();
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    // the next entry needs a new block of the root directory
+    for i in 0..BLKSIZE / DIRENT_SIZE - 2 {
+        root.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+    sfs.sync()?;
+    let bfree = sfs.info().bfree;
+    let list = root.list()?;
+    let nlinks = root.metadata()?.nlinks;
+
+    device.fail_writes.store(true, Ordering::SeqCst);
+    for type_ in [FileType::File, FileType::Dir, FileType::SymLink] {
+        assert_eq!(
+            root.create("new", type_, 0o777).err(),
+            Some(FsError::DeviceError)
+        );
+        assert_eq!(sfs.info().bfree, bfree);
+        assert_eq!(root.list()?, list);
+        assert_eq!(root.metadata()?.nlinks, nlinks);
+    }
+    device.fail_writes.store(false, Ordering::SeqCst);
+
+    // nothing left behind on the device either
+    root.create("dir", FileType::Dir, 0o777)?;
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    assert_eq!(root.list()?.len(), list.len() + 1);
+    assert_eq!(root.find("new").err(), Some(FsError::EntryNotFound));
+    // the INode and content of "dir", and a block of the root directory
+    assert_eq!(sfs.info().bfree, bfree - 3);
+    Ok(())
+}
+
+#[test]
+fn alloc_near_parent() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let filler = root.create("filler", FileType::File, 0o777)?;
+    filler.resize(64 * BLKSIZE)?;
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    drop(filler);
+    // leave free blocks at the beginning, far from the directory
+    root.unlink("filler")?;
+
+    let dir_id = dir.metadata()?.inode;
+    for i in 0..4 {
+        let file = dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+        file.resize(4 * BLKSIZE)?;
+        let file = file.downcast_ref::<INodeImpl>().unwrap();
+        let mut blocks = vec![file.id];
+        for extent in file.extents()? {
+            blocks.extend(extent);
+        }
+        for id in blocks {
+            assert!(id.abs_diff(dir_id) < 32, "block {} of file{}", id, i);
+        }
+    }
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    file1.resize(3 * BLKSIZE)?;
+    sfs.sync()?;
+
+    let stats = sfs.stats();
+    let mut buf = [0u8; BLKSIZE];
+    file1.read_at(BLKSIZE, &mut buf)?;
+    assert_eq!(sfs.stats().block_reads, stats.block_reads + 1);
+    file1.write_at(0, &buf)?;
+    assert_eq!(sfs.stats().block_writes, stats.block_writes + 1);
+
+    drop(file1);
+    let stats = sfs.stats();
+    let file1 = root.find("file1")?;
+    assert_eq!(sfs.stats().inode_loads, stats.inode_loads + 1);
+    root.find("file1")?;
+    assert_eq!(sfs.stats().inode_loads, stats.inode_loads + 1);
+
+    let stats = sfs.stats();
+    root.unlink("file1")?;
+    drop(file1);
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    file2.resize(BLKSIZE)?;
+    sfs.sync()?;
+    let after = sfs.stats();
+    assert_eq!(after.blocks_freed, stats.blocks_freed + 4);
+    assert_eq!(after.blocks_allocated, stats.blocks_allocated + 2);
+    assert_eq!(after.syncs, stats.syncs + 1);
+    Ok(())
+}
+
+#[test]
+fn dir_blocks() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let dir_impl = dir.downcast_ref::<INodeImpl>().unwrap();
+    for i in 0..100 {
+        dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+        let metadata = dir.metadata()?;
+        let allocated: usize = dir_impl.extents()?.iter().map(|e| e.len()).sum();
+        assert_eq!(metadata.blocks, allocated);
+        assert_eq!(metadata.blocks, metadata.size.div_ceil(metadata.blk_size));
+    }
+    // 102 entries, not 102 blocks
+    assert!(dir.metadata()?.blocks < 102);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn move_onto_existing() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    root.create("a", FileType::Dir, 0o777)?
+        .create("x", FileType::File, 0o777)?;
+    root.create("empty", FileType::Dir, 0o777)?;
+    root.create("full", FileType::Dir, 0o777)?
+        .create("y", FileType::File, 0o777)?;
+    root.create("file", FileType::File, 0o777)?;
+    assert_eq!(root.metadata()?.nlinks, 5);
+
+    // in the same directory
+    assert_eq!(root.move_("a", &root, "full"), Err(FsError::DirNotEmpty));
+    assert_eq!(root.move_("a", &root, "file"), Err(FsError::NotDir));
+    assert_eq!(root.move_("file", &root, "empty"), Err(FsError::IsDir));
+    root.move_("a", &root, "empty")?;
+    assert!(root.lookup("empty/x").is_ok());
+    assert_eq!(root.find("a").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.metadata()?.nlinks, 4);
+
+    // across directories
+    let sub = root.create("sub", FileType::Dir, 0o777)?;
+    sub.create("empty", FileType::Dir, 0o777)?;
+    sub.create("full", FileType::Dir, 0o777)?
+        .create("z", FileType::File, 0o777)?;
+    sub.create("file", FileType::File, 0o777)?;
+    assert_eq!(root.move_("empty", &sub, "full"), Err(FsError::DirNotEmpty));
+    assert_eq!(root.move_("empty", &sub, "file"), Err(FsError::NotDir));
+    assert_eq!(root.move_("file", &sub, "empty"), Err(FsError::IsDir));
+    root.move_("empty", &sub, "empty")?;
+    assert!(sub.lookup("empty/x").is_ok());
+    assert_eq!(
+        sub.lookup("empty/..")?.metadata()?.inode,
+        sub.metadata()?.inode
+    );
+    assert_eq!(sub.metadata()?.nlinks, 4);
+    assert_eq!(root.metadata()?.nlinks, 4);
+
+    // a file replaces a file
+    let file = root.find("file")?;
+    root.move_("file", &sub, "file")?;
+    assert_eq!(sub.find("file")?.metadata()?.inode, file.metadata()?.inode);
+    assert_eq!(sub.list()?.len(), 5);
+
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn move_rollback() -> Result<()> {
+    // onto an existing entry, and as a new one
+    for new_name in ["victim", "new"] {
+        // fail each write of the move in turn, until it succeeds
+        for fail_at in 1.. {
+            let device = _new_test_device();
+            let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+            let root = sfs.root_inode();
+            let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+            let dir2 = root.create("dir2", FileType::Dir, 0o777)?;
+            let file = dir1.create("file", FileType::File, 0o777)?;
+            dir1.create("other", FileType::File, 0o777)?;
+            let victim = dir2.create("victim", FileType::File, 0o777)?;
+            let id = |inode: &Arc<dyn INode>| inode.metadata().unwrap().inode;
+
+            device.fail_writes_after.store(fail_at, Ordering::SeqCst);
+            let moved = dir1.move_("file", &dir2, new_name);
+            device.fail_writes_after.store(0, Ordering::SeqCst);
+            device.fail_writes.store(false, Ordering::SeqCst);
+
+            // every name is counted in nlinks, once
+            let names = [dir1.find("file"), dir2.find(new_name)];
+            let links = |inode| {
+                names
+                    .iter()
+                    .flatten()
+                    .filter(|i| id(i) == id(inode))
+                    .count()
+            };
+            assert_eq!(
+                file.metadata()?.nlinks,
+                links(&file),
+                "failed at {}",
+                fail_at
+            );
+            assert_eq!(
+                victim.metadata()?.nlinks,
+                links(&victim) + (new_name != "victim") as usize,
+                "failed at {}",
+                fail_at
+            );
+            if moved.is_ok() {
+                assert_eq!(links(&file), 1);
+                break;
+            }
+            assert_eq!(moved, Err(FsError::DeviceError));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn info_cached() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let bfree = sfs.info().bfree;
+
+    // served from the cache while a writer holds the superblock,
+    // it would spin forever if the lock was taken
+    let super_block = sfs.super_block.write();
+    for _ in 0..3 {
+        assert_eq!(sfs.info().bfree, bfree);
+    }
+    drop(super_block);
+
+    let file = root.create("file", FileType::File, 0o777)?;
+    file.write_at(0, &[1; BLKSIZE])?;
+    // the inode and its content
+    assert_eq!(sfs.info().bfree, bfree - 2);
+    root.unlink("file")?;
+    drop(file);
+    assert_eq!(sfs.info().bfree, bfree);
+    Ok(())
+}
+
+#[test]
+fn grow() -> Result<()> {
+    let file = tempfile::tempfile().unwrap();
+    file.set_len(20000 * BLKSIZE as u64).unwrap();
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 64 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let data: Vec<u8> = (0..100 * BLKSIZE).map(|i| (i % 251) as u8).collect();
+    let file = root.create("file", FileType::File, 0o777)?;
+    assert_eq!(file.write_at(0, &data), Err(FsError::NoDeviceSpace));
+    let bfree = sfs.info().bfree;
+
+    assert_eq!(sfs.grow(32), Err(FsError::InvalidParam));
+    assert_eq!(sfs.grow(40000), Err(FsError::NoDeviceSpace));
+    sfs.grow(20000)?;
+    assert_eq!(sfs.info().blocks, 20000);
+    assert_eq!(sfs.info().bfree, bfree + 20000 - 64);
+    let map = sfs.block_usage_map();
+    assert_eq!(map[63], BlockUsage::Free);
+    assert_eq!(map[19999], BlockUsage::SuperBlock);
+
+    // the new space is usable
+    file.write_at(0, &data)?;
+    let bfree = sfs.info().bfree;
+    drop(file);
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    assert_eq!(sfs.info().blocks, 20000);
+    assert_eq!(sfs.info().bfree, bfree);
+    let mut buf = vec![0; data.len()];
+    sfs.root_inode().find("file")?.read_at(0, &mut buf)?;
+    assert_eq!(buf, data);
+    Ok(())
+}
+
+#[test]
+fn grow_past_freemap() -> Result<()> {
+    let file = tempfile::tempfile().unwrap();
+    file.set_len((BLKBITS + 100) as u64 * BLKSIZE as u64)
+        .unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 64 * BLKSIZE)?;
+    // a second freemap block would take the one after it, which holds the root dir
+    assert_eq!(sfs.block_usage_map()[BLKN_FREEMAP + 1], BlockUsage::Data);
+    let info = sfs.info();
+    assert_eq!(sfs.grow(BLKBITS + 100), Err(FsError::NoDeviceSpace));
+    assert_eq!(sfs.info().blocks, info.blocks);
+    assert_eq!(sfs.info().bfree, info.bfree);
+    // up to what one freemap block covers
+    sfs.grow(BLKBITS)?;
+    assert_eq!(sfs.info().bfree, info.bfree + BLKBITS - 64);
+    Ok(())
+}
+
+#[test]
+fn short_reads() {
+    check_short_reads(_create_new_sfs().root_inode());
+}
+
+#[test]
+fn invalid_names() {
+    check_invalid_names(_create_new_sfs().root_inode());
+}
+
+#[test]
+fn write_at_offset_overflow() -> Result<()> {
+    let root = _create_new_sfs().root_inode();
+    check_write_at_offset_overflow(root.clone());
+    let file = root.find("file")?;
+    assert_eq!(
+        file.write_at(MAX_FILE_SIZE, &[0u8; 8]),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(file.metadata()?.size, 7);
+    Ok(())
+}
+
+#[test]
+fn list_while_unlinking() {
+    check_list_while_unlinking(_create_new_sfs().root_inode());
+}
+
+#[test]
+fn create_with_content_never_empty() {
+    check_create_with_content_never_empty(_create_new_sfs().root_inode());
+}
@@ -21,7 +21,9 @@ use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem;
 use core::ops::Deref;
+use core::ptr;
 use core::slice;
+use rcore_fs::dev::blocked_device::{BlockedDevice, BlockedDeviceAdapter};
 use rcore_fs::{sfs, vfs};
 use spin::Mutex;
 
@@ -114,7 +116,7 @@ pub extern fn sfs_do_mount(dev: *mut Device, fs_store: &mut *mut Fs) -> ErrorCod
     debug_assert!(!dev.is_null());
     let mut device = unsafe { Box::from_raw(dev) };  // TODO: fix unsafe
     device.open();
-    let sfs = sfs::SimpleFileSystem::open(device).unwrap();
+    let sfs = sfs::SimpleFileSystem::open(Arc::new(BlockedDeviceAdapter::new(*device))).unwrap();
     // `fs.fs` is uninitialized, so it must be `replace` out and `forget`
     mem::forget(mem::replace(unsafe { &mut (*fs).fs }, sfs));
     *fs_store = fs;
@@ -350,7 +352,7 @@ impl IoBuf {
     }
 }
 
-impl vfs::BlockedDevice for Device {
+impl BlockedDevice for Device {
     const BLOCK_SIZE_LOG2: u8 = 12;
 
     fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
@@ -574,19 +576,39 @@ static FS_OPS: FsOps = {
 };
 
 /// Allocator supported by ucore functions
+///
+/// `kmalloc` makes no promise on alignment, so each block is over-allocated and aligned,
+/// with the pointer returned by `kmalloc` stored right before it for `kfree`.
 pub struct UcoreAllocator;
 
 #[global_allocator]
 pub static UCORE_ALLOCATOR: UcoreAllocator = UcoreAllocator;
 
+/// Size of the header before each block, holding the pointer from `kmalloc`
+const HEADER_SIZE: usize = mem::size_of::<*mut u8>();
+
 unsafe impl GlobalAlloc for UcoreAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
 //        cprintf!("alloc %d\n", layout.size());
-        ucore::kmalloc(layout.size())
+        let align = layout.align();
+        let size = match layout.size().checked_add(HEADER_SIZE + align - 1) {
+            Some(size) => size,
+            None => return ptr::null_mut(),
+        };
+        let raw = ucore::kmalloc(size);
+        // let the OOM handler fire, instead of using a null block
+        if raw.is_null() {
+            return ptr::null_mut();
+        }
+        let addr = (raw as usize + HEADER_SIZE + align - 1) & !(align - 1);
+        let block = raw.add(addr - raw as usize);
+        (block.sub(HEADER_SIZE) as *mut *mut u8).write_unaligned(raw);
+        block
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
 //        cprintf!("free %d\n", layout.size());
-        ucore::kfree(ptr);
+        let raw = (ptr.sub(HEADER_SIZE) as *mut *mut u8).read_unaligned();
+        ucore::kfree(raw);
     }
 }
 
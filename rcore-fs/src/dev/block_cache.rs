@@ -75,7 +75,9 @@ impl<T: BlockDevice> BlockCache<T> {
     /// Write back data if buffer is dirty
     fn write_back(&self, buf: &mut Buf) -> Result<()> {
         if let BufStatus::Dirty(block_id) = buf.status {
-            self.device.write_at(block_id, &buf.data)?;
+            self.device
+                .write_at(block_id, &buf.data)
+                .map_err(|e| e.at(block_id << T::BLOCK_SIZE_LOG2, buf.data.len()))?;
             buf.status = BufStatus::Valid(block_id);
         }
         Ok(())
@@ -112,12 +114,44 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     }
 
     fn sync(&self) -> Result<()> {
+        let sync_failed = |e: DevError| e.with_kind(DevErrorKind::SyncFailed);
         for buf in self.bufs.iter() {
-            self.write_back(&mut buf.lock())?;
+            self.write_back(&mut buf.lock()).map_err(sync_failed)?;
         }
-        self.device.sync()?;
+        self.device.sync().map_err(sync_failed)?;
         Ok(())
     }
+
+    fn block_count(&self) -> Option<usize> {
+        self.device.block_count()
+    }
+
+    /// Evict the block on `DontNeed`, load it on `WillNeed`
+    fn fadvise(&self, block_id: BlockId, advice: Advice) -> Result<()> {
+        match advice {
+            Advice::DontNeed => {
+                for buf in self.bufs.iter() {
+                    let mut buf = buf.lock();
+                    match buf.status {
+                        BufStatus::Valid(id) | BufStatus::Dirty(id) if id == block_id => {
+                            self.write_back(&mut buf)?;
+                            buf.status = BufStatus::Unused;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Advice::WillNeed => {
+                let mut buf = self.get_buf(block_id);
+                if let BufStatus::Unused = buf.status {
+                    self.device.read_at(block_id, &mut buf.data)?;
+                    buf.status = BufStatus::Valid(block_id);
+                }
+            }
+            _ => {}
+        }
+        self.device.fadvise(block_id, advice)
+    }
 }
 
 /// Doubly circular linked list LRU manager
@@ -160,3 +194,55 @@ impl LRU {
         self.prev[head] = id;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    /// A device of 4-byte blocks, failing to write block 2 while `broken`
+    struct BrokenDevice {
+        data: Mutex<[u8; 16]>,
+        broken: AtomicBool,
+    }
+
+    impl BlockDevice for BrokenDevice {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.data.lock()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            if block_id == 2 && self.broken.load(Ordering::SeqCst) {
+                return Err(DevError::new(DevErrorKind::WriteFailed));
+            }
+            let begin = block_id << 2;
+            self.data.lock()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sync_error_location() {
+        let cache = BlockCache::new(
+            BrokenDevice {
+                data: Mutex::new([0; 16]),
+                broken: AtomicBool::new(true),
+            },
+            4,
+        );
+        BlockDevice::write_at(&cache, 1, &[1; 4]).unwrap();
+        BlockDevice::write_at(&cache, 2, &[2; 4]).unwrap();
+        let err = BlockDevice::sync(&cache).unwrap_err();
+        assert_eq!(err.kind, DevErrorKind::SyncFailed);
+        assert_eq!(err.location, Some((8, 4)));
+
+        cache.device.broken.store(false, Ordering::SeqCst);
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(cache.device.data.lock()[4..12], [1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+}
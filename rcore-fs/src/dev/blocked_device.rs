@@ -0,0 +1,105 @@
+//! An adapter for devices of the old `BlockedDevice` interface, e.g. those provided by ucore
+use super::*;
+use spin::Mutex;
+
+/// The old interface of a device R/W in whole blocks, by `&mut self`,
+/// reporting only whether an operation succeeded
+pub trait BlockedDevice: Send {
+    const BLOCK_SIZE_LOG2: u8;
+    fn read_at(&mut self, block_id: BlockId, buf: &mut [u8]) -> bool;
+    fn write_at(&mut self, block_id: BlockId, buf: &[u8]) -> bool;
+}
+
+/// Wraps a `BlockedDevice` as a `BlockDevice`, and so a `Device` R/W in bytes.
+/// Operations are serialized by a lock. Writes are taken as durable once done,
+/// so `sync` does nothing.
+pub struct BlockedDeviceAdapter<T: BlockedDevice>(Mutex<T>);
+
+impl<T: BlockedDevice> BlockedDeviceAdapter<T> {
+    pub fn new(device: T) -> Self {
+        BlockedDeviceAdapter(Mutex::new(device))
+    }
+}
+
+impl<T: BlockedDevice> BlockDevice for BlockedDeviceAdapter<T> {
+    const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
+
+    fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+        match self.0.lock().read_at(block_id, buf) {
+            true => Ok(()),
+            false => Err(DevError::new(DevErrorKind::ReadFailed).at(
+                block_id << Self::BLOCK_SIZE_LOG2,
+                1 << Self::BLOCK_SIZE_LOG2,
+            )),
+        }
+    }
+
+    fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+        match self.0.lock().write_at(block_id, buf) {
+            true => Ok(()),
+            false => Err(DevError::new(DevErrorKind::WriteFailed).at(
+                block_id << Self::BLOCK_SIZE_LOG2,
+                1 << Self::BLOCK_SIZE_LOG2,
+            )),
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A memory device of 4 blocks of 4 KB, like a ucore disk
+    struct MemBlockedDevice {
+        data: Vec<u8>,
+        block_ops: usize,
+    }
+
+    impl BlockedDevice for MemBlockedDevice {
+        const BLOCK_SIZE_LOG2: u8 = 12;
+        fn read_at(&mut self, block_id: BlockId, buf: &mut [u8]) -> bool {
+            if block_id >= 4 {
+                return false;
+            }
+            self.block_ops += 1;
+            buf[..4096].copy_from_slice(&self.data[block_id * 4096..(block_id + 1) * 4096]);
+            true
+        }
+        fn write_at(&mut self, block_id: BlockId, buf: &[u8]) -> bool {
+            if block_id >= 4 {
+                return false;
+            }
+            self.block_ops += 1;
+            self.data[block_id * 4096..(block_id + 1) * 4096].copy_from_slice(&buf[..4096]);
+            true
+        }
+    }
+
+    #[test]
+    fn read_write_bytes() {
+        let dev = BlockedDeviceAdapter::new(MemBlockedDevice {
+            data: vec![0; 4 * 4096],
+            block_ops: 0,
+        });
+        let data: Vec<u8> = (0..8192).map(|i| i as u8).collect();
+        // partial head and tail blocks, a full one between
+        assert_eq!(Device::write_at(&dev, 4000, &data), Ok(8192));
+        // a read and a write per partial block, a write for the full one
+        assert_eq!(dev.0.lock().block_ops, 5);
+
+        let mut buf = vec![0u8; 8192 + 200];
+        assert_eq!(Device::read_at(&dev, 3900, &mut buf), Ok(8192 + 200));
+        assert_eq!(buf[..100], [0; 100]);
+        assert_eq!(buf[100..8192 + 100], data[..]);
+        assert_eq!(buf[8192 + 100..], [0; 100]);
+
+        // short beyond the end
+        let mut buf = [0u8; 16];
+        assert_eq!(Device::read_at(&dev, 4 * 4096 - 8, &mut buf), Ok(8));
+        assert_eq!(Device::write_at(&dev, 4 * 4096, &buf), Ok(0));
+    }
+}
@@ -0,0 +1,244 @@
+//! A single-block read-modify-write buffer layer for `Device`
+use super::*;
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+/// Wraps a `Device`, keeping the most recently accessed block in memory.
+///
+/// Small accesses within the buffered block are served from memory,
+/// the block is only written back when another block is accessed or on `sync`.
+pub struct BufDevice<D: Device> {
+    device: D,
+    block_size_log2: u8,
+    buf: Mutex<Buf>,
+}
+
+struct Buf {
+    /// the block currently held in `data`
+    block: Option<BlockId>,
+    /// `data` has been modified since it was loaded
+    dirty: bool,
+    /// number of valid bytes in `data`, less than a block at the end of device
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl<D: Device> BufDevice<D> {
+    pub fn new(device: D, block_size_log2: u8) -> Self {
+        BufDevice {
+            device,
+            block_size_log2,
+            buf: Mutex::new(Buf {
+                block: None,
+                dirty: false,
+                len: 0,
+                data: vec![0; 1 << block_size_log2 as usize],
+            }),
+        }
+    }
+
+    /// Make `block_id` the buffered block, writing back the old one if dirty
+    fn switch_to(&self, buf: &mut Buf, block_id: BlockId) -> Result<()> {
+        if buf.block == Some(block_id) {
+            return Ok(());
+        }
+        self.write_back(buf)?;
+        // `data` is about to be overwritten, keep no block paired with it if the read fails
+        buf.block = None;
+        buf.dirty = false;
+        let len = self
+            .device
+            .read_at(block_id << self.block_size_log2, &mut buf.data)?;
+        buf.data[len..].fill(0);
+        buf.block = Some(block_id);
+        buf.len = len;
+        Ok(())
+    }
+
+    /// Write back data if buffer is dirty
+    fn write_back(&self, buf: &mut Buf) -> Result<()> {
+        if let (Some(block_id), true) = (buf.block, buf.dirty) {
+            let offset = block_id << self.block_size_log2;
+            let len = self
+                .device
+                .write_at(offset, &buf.data[..buf.len])
+                .map_err(|e| e.at(offset, buf.len))?;
+            if len != buf.len {
+                return Err(
+                    DevError::new(DevErrorKind::WriteFailed).at(offset + len, buf.len - len)
+                );
+            }
+            buf.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Device> Drop for BufDevice<D> {
+    /// Write back when drop. Errors are only logged, as panicking here may abort.
+    fn drop(&mut self) {
+        if let Err(e) = Device::sync(self) {
+            error!("failed to sync when dropping the BufDevice: {:?}", e);
+        }
+    }
+}
+
+impl<D: Device> Device for BufDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: self.block_size_log2,
+        };
+        let mut block_buf = self.buf.lock();
+
+        // For each block
+        for range in iter {
+            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            self.switch_to(&mut block_buf, range.block)?;
+            if range.begin >= block_buf.len {
+                return Ok(range.origin_begin() - offset);
+            }
+            let end = range.end.min(block_buf.len);
+            buf[..end - range.begin].copy_from_slice(&block_buf.data[range.begin..end]);
+            if end < range.end {
+                return Ok(range.origin_begin() - offset + end - range.begin);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: self.block_size_log2,
+        };
+        let mut block_buf = self.buf.lock();
+
+        // For each block
+        for range in iter {
+            let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            self.switch_to(&mut block_buf, range.block)?;
+            block_buf.data[range.begin..range.end].copy_from_slice(buf);
+            block_buf.len = block_buf.len.max(range.end);
+            block_buf.dirty = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        let sync_failed = |e: DevError| e.with_kind(DevErrorKind::SyncFailed);
+        self.write_back(&mut self.buf.lock()).map_err(sync_failed)?;
+        self.device.sync().map_err(sync_failed)
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.device.size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// A memory device counting the writes reaching it
+    struct CountingDevice {
+        data: Mutex<Vec<u8>>,
+        writes: AtomicUsize,
+        /// fail reads, after scribbling over the buffer
+        fail_reads: AtomicBool,
+    }
+
+    impl Device for CountingDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            if self.fail_reads.load(Ordering::SeqCst) {
+                buf.fill(0xff);
+                return Err(DevError::new(DevErrorKind::ReadFailed));
+            }
+            let data = self.data.lock().unwrap();
+            let begin = data.len().min(offset);
+            let end = data.len().min(offset + buf.len());
+            buf[..end - begin].copy_from_slice(&data[begin..end]);
+            Ok(end - begin)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            let mut data = self.data.lock().unwrap();
+            if data.len() < offset + buf.len() {
+                data.resize(offset + buf.len(), 0);
+            }
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_device() -> BufDevice<CountingDevice> {
+        BufDevice::new(
+            CountingDevice {
+                data: Mutex::new(vec![0; 64]),
+                writes: AtomicUsize::new(0),
+                fail_reads: AtomicBool::new(false),
+            },
+            4,
+        )
+    }
+
+    #[test]
+    fn small_writes_in_one_block() {
+        let dev = new_device();
+        for i in 0..100 {
+            assert_eq!(Device::write_at(&dev, 16 + i % 16, &[i as u8]), Ok(1));
+        }
+        assert_eq!(dev.device.writes.load(Ordering::SeqCst), 0);
+        Device::sync(&dev).unwrap();
+        assert_eq!(dev.device.writes.load(Ordering::SeqCst), 1);
+
+        let mut res = [0u8; 16];
+        assert_eq!(Device::read_at(&dev, 16, &mut res), Ok(16));
+        assert_eq!(
+            res,
+            [96, 97, 98, 99, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95]
+        );
+    }
+
+    #[test]
+    fn switch_block() {
+        let dev = new_device();
+        Device::write_at(&dev, 3, &[1, 2, 3]).unwrap();
+        Device::write_at(&dev, 35, &[4]).unwrap();
+        assert_eq!(dev.device.writes.load(Ordering::SeqCst), 1);
+
+        // partly inside
+        let mut res = [0u8; 6];
+        assert_eq!(Device::read_at(&dev, 62, &mut res), Ok(2));
+        assert_eq!(Device::read_at(&dev, 2, &mut res), Ok(6));
+        assert_eq!(res, [0, 1, 2, 3, 0, 0]);
+    }
+
+    #[test]
+    fn failed_switch() {
+        let dev = new_device();
+        Device::write_at(&dev, 3, &[1, 2, 3]).unwrap();
+        dev.device.fail_reads.store(true, Ordering::SeqCst);
+        let mut res = [0u8; 6];
+        assert!(Device::read_at(&dev, 32, &mut res).is_err());
+        assert_eq!(dev.device.writes.load(Ordering::SeqCst), 1);
+
+        // the old block is read back from the device, not from the clobbered buffer
+        dev.device.fail_reads.store(false, Ordering::SeqCst);
+        assert_eq!(Device::read_at(&dev, 2, &mut res), Ok(6));
+        assert_eq!(res, [0, 1, 2, 3, 0, 0]);
+        Device::sync(&dev).unwrap();
+        assert_eq!(dev.device.writes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,18 +1,86 @@
-use crate::{util::*, vfs::Timespec};
+use crate::{
+    util::*,
+    vfs::{Advice, Timespec},
+};
+use alloc::vec;
 
 pub mod block_cache;
+pub mod blocked_device;
+pub mod buf_device;
+pub mod retry_device;
 pub mod std_impl;
+pub mod verify_device;
 
 /// A current time provider
 pub trait TimeProvider: Send + Sync {
     fn current_time(&self) -> Timespec;
 }
 
+/// A `TimeProvider` always returning the epoch, for platforms without a clock
+pub struct ZeroTimeProvider;
+
+impl TimeProvider for ZeroTimeProvider {
+    fn current_time(&self) -> Timespec {
+        Timespec { sec: 0, nsec: 0 }
+    }
+}
+
+/// A `TimeProvider` usable in `no_std`, driven by the platform (e.g. on timer interrupts).
+/// The time it returns never goes backwards.
+pub struct MonotonicTimeProvider {
+    now: spin::Mutex<Timespec>,
+}
+
+impl MonotonicTimeProvider {
+    pub const fn new(start: Timespec) -> Self {
+        MonotonicTimeProvider {
+            now: spin::Mutex::new(start),
+        }
+    }
+
+    /// Set the current time, ignored if it is earlier than the current one
+    pub fn set(&self, time: Timespec) {
+        let mut now = self.now.lock();
+        if time > *now {
+            *now = time;
+        }
+    }
+
+    /// Advance the current time by `nsec` nanoseconds
+    pub fn advance(&self, nsec: u64) {
+        let mut now = self.now.lock();
+        let nsec = now.nsec as u64 + nsec;
+        now.sec += (nsec / 1_000_000_000) as i64;
+        now.nsec = (nsec % 1_000_000_000) as i32;
+    }
+}
+
+impl TimeProvider for MonotonicTimeProvider {
+    fn current_time(&self) -> Timespec {
+        *self.now.lock()
+    }
+}
+
 /// Interface for FS to read & write
+///
+/// File systems take it as `Arc<dyn Device>`, so a read-only device may be shared,
+/// e.g. to mount the same image at several points.
 pub trait Device: Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
     fn sync(&self) -> Result<()>;
+    /// Hint the expected access pattern of bytes `offset..offset + len`
+    fn fadvise(&self, _offset: usize, _len: usize, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+    /// Size of the device in bytes, if known
+    fn size(&self) -> Option<usize> {
+        None
+    }
+    /// Whether the device rejects writes, e.g. a file opened without write access
+    fn is_read_only(&self) -> bool {
+        false
+    }
 }
 
 /// Device which can only R/W in blocks
@@ -21,11 +89,54 @@ pub trait BlockDevice: Send + Sync {
     fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()>;
     fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()>;
     fn sync(&self) -> Result<()>;
+    /// Hint the expected access pattern of the block
+    fn fadvise(&self, _block_id: BlockId, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+    /// Number of blocks of the device, if known
+    fn block_count(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// The error type for device.
-#[derive(Debug, PartialEq, Eq)]
-pub struct DevError;
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DevError {
+    pub kind: DevErrorKind,
+    /// The failing bytes `offset..offset + len` of the device as `(offset, len)`, if known
+    pub location: Option<(usize, usize)>,
+}
+
+/// The operation a device failed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DevErrorKind {
+    #[default]
+    Other,
+    ReadFailed,
+    WriteFailed,
+    SyncFailed,
+}
+
+impl DevError {
+    pub const fn new(kind: DevErrorKind) -> Self {
+        DevError {
+            kind,
+            location: None,
+        }
+    }
+
+    /// Set the failing bytes, unless already set by a lower layer
+    pub fn at(mut self, offset: usize, len: usize) -> Self {
+        self.location.get_or_insert((offset, len));
+        self
+    }
+
+    /// Set the failing operation
+    pub fn with_kind(mut self, kind: DevErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+}
 
 /// A specialized `Result` type for device.
 pub type Result<T> = core::result::Result<T, DevError>;
@@ -57,8 +168,7 @@ impl<T: BlockDevice> Device for T {
                 // Read to target buf directly
                 try0!(len, BlockDevice::read_at(self, range.block, buf));
             } else {
-                let mut block_buf: [u8; 1 << 10] = unsafe { uninit_memory() };
-                assert!(Self::BLOCK_SIZE_LOG2 <= 10);
+                let mut block_buf = vec![0u8; 1 << Self::BLOCK_SIZE_LOG2];
                 // Read to local buf first
                 try0!(len, BlockDevice::read_at(self, range.block, &mut block_buf));
                 // Copy to target buf then
@@ -83,8 +193,7 @@ impl<T: BlockDevice> Device for T {
                 // Write to target buf directly
                 try0!(len, BlockDevice::write_at(self, range.block, buf));
             } else {
-                let mut block_buf: [u8; 1 << 10] = unsafe { uninit_memory() };
-                assert!(Self::BLOCK_SIZE_LOG2 <= 10);
+                let mut block_buf = vec![0u8; 1 << Self::BLOCK_SIZE_LOG2];
                 // Read to local buf first
                 try0!(len, BlockDevice::read_at(self, range.block, &mut block_buf));
                 // Write to local buf
@@ -99,6 +208,23 @@ impl<T: BlockDevice> Device for T {
     fn sync(&self) -> Result<()> {
         BlockDevice::sync(self)
     }
+
+    fn fadvise(&self, offset: usize, len: usize, advice: Advice) -> Result<()> {
+        let end = offset.saturating_add(len);
+        let iter = BlockIter {
+            begin: offset,
+            end: Device::size(self).map_or(end, |size| end.min(size)),
+            block_size_log2: Self::BLOCK_SIZE_LOG2,
+        };
+        for range in iter {
+            BlockDevice::fadvise(self, range.block, advice)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.block_count()? << Self::BLOCK_SIZE_LOG2)
+    }
 }
 
 #[cfg(test)]
@@ -110,7 +236,7 @@ mod test {
         const BLOCK_SIZE_LOG2: u8 = 2;
         fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::new(DevErrorKind::ReadFailed));
             }
             let begin = block_id << 2;
             buf[..4].copy_from_slice(&self.lock().unwrap()[begin..begin + 4]);
@@ -118,7 +244,7 @@ mod test {
         }
         fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::new(DevErrorKind::WriteFailed));
             }
             let begin = block_id << 2;
             self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
@@ -127,6 +253,22 @@ mod test {
         fn sync(&self) -> Result<()> {
             Ok(())
         }
+        fn fadvise(&self, block_id: BlockId, _advice: Advice) -> Result<()> {
+            assert!(block_id < 4);
+            Ok(())
+        }
+        fn block_count(&self) -> Option<usize> {
+            Some(4)
+        }
+    }
+
+    #[test]
+    fn fadvise() {
+        let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+        // clamped to the device instead of overflowing
+        let ret = Device::fadvise(&buf, 5, usize::MAX, Advice::WillNeed);
+        assert_eq!(ret, Ok(()));
+        assert_eq!(Device::size(&buf), Some(16));
     }
 
     #[test]
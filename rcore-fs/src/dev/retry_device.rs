@@ -0,0 +1,155 @@
+//! A layer for `Device` retrying failed operations, for flaky or network-backed storage
+use super::*;
+use alloc::boxed::Box;
+
+/// Wraps a `Device`, retrying a failed read, write or sync up to `retries` more times
+/// before returning the last error.
+///
+/// `backoff` is called with the number of failed attempts so far before each retry,
+/// e.g. to sleep for a while.
+pub struct RetryingDevice<D: Device> {
+    device: D,
+    retries: usize,
+    backoff: Box<dyn Fn(usize) + Send + Sync>,
+}
+
+impl<D: Device> RetryingDevice<D> {
+    pub fn new(device: D, retries: usize, backoff: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        RetryingDevice {
+            device,
+            retries,
+            backoff: Box::new(backoff),
+        }
+    }
+
+    /// Run `op` until it succeeds or has failed `retries + 1` times
+    fn retry<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut failures = 0;
+        loop {
+            match op() {
+                Err(_) if failures < self.retries => {
+                    failures += 1;
+                    (self.backoff)(failures);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<D: Device> Device for RetryingDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.retry(|| self.device.read_at(offset, buf))
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.retry(|| self.device.write_at(offset, buf))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.retry(|| self.device.sync())
+    }
+
+    fn fadvise(&self, offset: usize, len: usize, advice: Advice) -> Result<()> {
+        self.device.fadvise(offset, len, advice)
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.device.size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// A memory device failing the next `failures` operations
+    struct FlakyDevice {
+        data: Mutex<Vec<u8>>,
+        failures: AtomicUsize,
+    }
+
+    impl FlakyDevice {
+        fn fail(&self, kind: DevErrorKind) -> Result<()> {
+            match self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err(DevError::new(kind)),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    impl Device for FlakyDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            self.fail(DevErrorKind::ReadFailed)?;
+            let data = self.data.lock().unwrap();
+            let begin = data.len().min(offset);
+            let end = data.len().min(offset + buf.len());
+            buf[..end - begin].copy_from_slice(&data[begin..end]);
+            Ok(end - begin)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            self.fail(DevErrorKind::WriteFailed)?;
+            let mut data = self.data.lock().unwrap();
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            self.fail(DevErrorKind::SyncFailed)
+        }
+    }
+
+    fn new_device(retries: usize) -> (RetryingDevice<FlakyDevice>, Arc<Mutex<Vec<usize>>>) {
+        let backoffs = Arc::new(Mutex::new(Vec::new()));
+        let log = backoffs.clone();
+        let dev = RetryingDevice::new(
+            FlakyDevice {
+                data: Mutex::new(vec![0; 64]),
+                failures: AtomicUsize::new(0),
+            },
+            retries,
+            move |failures| log.lock().unwrap().push(failures),
+        );
+        (dev, backoffs)
+    }
+
+    #[test]
+    fn retry_transient_failures() {
+        let (dev, backoffs) = new_device(2);
+        dev.device.failures.store(2, Ordering::SeqCst);
+        assert_eq!(dev.write_at(8, &[1; 8]), Ok(8));
+        assert_eq!(*backoffs.lock().unwrap(), [1, 2]);
+
+        dev.device.failures.store(2, Ordering::SeqCst);
+        let mut buf = [0u8; 8];
+        assert_eq!(dev.read_at(8, &mut buf), Ok(8));
+        assert_eq!(buf, [1; 8]);
+
+        dev.device.failures.store(2, Ordering::SeqCst);
+        assert_eq!(dev.sync(), Ok(()));
+        assert_eq!(backoffs.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn give_up_after_retries() {
+        let (dev, backoffs) = new_device(1);
+        dev.device.failures.store(2, Ordering::SeqCst);
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            dev.read_at(0, &mut buf),
+            Err(DevError::new(DevErrorKind::ReadFailed))
+        );
+        assert_eq!(*backoffs.lock().unwrap(), [1]);
+        // the next attempt goes through
+        assert_eq!(dev.read_at(0, &mut buf), Ok(8));
+    }
+}
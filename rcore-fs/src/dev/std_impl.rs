@@ -2,6 +2,7 @@
 
 use std::fs::File;
 use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -29,6 +30,28 @@ impl Device for Mutex<File> {
         file.sync_all()?;
         Ok(())
     }
+
+    fn size(&self) -> Option<usize> {
+        let file = self.lock().unwrap();
+        Some(file.metadata().ok()?.len() as usize)
+    }
+
+    fn is_read_only(&self) -> bool {
+        is_read_only(&self.lock().unwrap())
+    }
+}
+
+/// Whether `file` was opened without write access
+#[cfg(unix)]
+fn is_read_only(file: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    flags >= 0 && flags & libc::O_ACCMODE == libc::O_RDONLY
+}
+
+#[cfg(not(unix))]
+fn is_read_only(_file: &File) -> bool {
+    false
 }
 
 pub struct StdTimeProvider;
@@ -43,8 +66,260 @@ impl TimeProvider for StdTimeProvider {
     }
 }
 
+/// A `Device` over a file counting the calls reaching it, with switches to make them fail.
+///
+/// For testing how a file system copes with its device.
+pub struct TestDevice {
+    file: Mutex<File>,
+    pub reads: AtomicUsize,
+    pub writes: AtomicUsize,
+    pub syncs: AtomicUsize,
+    pub fail_reads: AtomicBool,
+    pub fail_writes: AtomicBool,
+    pub fail_syncs: AtomicBool,
+    /// If not 0, `cancel` is set once this many more writes reached the device
+    pub writes_left: AtomicUsize,
+    pub cancel: AtomicBool,
+    /// If not 0, `fail_writes` is set when this many more writes reach the device,
+    /// failing the last of them
+    pub fail_writes_after: AtomicUsize,
+}
+
+impl TestDevice {
+    pub fn new(file: File) -> Self {
+        TestDevice {
+            file: Mutex::new(file),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            syncs: AtomicUsize::new(0),
+            fail_reads: AtomicBool::new(false),
+            fail_writes: AtomicBool::new(false),
+            fail_syncs: AtomicBool::new(false),
+            writes_left: AtomicUsize::new(0),
+            cancel: AtomicBool::new(false),
+            fail_writes_after: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Device for TestDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        if self.fail_reads.load(Ordering::SeqCst) {
+            return Err(DevError::new(DevErrorKind::ReadFailed).at(offset, buf.len()));
+        }
+        self.file.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        let left = self
+            .writes_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if left == Ok(1) {
+            self.cancel.store(true, Ordering::SeqCst);
+        }
+        let left = self
+            .fail_writes_after
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if left == Ok(1) {
+            self.fail_writes.store(true, Ordering::SeqCst);
+        }
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(DevError::new(DevErrorKind::WriteFailed).at(offset, buf.len()));
+        }
+        self.file.write_at(offset, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        if self.fail_syncs.load(Ordering::SeqCst) {
+            return Err(DevError::new(DevErrorKind::SyncFailed));
+        }
+        self.file.sync()
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.file.size()
+    }
+}
+
 impl From<Error> for DevError {
     fn from(_: Error) -> Self {
-        DevError
+        DevError::default()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::block_file::BlockFileDevice;
+
+/// Raw host block device, e.g. `/dev/sdX`
+#[cfg(target_os = "linux")]
+mod block_file {
+    use super::*;
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    /// Alignment of buffer, offset and length required by `O_DIRECT`
+    const ALIGN: usize = 4096;
+    /// `_IOR(0x12, 114, size_t)`
+    const BLKGETSIZE64: u64 = 0x8008_1272;
+
+    /// A host block device opened with `O_DIRECT`, bypassing the host page cache.
+    ///
+    /// Unaligned accesses are handled by read-modify-write of the aligned range.
+    pub struct BlockFileDevice {
+        file: File,
+        size: usize,
+        /// serialize read-modify-write of partial blocks
+        write_lock: Mutex<()>,
+    }
+
+    impl BlockFileDevice {
+        pub fn open(path: impl AsRef<Path>, writable: bool) -> std::io::Result<Self> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)?;
+            let metadata = file.metadata()?;
+            let size = if metadata.file_type().is_block_device() {
+                let mut size: u64 = 0;
+                let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) };
+                if ret < 0 {
+                    return Err(Error::last_os_error());
+                }
+                size as usize
+            } else {
+                metadata.len() as usize
+            };
+            Ok(BlockFileDevice {
+                file,
+                size,
+                write_lock: Mutex::new(()),
+            })
+        }
+
+        /// Size of the device in bytes
+        pub fn size_hint(&self) -> usize {
+            self.size
+        }
+
+        /// Read the aligned range covering `begin..end` into a new buffer
+        fn read_aligned(&self, begin: usize, end: usize) -> Result<AlignedBuf> {
+            let begin = begin / ALIGN * ALIGN;
+            let end = end.div_ceil(ALIGN) * ALIGN;
+            let mut buf = AlignedBuf::new(end - begin);
+            let mut len = 0;
+            while begin + len < end.min(self.size) {
+                match self.file.read_at(&mut buf[len..], (begin + len) as u64)? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            Ok(buf)
+        }
+    }
+
+    impl Device for BlockFileDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let end = self.size.min(offset + buf.len());
+            if offset >= end {
+                return Ok(0);
+            }
+            let aligned = self.read_aligned(offset, end)?;
+            let skip = offset % ALIGN;
+            buf[..end - offset].copy_from_slice(&aligned[skip..skip + end - offset]);
+            Ok(end - offset)
+        }
+
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let end = self.size.min(offset + buf.len());
+            if offset >= end {
+                return Ok(0);
+            }
+            let _guard = self.write_lock.lock().unwrap();
+            let mut aligned = self.read_aligned(offset, end)?;
+            let skip = offset % ALIGN;
+            aligned[skip..skip + end - offset].copy_from_slice(&buf[..end - offset]);
+            let begin = offset / ALIGN * ALIGN;
+            self.file.write_all_at(&aligned, begin as u64)?;
+            Ok(end - offset)
+        }
+
+        fn sync(&self) -> Result<()> {
+            self.file.sync_all()?;
+            Ok(())
+        }
+
+        fn size(&self) -> Option<usize> {
+            Some(self.size)
+        }
+
+        fn is_read_only(&self) -> bool {
+            is_read_only(&self.file)
+        }
+    }
+
+    /// Heap buffer aligned to `ALIGN`
+    struct AlignedBuf {
+        ptr: *mut u8,
+        layout: Layout,
+    }
+
+    impl AlignedBuf {
+        fn new(len: usize) -> Self {
+            let layout = Layout::from_size_align(len.max(ALIGN), ALIGN).unwrap();
+            let ptr = unsafe { alloc_zeroed(layout) };
+            assert!(!ptr.is_null(), "failed to allocate aligned buffer");
+            AlignedBuf { ptr, layout }
+        }
+    }
+
+    impl core::ops::Deref for AlignedBuf {
+        type Target = [u8];
+        fn deref(&self) -> &[u8] {
+            unsafe { core::slice::from_raw_parts(self.ptr, self.layout.size()) }
+        }
+    }
+
+    impl core::ops::DerefMut for AlignedBuf {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { core::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+        }
+    }
+
+    impl Drop for AlignedBuf {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.ptr, self.layout) }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        /// Run with a scratch loop device, e.g.
+        /// `LOOP_DEVICE=/dev/loop0 cargo test -- --ignored`
+        #[test]
+        #[ignore]
+        fn loop_device() {
+            let path = std::env::var("LOOP_DEVICE").unwrap_or_else(|_| "/dev/loop0".into());
+            let dev = BlockFileDevice::open(path, true).expect("failed to open loop device");
+            assert!(dev.size_hint() >= 2 * ALIGN);
+
+            let data: Vec<u8> = (0..100).collect();
+            assert_eq!(dev.write_at(ALIGN - 10, &data), Ok(100));
+            let mut buf = [0u8; 100];
+            assert_eq!(dev.read_at(ALIGN - 10, &mut buf), Ok(100));
+            assert_eq!(&buf[..], &data[..]);
+
+            let mut buf = [0u8; 10];
+            assert_eq!(dev.read_at(dev.size_hint() - 4, &mut buf), Ok(4));
+            dev.sync().unwrap();
+        }
     }
 }
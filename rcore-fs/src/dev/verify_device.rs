@@ -0,0 +1,130 @@
+//! A layer for `Device` reading back writes to detect silent failures
+use super::*;
+use alloc::vec;
+
+/// Wraps a `Device`, optionally reading back every write and comparing it to what was written,
+/// for environments not trusting the storage underneath (e.g. SGX).
+///
+/// Verifying costs a read per write, so it is only done if enabled by `new`.
+pub struct VerifyingDevice<D: Device> {
+    device: D,
+    verify_writes: bool,
+}
+
+impl<D: Device> VerifyingDevice<D> {
+    pub fn new(device: D, verify_writes: bool) -> Self {
+        VerifyingDevice {
+            device,
+            verify_writes,
+        }
+    }
+
+    /// Check bytes `offset..offset + buf.len()` of the device hold `buf`
+    fn verify(&self, offset: usize, buf: &[u8]) -> Result<()> {
+        let mut readback = vec![0u8; buf.len()];
+        let len = self
+            .device
+            .read_at(offset, &mut readback)
+            .map_err(|e| e.at(offset, buf.len()))?;
+        let mismatch = readback[..len]
+            .iter()
+            .zip(buf)
+            .position(|(a, b)| a != b)
+            .unwrap_or(len);
+        if mismatch != buf.len() {
+            return Err(DevError::new(DevErrorKind::WriteFailed)
+                .at(offset + mismatch, buf.len() - mismatch));
+        }
+        Ok(())
+    }
+}
+
+impl<D: Device> Device for VerifyingDevice<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.device.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let len = self.device.write_at(offset, buf)?;
+        if self.verify_writes {
+            self.verify(offset, &buf[..len])?;
+        }
+        Ok(len)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()
+    }
+
+    fn fadvise(&self, offset: usize, len: usize, advice: Advice) -> Result<()> {
+        self.device.fadvise(offset, len, advice)
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.device.size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A memory device flipping a bit of the byte at `corrupt_at` when writing it
+    struct CorruptingDevice {
+        data: Mutex<Vec<u8>>,
+        corrupt_at: usize,
+    }
+
+    impl Device for CorruptingDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.data.lock().unwrap();
+            let begin = data.len().min(offset);
+            let end = data.len().min(offset + buf.len());
+            buf[..end - begin].copy_from_slice(&data[begin..end]);
+            Ok(end - begin)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.data.lock().unwrap();
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            if (offset..offset + buf.len()).contains(&self.corrupt_at) {
+                data[self.corrupt_at] ^= 1;
+            }
+            Ok(buf.len())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_device(verify_writes: bool) -> VerifyingDevice<CorruptingDevice> {
+        VerifyingDevice::new(
+            CorruptingDevice {
+                data: Mutex::new(vec![0; 64]),
+                corrupt_at: 40,
+            },
+            verify_writes,
+        )
+    }
+
+    #[test]
+    fn verify_writes() {
+        let dev = new_device(true);
+        assert_eq!(dev.write_at(0, &[1; 32]), Ok(32));
+        assert_eq!(
+            dev.write_at(32, &[2; 16]),
+            Err(DevError::new(DevErrorKind::WriteFailed).at(40, 8))
+        );
+
+        // unnoticed if not verifying
+        let dev = new_device(false);
+        assert_eq!(dev.write_at(32, &[2; 16]), Ok(16));
+        let mut buf = [0u8; 1];
+        assert_eq!(dev.read_at(40, &mut buf), Ok(1));
+        assert_eq!(buf, [3]);
+    }
+}
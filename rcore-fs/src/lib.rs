@@ -1,12 +1,19 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 pub mod dev;
 pub mod dirty;
 pub mod file;
+mod probe;
 pub mod util;
 pub mod vfs;
 
+pub use self::probe::probe;
+
 #[cfg(any(test, feature = "std"))]
 mod std;
+#[cfg(any(test, feature = "std"))]
+pub mod testing;
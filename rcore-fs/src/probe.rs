@@ -0,0 +1,28 @@
+//! Detect the type of file system on a device without mounting it
+
+use crate::dev::Device;
+use alloc::sync::Arc;
+
+/// Known file systems, by the offset and bytes of their magic numbers
+const MAGICS: &[(&str, usize, &[u8])] = &[
+    // u32 at the start of the superblock
+    ("sfs", 0, &[0x2b, 0xbe, 0x8d, 0x2f]),
+    // u32 at the start of the superblock, in the metadata file of a SEFS storage
+    ("sefs", 0, &[0x2a, 0xbe, 0x8d, 0x2f]),
+    // u16 `s_magic` of the superblock at byte 1024
+    ("ext2", 0x438, &[0x53, 0xef]),
+];
+
+/// Identify the file system on `device` by its magic number, e.g. "sfs".
+/// Return `None` if unrecognized, or the device can not be read.
+pub fn probe(device: Arc<dyn Device>) -> Option<&'static str> {
+    let mut buf = [0u8; 4];
+    for &(name, offset, magic) in MAGICS {
+        let buf = &mut buf[..magic.len()];
+        match device.read_at(offset, buf) {
+            Ok(len) if len == magic.len() && buf == magic => return Some(name),
+            _ => {}
+        }
+    }
+    None
+}
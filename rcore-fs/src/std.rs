@@ -18,18 +18,62 @@ impl From<std::io::Error> for FsError {
         use std::io::ErrorKind;
         match e.kind() {
             ErrorKind::NotFound => FsError::EntryNotFound,
-            // We do not have permission in our fs, just ignore the file
-            ErrorKind::PermissionDenied => FsError::EntryNotFound,
+            ErrorKind::PermissionDenied => FsError::PermissionDenied,
             ErrorKind::AlreadyExists => FsError::EntryExist,
             ErrorKind::WouldBlock => FsError::Again,
             ErrorKind::InvalidInput => FsError::InvalidParam,
             ErrorKind::InvalidData => FsError::InvalidParam,
+            ErrorKind::Unsupported => FsError::NotSupported,
+            ErrorKind::NotADirectory => FsError::NotDir,
+            ErrorKind::IsADirectory => FsError::IsDir,
+            ErrorKind::DirectoryNotEmpty => FsError::DirNotEmpty,
+            ErrorKind::StorageFull => FsError::NoDeviceSpace,
+            ErrorKind::CrossesDevices => FsError::NotSameFs,
+            ErrorKind::ResourceBusy => FsError::Busy,
+            ErrorKind::StaleNetworkFileHandle => FsError::StaleFileHandle,
+            ErrorKind::Interrupted => FsError::Interrupted,
+            ErrorKind::ReadOnlyFilesystem => FsError::ReadOnlyFs,
+            ErrorKind::InvalidFilename => FsError::NameTooLong,
+            ErrorKind::TooManyLinks => FsError::TooManyLinks,
+            ErrorKind::ConnectionRefused => FsError::ConnectionRefused,
             // The host fs is the device here
             _ => FsError::DeviceError,
         }
     }
 }
 
+impl From<FsError> for std::io::Error {
+    fn from(e: FsError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match e {
+            FsError::NotSupported => ErrorKind::Unsupported,
+            FsError::IsDir => ErrorKind::IsADirectory,
+            // also returned for sockets, symlinks and devices, not only directories
+            FsError::NotFile => ErrorKind::InvalidInput,
+            FsError::NotDir => ErrorKind::NotADirectory,
+            FsError::EntryNotFound | FsError::DirRemoved => ErrorKind::NotFound,
+            FsError::EntryExist => ErrorKind::AlreadyExists,
+            FsError::NotSameFs => ErrorKind::CrossesDevices,
+            FsError::InvalidParam | FsError::WrongFs => ErrorKind::InvalidInput,
+            FsError::NoDeviceSpace => ErrorKind::StorageFull,
+            FsError::DirNotEmpty => ErrorKind::DirectoryNotEmpty,
+            FsError::Again => ErrorKind::WouldBlock,
+            FsError::Busy => ErrorKind::ResourceBusy,
+            FsError::Interrupted => ErrorKind::Interrupted,
+            FsError::StaleFileHandle => ErrorKind::StaleNetworkFileHandle,
+            FsError::PermissionDenied => ErrorKind::PermissionDenied,
+            FsError::ReadOnlyFs => ErrorKind::ReadOnlyFilesystem,
+            FsError::NameTooLong => ErrorKind::InvalidFilename,
+            FsError::TooManyLinks => ErrorKind::TooManyLinks,
+            FsError::ConnectionRefused => ErrorKind::ConnectionRefused,
+            FsError::SymLoop | FsError::DeviceError | FsError::IOCTLError | FsError::NoDevice => {
+                ErrorKind::Other
+            }
+        };
+        Error::new(kind, e)
+    }
+}
+
 #[cfg(unix)]
 impl From<std::fs::Metadata> for Metadata {
     fn from(m: std::fs::Metadata) -> Self {
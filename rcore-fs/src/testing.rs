@@ -0,0 +1,134 @@
+//! Checks of the behaviors every backend shares, for the tests of each backend.
+//! Each takes the root of a new, empty file system and panics on failure.
+
+use crate::vfs::*;
+use std::sync::Arc;
+
+/// `read_at` returns the bytes available, 0 at and past the end
+pub fn check_short_reads(root: Arc<dyn INode>) {
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    let data: Vec<u8> = (0..100).collect();
+    file.write_at(0, &data).unwrap();
+    let mut buf = [0u8; 50];
+    // fully within
+    assert_eq!(file.read_at(10, &mut buf), Ok(50));
+    assert_eq!(buf[..], data[10..60]);
+    // straddling the end
+    assert_eq!(file.read_at(80, &mut buf), Ok(20));
+    assert_eq!(buf[..20], data[80..]);
+    // at and past the end
+    assert_eq!(file.read_at(100, &mut buf), Ok(0));
+    assert_eq!(file.read_at(4096, &mut buf), Ok(0));
+}
+
+/// Empty names, names with a `/` and names longer than `FsInfo::namemax`
+/// are rejected by `create`, `link` and `move_`
+pub fn check_invalid_names(root: Arc<dyn INode>) {
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    // 0 for no limit
+    let namemax = root.fs().info().namemax;
+    let longest = "x".repeat(match namemax {
+        0 => 1000,
+        namemax => namemax,
+    });
+    let too_long = "x".repeat(namemax + 1);
+    let mut names = vec!["", "a/b"];
+    if namemax != 0 {
+        names.push(&too_long);
+    }
+    for name in names {
+        let err = || match name.len() > namemax && namemax != 0 {
+            true => FsError::NameTooLong,
+            false => FsError::InvalidParam,
+        };
+        assert_eq!(root.create(name, FileType::File, 0o777).err(), Some(err()));
+        assert_eq!(root.link(name, &file), Err(err()));
+        assert_eq!(root.move_("file", &root, name), Err(err()));
+    }
+    assert_eq!(root.list().unwrap(), [".", "..", "file"]);
+    root.create(&longest, FileType::File, 0o777).unwrap();
+}
+
+/// `list` while another thread unlinks the entries holds no name twice,
+/// and skips none still linked
+pub fn check_list_while_unlinking(root: Arc<dyn INode>) {
+    let names: Vec<String> = (0..200).map(|i| format!("file{:03}", i)).collect();
+    for name in names.iter() {
+        root.create(name, FileType::File, 0o777).unwrap();
+    }
+    let unlinker = {
+        let (root, names) = (root.clone(), names.clone());
+        std::thread::spawn(move || {
+            for name in names.iter() {
+                root.unlink(name).unwrap();
+            }
+        })
+    };
+    // removed in order, so each listing holds the names from some point on
+    loop {
+        let done = unlinker.is_finished();
+        let mut list = root.list().unwrap();
+        assert_eq!(&list[..2], [".", ".."]);
+        list.drain(..2);
+        list.sort();
+        assert!(names.ends_with(&list));
+        if done {
+            assert!(list.is_empty());
+            break;
+        }
+    }
+    unlinker.join().unwrap();
+}
+
+/// A file made by `create_with_content` is never seen without its content
+pub fn check_create_with_content_never_empty(root: Arc<dyn INode>) {
+    let data: Vec<u8> = (0..40_000).map(|i| i as u8).collect();
+    let creator = {
+        let (root, data) = (root.clone(), data.clone());
+        std::thread::spawn(move || {
+            for i in 0..20 {
+                let name = format!("file{}", i);
+                root.create_with_content(&name, 0o644, &data).unwrap();
+            }
+        })
+    };
+    for i in 0..20 {
+        let name = format!("file{}", i);
+        let file = loop {
+            let done = creator.is_finished();
+            match root.find(&name) {
+                Ok(file) => break file,
+                Err(e) if done => panic!("{} not created: {:?}", name, e),
+                Err(_) => {}
+            }
+        };
+        assert_eq!(file.metadata().unwrap().size, data.len());
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buf), Ok(data.len()));
+        assert_eq!(buf, data);
+    }
+    creator.join().unwrap();
+    assert_eq!(
+        root.create_with_content("file0", 0o644, b"x").err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(
+        root.find("file0").unwrap().metadata().unwrap().size,
+        data.len()
+    );
+}
+
+/// `write_at` fails on an offset overflowing with the length, writing nothing
+pub fn check_write_at_offset_overflow(root: Arc<dyn INode>) {
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"content").unwrap();
+    assert_eq!(
+        file.write_at(usize::MAX - 1, &[0u8; 8]),
+        Err(FsError::InvalidParam)
+    );
+    // nothing written at a wrapped offset
+    let mut buf = [0u8; 16];
+    assert_eq!(file.read_at(0, &mut buf), Ok(7));
+    assert_eq!(&buf[..7], b"content");
+    assert_eq!(file.metadata().unwrap().size, 7);
+}
@@ -1,3 +1,5 @@
+use crate::vfs::{FsError, Result};
+
 /// Given a range and iterate sub-range for each block
 pub struct BlockIter {
     pub begin: usize,
@@ -70,6 +72,76 @@ pub unsafe fn uninit_memory<T>() -> T {
     core::mem::MaybeUninit::uninit().assume_init()
 }
 
+/// CRC-32 (IEEE 802.3) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// Check `name` can be given to a new entry of a directory:
+/// not empty, without `/`, and at most `namemax` bytes long
+pub fn validate_name(name: &str, namemax: usize) -> Result<()> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidParam);
+    }
+    if name.len() > namemax {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// End of `len` bytes at `offset` in a file, or `InvalidParam` if it overflows,
+/// or is beyond `max_size`, the largest file size of a file system
+pub fn checked_end(offset: usize, len: usize, max_size: usize) -> Result<usize> {
+    match offset.checked_add(len) {
+        Some(end) if end <= max_size => Ok(end),
+        _ => Err(FsError::InvalidParam),
+    }
+}
+
+/// Iterator calling `yield_now` after every `every` items taken from `iter`, e.g. wrapping
+/// the entries of a large `import_tree` to let a cooperative scheduler run other tasks
+pub struct YieldEvery<'a, I> {
+    iter: I,
+    every: usize,
+    /// items taken since the last yield
+    count: usize,
+    yield_now: &'a mut dyn FnMut(),
+}
+
+impl<'a, I: Iterator> YieldEvery<'a, I> {
+    pub fn new(iter: I, every: usize, yield_now: &'a mut dyn FnMut()) -> Self {
+        assert!(every > 0, "must yield every 1 or more items");
+        YieldEvery {
+            iter,
+            every,
+            count: 0,
+            yield_now,
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for YieldEvery<'_, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        // yield before taking more, once the previous items are done with
+        if self.count == self.every {
+            self.count = 0;
+            (self.yield_now)();
+        }
+        let item = self.iter.next()?;
+        self.count += 1;
+        Some(item)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -110,4 +182,28 @@ mod test {
         );
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(super::crc32(b""), 0);
+        assert_eq!(super::crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn validate_name() {
+        assert_eq!(super::validate_name("file", 4), Ok(()));
+        assert_eq!(super::validate_name("", 4), Err(FsError::InvalidParam));
+        assert_eq!(super::validate_name("a/b", 4), Err(FsError::InvalidParam));
+        assert_eq!(super::validate_name("files", 4), Err(FsError::NameTooLong));
+    }
+
+    #[test]
+    fn checked_end() {
+        assert_eq!(super::checked_end(4, 4, 8), Ok(8));
+        assert_eq!(super::checked_end(4, 5, 8), Err(FsError::InvalidParam));
+        assert_eq!(
+            super::checked_end(usize::MAX - 1, 8, usize::MAX),
+            Err(FsError::InvalidParam)
+        );
+    }
 }
@@ -1,15 +1,28 @@
 use crate::dev::DevError;
-use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
 use core::any::Any;
 use core::fmt;
 use core::future::Future;
 use core::pin::Pin;
 use core::result;
 use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Default max length of symlink content, see `FileSystem::symlink_max`
+pub const SYMLINK_MAX: usize = 1024;
 
 /// Abstract file system object such as file or directory.
 pub trait INode: Any + Sync + Send {
     /// Read bytes at `offset` into `buf`, return the number of bytes read.
+    /// For a file it is `min(size - offset, buf.len())`: short only when reaching the end,
+    /// and 0 at or past the end.
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
 
     /// Write bytes at `offset` from `buf`, return the number of bytes written.
@@ -30,11 +43,47 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Get the type of the INode, without building the whole metadata
+    fn file_type(&self) -> Result<FileType> {
+        Ok(self.metadata()?.type_)
+    }
+
     /// Set metadata of the INode
     fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
         Err(FsError::NotSupported)
     }
 
+    /// Get the value of extended attribute `name`, e.g. "security.selinux".
+    /// Return `EntryNotFound` if it is not set.
+    fn get_xattr(&self, _name: &str) -> Result<Vec<u8>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Set extended attribute `name` to `value`, replacing the old value if any
+    fn set_xattr(&self, _name: &str, _value: &[u8]) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Names of all extended attributes of the INode
+    fn list_xattr(&self) -> Result<Vec<String>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Remove extended attribute `name`, or return `EntryNotFound` if it is not set
+    fn remove_xattr(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Bind a socket INode to `endpoint`, an opaque handle for whoever connects to its path
+    fn bind(&self, _endpoint: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Get the endpoint a socket INode is bound to, or `ConnectionRefused` if it is not
+    fn endpoint(&self) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
     /// Sync all data and metadata
     fn sync_all(&self) -> Result<()> {
         Err(FsError::NotSupported)
@@ -66,6 +115,13 @@ pub trait INode: Any + Sync + Send {
         self.create(name, type_, mode)
     }
 
+    /// Create a regular file `name` already containing `data`.
+    /// The entry is linked only after the content is written, so the file is never
+    /// seen empty, even after a crash in between.
+    fn create_with_content(&self, _name: &str, _mode: u32, _data: &[u8]) -> Result<Arc<dyn INode>> {
+        Err(FsError::NotSupported)
+    }
+
     /// Create a hard link `name` to `other`
     fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
         Err(FsError::NotSupported)
@@ -88,10 +144,45 @@ pub trait INode: Any + Sync + Send {
     }
 
     /// Get the name of directory entry
+    ///
+    /// Every directory yields "." at `id` 0 and ".." at `id` 1,
+    /// whether the backend stores them on disk or synthesizes them.
     fn get_entry(&self, _id: usize) -> Result<String> {
         Err(FsError::NotSupported)
     }
 
+    /// Call `f` with the id and name of each directory entry in order,
+    /// stop when `f` returns `Ok(false)`
+    fn iterate_dir(&self, f: &mut dyn FnMut(usize, &str) -> Result<bool>) -> Result<()> {
+        // a default implementation based on `get_entry`
+        for id in 0.. {
+            let name = match self.get_entry(id) {
+                Ok(name) => name,
+                Err(FsError::EntryNotFound) => break,
+                Err(e) => return Err(e),
+            };
+            if !f(id, &name)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the inode number and name of directory entry
+    fn read_dir_plus(&self, id: usize) -> Result<(usize, String)> {
+        // a default and slow implementation
+        let name = self.get_entry(id)?;
+        let entry = self.find(&name)?;
+        Ok((entry.metadata()?.inode, name))
+    }
+
+    /// Names and inode numbers of all directory entries at a single point in time.
+    /// Unlike iterating `get_entry`, no entry is skipped or repeated
+    /// if the directory is modified meanwhile.
+    fn snapshot_entries(&self) -> Result<Vec<(String, usize)>> {
+        Err(FsError::NotSupported)
+    }
+
     /// Get the name of directory entry with metadata
     fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
         // a default and slow implementation
@@ -110,6 +201,55 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Hint the expected access pattern of bytes `offset..offset + len`.
+    /// `len == 0` means to the end of file.
+    fn fadvise(&self, _offset: usize, _len: usize, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+
+    /// Load bytes `offset..offset + len` into caches without returning them
+    fn readahead(&self, _offset: usize, _len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Deallocate bytes `offset..offset + len`, which read as zeros afterwards.
+    /// The size of file is unchanged.
+    fn punch_hole(&self, _offset: usize, _len: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Hint that `count` more entries will be created in this directory,
+    /// so the space for them may be allocated at once
+    fn reserve_entries(&self, _count: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Count a new open handle of the INode, e.g. on `open(2)`, paired with `close`
+    fn open(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Drop an open handle of the INode.
+    /// An unlinked INode may be freed on the last close, even if still referenced.
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Number of open handles of the INode, 0 if not tracked
+    fn open_count(&self) -> usize {
+        0
+    }
+
+    /// Whether a file system is mounted at this INode
+    fn is_mountpoint(&self) -> bool {
+        false
+    }
+
+    /// View the INode as a device file, if it is one
+    fn as_device(&self) -> Option<&dyn DeviceINode> {
+        None
+    }
+
     /// Get the file system of the INode
     fn fs(&self) -> Arc<dyn FileSystem> {
         unimplemented!();
@@ -126,17 +266,45 @@ impl dyn INode {
         self.as_any_ref().downcast_ref::<T>()
     }
 
-    /// Get all directory entries as a Vec
+    /// Get all directory entries as a Vec, taken by `snapshot_entries` if supported
     pub fn list(&self) -> Result<Vec<String>> {
-        let info = self.metadata()?;
-        if info.type_ != FileType::Dir {
+        if self.file_type()? != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        match self.snapshot_entries() {
+            Ok(entries) => Ok(entries.into_iter().map(|(name, _)| name).collect()),
+            // may be inconsistent if modified meanwhile
+            Err(FsError::NotSupported) => Ok((0..)
+                .map(|i| self.get_entry(i))
+                .take_while(|result| result.is_ok())
+                .filter_map(|result| result.ok())
+                .collect()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Visit directory entries without collecting them, see `INode::iterate_dir`
+    pub fn for_each_entry(&self, mut f: impl FnMut(usize, &str) -> Result<bool>) -> Result<()> {
+        if self.file_type()? != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        Ok((0..)
-            .map(|i| self.get_entry(i))
-            .take_while(|result| result.is_ok())
-            .filter_map(|result| result.ok())
-            .collect())
+        self.iterate_dir(&mut f)
+    }
+
+    /// Read the content of a symlink, i.e. the path it refers to.
+    /// Fails with `FsError::NameTooLong` if longer than `FileSystem::symlink_max`.
+    pub fn read_link(&self) -> Result<String> {
+        let metadata = self.metadata()?;
+        if metadata.type_ != FileType::SymLink {
+            return Err(FsError::InvalidParam);
+        }
+        if metadata.size > self.fs().symlink_max() {
+            return Err(FsError::NameTooLong);
+        }
+        let mut content = vec![0u8; metadata.size];
+        let len = self.read_at(0, &mut content)?;
+        content.truncate(len);
+        String::from_utf8(content).map_err(|_| FsError::InvalidParam)
     }
 
     /// Lookup path from current INode, and do not follow symlinks
@@ -146,7 +314,7 @@ impl dyn INode {
 
     /// Lookup path from current INode, and follow symlinks at most `follow_times` times
     pub fn lookup_follow(&self, path: &str, follow_times: usize) -> Result<Arc<dyn INode>> {
-        if self.metadata()?.type_ != FileType::Dir {
+        if self.file_type()? != FileType::Dir {
             return Err(FsError::NotDir);
         }
 
@@ -158,7 +326,7 @@ impl dyn INode {
         };
 
         while !rest_path.is_empty() {
-            if result.metadata()?.type_ != FileType::Dir {
+            if result.file_type()? != FileType::Dir {
                 return Err(FsError::NotDir);
             }
             let name;
@@ -177,11 +345,8 @@ impl dyn INode {
             }
             let inode = result.find(&name)?;
             // Handle symlink
-            if inode.metadata()?.type_ == FileType::SymLink && follow_times > 0 {
-                let mut content = [0u8; 256];
-                let len = inode.read_at(0, &mut content)?;
-                let link_path =
-                    String::from(str::from_utf8(&content[..len]).map_err(|_| FsError::NotDir)?);
+            if inode.file_type()? == FileType::SymLink && follow_times > 0 {
+                let link_path = inode.read_link()?;
                 // result remains unchanged
                 let new_path = link_path + "/" + &rest_path;
                 return result.lookup_follow(&new_path, follow_times - 1);
@@ -191,6 +356,177 @@ impl dyn INode {
         }
         Ok(result)
     }
+
+    /// Visit all INodes under this directory depth-first, with their paths relative to it.
+    /// Symlinks are not followed, and a directory reached again (e.g. through a mount loop)
+    /// is not entered twice.
+    #[allow(clippy::type_complexity)]
+    pub fn walk(&self, visitor: &mut dyn FnMut(&str, &Arc<dyn INode>) -> Result<()>) -> Result<()> {
+        if self.file_type()? != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![(String::new(), self.find(".")?)];
+        while let Some((path, inode)) = stack.pop() {
+            if !path.is_empty() {
+                visitor(&path, &inode)?;
+            }
+            if inode.file_type()? != FileType::Dir {
+                continue;
+            }
+            let metadata = inode.metadata()?;
+            if !visited.insert((metadata.dev, metadata.inode)) {
+                continue;
+            }
+            let mut names = Vec::new();
+            inode.for_each_entry(|_, name| {
+                if name != "." && name != ".." {
+                    names.push(String::from(name));
+                }
+                Ok(true)
+            })?;
+            // push in reverse to visit in order
+            for name in names.into_iter().rev() {
+                let child = inode.find(&name)?;
+                let path = match path.as_str() {
+                    "" => name,
+                    _ => path.clone() + "/" + &name,
+                };
+                stack.push((path, child));
+            }
+        }
+        Ok(())
+    }
+
+    /// Create all `entries` under this directory, in order, without syncing.
+    /// The parent of each entry must exist or be imported before it.
+    /// See `FileSystem::import_tree` to import and sync at once,
+    /// and `util::YieldEvery` to let other tasks run during a large import.
+    pub fn import_tree(&self, entries: &mut dyn Iterator<Item = ImportEntry>) -> Result<()> {
+        // directories by path, to look up each of them only once
+        let mut dirs: BTreeMap<String, Arc<dyn INode>> = BTreeMap::new();
+        for entry in entries {
+            let (path, type_, mode) = match &entry {
+                ImportEntry::File { path, mode, .. } => (path, FileType::File, *mode),
+                ImportEntry::Dir { path, mode } => (path, FileType::Dir, *mode),
+                ImportEntry::SymLink { path, mode, .. } => (path, FileType::SymLink, *mode),
+            };
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let inode = if parent.is_empty() {
+                self.create(name, type_, mode)?
+            } else {
+                let dir = match dirs.get(parent) {
+                    Some(dir) => dir.clone(),
+                    None => {
+                        let dir = self.lookup(parent)?;
+                        dirs.insert(String::from(parent), dir.clone());
+                        dir
+                    }
+                };
+                dir.create(name, type_, mode)?
+            };
+            let data = match &entry {
+                ImportEntry::File { data, .. } => data.as_slice(),
+                ImportEntry::SymLink { target, .. } => target.as_bytes(),
+                ImportEntry::Dir { path, .. } => {
+                    dirs.insert(path.clone(), inode);
+                    continue;
+                }
+            };
+            if !data.is_empty() {
+                inode.resize(data.len())?;
+                inode.write_at(0, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy everything under this directory into the directory `dest`,
+    /// keeping hard links and, where `dest` supports them, the metadata.
+    pub fn copy_tree_to(&self, dest: &Arc<dyn INode>) -> Result<()> {
+        fn copy_metadata(inode: &Arc<dyn INode>, metadata: &Metadata) -> Result<()> {
+            match inode.set_metadata(metadata) {
+                Err(FsError::NotSupported) => Ok(()),
+                ret => ret,
+            }
+        }
+        // copied directories by path, with their metadata set last
+        // since creating entries in them changes it
+        let mut dirs: BTreeMap<String, (Arc<dyn INode>, Metadata)> = BTreeMap::new();
+        // copied INodes linked more than once, by (dev, inode) of the source
+        let mut links: BTreeMap<(usize, usize), Arc<dyn INode>> = BTreeMap::new();
+        let mut buf = vec![0u8; dest.fs().preferred_io_size()];
+        self.walk(&mut |path, inode| {
+            let metadata = inode.metadata()?;
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let parent = match parent {
+                "" => dest,
+                parent => &dirs[parent].0,
+            };
+            let key = (metadata.dev, metadata.inode);
+            if let Some(other) = links.get(&key) {
+                return parent.link(name, other);
+            }
+            let type_ = metadata.type_;
+            let new = parent.create2(name, type_, metadata.mode as u32, metadata.rdev)?;
+            if type_ == FileType::Dir {
+                dirs.insert(String::from(path), (new, metadata));
+                return Ok(());
+            }
+            if type_ == FileType::File || type_ == FileType::SymLink {
+                let mut offset = 0;
+                loop {
+                    let len = inode.read_at(offset, &mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    new.write_at(offset, &buf[..len])?;
+                    offset += len;
+                }
+            }
+            copy_metadata(&new, &metadata)?;
+            if metadata.nlinks > 1 {
+                links.insert(key, new);
+            }
+            Ok(())
+        })?;
+        for (dir, metadata) in dirs.values() {
+            copy_metadata(dir, metadata)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry to create by `import_tree`, at a '/'-separated `path` relative to the directory
+/// imported into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportEntry {
+    File {
+        path: String,
+        mode: u32,
+        data: Vec<u8>,
+    },
+    Dir {
+        path: String,
+        mode: u32,
+    },
+    SymLink {
+        path: String,
+        mode: u32,
+        target: String,
+    },
+}
+
+/// Operations specific to device files, see `INode::as_device`
+pub trait DeviceINode: Send + Sync {
+    /// Major and minor device numbers
+    fn device_id(&self) -> (usize, usize);
+
+    /// Control device
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize>;
+
+    /// Poll the events, return a bitmap of events.
+    fn poll(&self) -> Result<PollStatus>;
 }
 
 pub enum IOCTLError {
@@ -207,6 +543,21 @@ pub struct PollStatus {
     pub error: bool,
 }
 
+/// Access pattern hint for `INode::fadvise`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Advice {
+    /// No special treatment
+    Normal,
+    /// Expect sequential accesses
+    Sequential,
+    /// Expect random accesses
+    Random,
+    /// Expect accesses in the near future, prefetch them
+    WillNeed,
+    /// Do not expect accesses in the near future, drop them from caches
+    DontNeed,
+}
+
 #[derive(Debug)]
 pub struct MMapArea {
     /// Start virtual address
@@ -233,12 +584,13 @@ pub struct Metadata {
     /// Size in bytes
     ///
     /// SFS Note: for normal file size is the actuate file size
-    /// for directory this is count of dirent.
+    /// for directory this is the bytes taken by its dirents.
     pub size: usize,
     /// A file system-specific preferred I/O block size for this object.
     /// In some file system types, this may vary from file to file.
     pub blk_size: usize,
-    /// Size in blocks
+    /// Number of `blk_size`-byte blocks allocated for the content,
+    /// for files and directories alike. Holes are not counted.
     pub blocks: usize,
     /// Time of last access
     pub atime: Timespec,
@@ -284,7 +636,7 @@ pub enum FileType {
 /// Metadata of FileSystem
 ///
 /// Ref: [http://pubs.opengroup.org/onlinepubs/9699919799/]
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct FsInfo {
     /// File system block size
     pub bsize: usize,
@@ -304,6 +656,46 @@ pub struct FsInfo {
     pub namemax: usize,
 }
 
+/// Counts of operations a file system has done since it was opened
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Statistics {
+    /// Read requests to the underlying device
+    pub block_reads: usize,
+    /// Write requests to the underlying device
+    pub block_writes: usize,
+    /// INodes loaded from the device, not found in memory
+    pub inode_loads: usize,
+    pub blocks_allocated: usize,
+    pub blocks_freed: usize,
+    /// Successful `sync`s of the whole file system
+    pub syncs: usize,
+}
+
+/// `Statistics` kept up to date by a file system
+#[derive(Debug, Default)]
+pub struct StatCounters {
+    pub block_reads: AtomicUsize,
+    pub block_writes: AtomicUsize,
+    pub inode_loads: AtomicUsize,
+    pub blocks_allocated: AtomicUsize,
+    pub blocks_freed: AtomicUsize,
+    pub syncs: AtomicUsize,
+}
+
+impl StatCounters {
+    /// Values of the counters now
+    pub fn snapshot(&self) -> Statistics {
+        Statistics {
+            block_reads: self.block_reads.load(Ordering::Relaxed),
+            block_writes: self.block_writes.load(Ordering::Relaxed),
+            inode_loads: self.inode_loads.load(Ordering::Relaxed),
+            blocks_allocated: self.blocks_allocated.load(Ordering::Relaxed),
+            blocks_freed: self.blocks_freed.load(Ordering::Relaxed),
+            syncs: self.syncs.load(Ordering::Relaxed),
+        }
+    }
+}
+
 // Note: IOError/NoMemory always lead to a panic since it's hard to recover from it.
 //       We also panic when we can not parse the fs on disk normally
 #[derive(Debug, Eq, PartialEq)]
@@ -323,10 +715,16 @@ pub enum FsError {
     DeviceError,
     IOCTLError,
     NoDevice,
-    Again,       // E_AGAIN, when no data is available, never happens in fs
-    SymLoop,     // E_LOOP
-    Busy,        // E_BUSY
-    Interrupted, // E_INTR
+    Again,             // E_AGAIN, when no data is available, never happens in fs
+    SymLoop,           // E_LOOP
+    Busy,              // E_BUSY
+    Interrupted,       // E_INTR
+    StaleFileHandle,   // E_STALE, when the INode has been freed
+    PermissionDenied,  // E_ACCES or E_PERM, reported by the host
+    ReadOnlyFs,        // E_ROFS, when the file system is mounted read-only
+    NameTooLong,       // E_NAMETOOLONG
+    TooManyLinks,      // E_MLINK, when the link count of an INode is at its max
+    ConnectionRefused, // E_CONNREFUSED, when a socket INode is not bound to an endpoint
 }
 
 impl fmt::Display for FsError {
@@ -348,13 +746,186 @@ pub trait FileSystem: Sync + Send {
     /// Sync all data to the storage
     fn sync(&self) -> Result<()>;
 
+    /// Sync like `sync`, but give up with `FsError::Interrupted` once `cancel` is set,
+    /// e.g. by a signal during fsync. What is written by then is left persisted.
+    /// The flag is checked between INodes, or only before starting if not overridden.
+    fn sync_cancellable(&self, cancel: &AtomicBool) -> Result<()> {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(FsError::Interrupted);
+        }
+        self.sync()
+    }
+
     /// Get the root INode of the file system
     fn root_inode(&self) -> Arc<dyn INode>;
 
     /// Get the file system information
     fn info(&self) -> FsInfo;
+
+    /// Whether there is anything to flush by `sync`, without syncing.
+    /// Return true if unknown.
+    fn is_dirty(&self) -> bool {
+        true
+    }
+
+    /// Run `f` as a transaction, sync all its changes only if it returns `Ok`.
+    /// On `Err`, the in-memory changes are left as is without flushing (best effort).
+    fn transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let ret = f()?;
+        self.sync()?;
+        Ok(ret)
+    }
+
+    /// Create all `entries` under the root directory, see `INode::import_tree`,
+    /// then sync once at the end.
+    /// Much faster than creating them one by one for a large tree, e.g. when building an image.
+    fn import_tree(&self, entries: &mut dyn Iterator<Item = ImportEntry>) -> Result<()> {
+        self.root_inode().import_tree(entries)?;
+        self.sync()
+    }
+
+    /// Copy the whole tree of `template` into the root directory, see `INode::copy_tree_to`,
+    /// then sync. E.g. to build a new image like an existing one.
+    fn mkfs_from_template(&self, template: &dyn FileSystem) -> Result<()> {
+        template.root_inode().copy_tree_to(&self.root_inode())?;
+        self.sync()
+    }
+
+    /// Change mount options of the mounted file system, without reopening it
+    fn remount(&self, _options: MountOptions) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Current mount options, as last set by `remount`
+    fn mount_options(&self) -> MountOptions {
+        MountOptions::default()
+    }
+
+    /// Name of the file system type, e.g. "sfs", as listed in `/proc/mounts`
+    fn fs_type(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Counts of operations done so far, all zero if not kept
+    fn stats(&self) -> Statistics {
+        Statistics::default()
+    }
+
+    /// The optimal size of a single read or write, e.g. for buffers copying files
+    fn preferred_io_size(&self) -> usize {
+        0x1000
+    }
+
+    /// Max length of symlink content, longer ones can not be followed
+    fn symlink_max(&self) -> usize {
+        SYMLINK_MAX
+    }
+
+    /// Whether files may have holes, i.e. `Metadata::blocks` counts only allocated blocks.
+    /// Otherwise it may be anything, and all blocks up to the size should be taken as allocated.
+    fn supports_holes(&self) -> bool {
+        false
+    }
+
+    /// Set the umask, whose bits are cleared from the mode of INodes created afterwards.
+    /// It is 0 by default, keeping the mode as given.
+    fn set_umask(&self, _umask: u32) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// Options of a mounted file system, see `FileSystem::remount`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Reject all modifications with `FsError::ReadOnlyFs`
+    pub read_only: bool,
+}
+
+/// Generate a new device id for a `FileSystem`, reported in `Metadata::dev`
+/// to tell its INodes apart from those of other file systems
+pub fn new_dev_id() -> usize {
+    use core::sync::atomic::*;
+    static ID: AtomicUsize = AtomicUsize::new(1);
+    ID.fetch_add(1, Ordering::SeqCst)
 }
 
 pub fn make_rdev(major: usize, minor: usize) -> usize {
     ((major & 0xfff) << 8) | (minor & 0xff)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory counting the calls of `metadata` and `get_entry`
+    struct CountingDir {
+        metadata_calls: AtomicUsize,
+        entry_calls: AtomicUsize,
+    }
+
+    impl CountingDir {
+        fn new() -> Self {
+            CountingDir {
+                metadata_calls: AtomicUsize::new(0),
+                entry_calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl INode for CountingDir {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Err(FsError::IsDir)
+        }
+        fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+            Err(FsError::IsDir)
+        }
+        fn poll(&self) -> Result<PollStatus> {
+            Err(FsError::NotSupported)
+        }
+        fn metadata(&self) -> Result<Metadata> {
+            self.metadata_calls.fetch_add(1, Ordering::SeqCst);
+            Err(FsError::NotSupported)
+        }
+        fn file_type(&self) -> Result<FileType> {
+            Ok(FileType::Dir)
+        }
+        fn get_entry(&self, id: usize) -> Result<String> {
+            self.entry_calls.fetch_add(1, Ordering::SeqCst);
+            [".", "..", "a", "b", "c"]
+                .get(id)
+                .map(|&name| String::from(name))
+                .ok_or(FsError::EntryNotFound)
+        }
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn list_uses_file_type() {
+        let dir = CountingDir::new();
+        let inode: &dyn INode = &dir;
+        assert_eq!(inode.list().unwrap(), [".", "..", "a", "b", "c"]);
+        assert_eq!(inode.lookup("").err(), Some(FsError::NotSupported));
+        assert_eq!(dir.metadata_calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn for_each_entry_stops_early() {
+        let dir = CountingDir::new();
+        let inode: &dyn INode = &dir;
+        let mut names = Vec::new();
+        inode
+            .for_each_entry(|id, name| {
+                names.push(String::from(name));
+                Ok(id < 2)
+            })
+            .unwrap();
+        assert_eq!(names, [".", "..", "a"]);
+        assert_eq!(dir.entry_calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -27,6 +27,7 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use structopt::StructOpt;
 
@@ -97,11 +98,11 @@ fn main() {
         true => {
             std::fs::create_dir(&opt.image)
                 .expect("failed to create dir for SEFS");
-            sefs::SEFS::create(Box::new(device), &StdTimeProvider)
+            sefs::SEFS::create(Arc::new(device), &StdTimeProvider)
                 .expect("failed to create sefs")
         }
         false => {
-            sefs::SEFS::open(Box::new(device), &StdTimeProvider)
+            sefs::SEFS::open(Arc::new(device), &StdTimeProvider)
                 .expect("failed to open sefs")
         }
     };